@@ -113,6 +113,17 @@ pub fn trunc_bytes(bytes: &[u8]) -> u32 {
     return unpack_u32_be(bytes, 0);
 }
 
+/// Adler-32 checksum of the bytes, as used in the zlib format.
+pub fn adler32(bytes: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for byte in bytes.iter() {
+        a = (a + (*byte as u32)) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
 
 #[test]
 fn test() {
@@ -149,5 +160,7 @@ fn test() {
     pack_u64_be(buf, 0, 0x0000000100000002);
     println( fmt!("%? %?", fold_bytes(buf), buf) );
 
+    assert_eq!(adler32("Wikipedia".as_bytes()), 0x11E60398);
+
 }
 
@@ -14,6 +14,7 @@
  ******************************************************************************/
 
 
+use std::str;
 use std::str::CharEq;
 //use std::int;
 //use std::to_str;
@@ -44,6 +45,38 @@ pub fn clean_split<Sep: CharEq>(s : &str, seperator: Sep) -> ~[&str] {
     return parts;
 }
 
+// Decode the %XX escapes of a URL-encoded string, e.g. the keys in memcached's metadump.  Malformed escapes
+// are kept as is.  Return the string as is if the decoded bytes are not UTF-8.
+pub fn url_decode(s: &str) -> ~str {
+    let bytes = s.as_bytes();
+    let mut decoded : ~[u8] = ~[];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == '%' as u8 && i + 2 < bytes.len() {
+            match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    decoded.push(hi * 16 + lo);
+                    i += 3;
+                    continue;
+                },
+                _ => ()
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    return if str::is_utf8(decoded) { str::from_utf8(decoded) } else { s.to_owned() };
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c as char {
+        '0'..'9' => Some(c - '0' as u8),
+        'a'..'f' => Some(c - 'a' as u8 + 10),
+        'A'..'F' => Some(c - 'A' as u8 + 10),
+        _ => None
+    }
+}
+
 #[test]
 fn test_to_num() {
     println( fmt!("%?", from_str::<int>("28")) );
@@ -59,3 +92,12 @@ fn test_clean_split() {
     println( fmt!("%?", clean_split(" a :123.c ", ':')) );
 }
 
+#[test]
+fn test_url_decode() {
+    println( fmt!("%?", url_decode("user%3A1%20a%2Fb")) );
+    assert_eq!(url_decode("user%3A1%20a%2Fb"), ~"user:1 a/b");
+    assert_eq!(url_decode("plain_key"), ~"plain_key");
+    assert_eq!(url_decode("bad%2"), ~"bad%2");
+    assert_eq!(url_decode("bad%zz"), ~"bad%zz");
+    assert_eq!(url_decode("%E2%82%AC"), ~"\u20ac");
+}
@@ -0,0 +1,56 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+use std::rt::io::timer::Timer;
+use extra::time;
+
+
+/// Wall clock time in seconds since the Unix epoch.
+pub fn now_secs() -> u64 {
+    return time::get_time().sec as u64;
+}
+
+/// Monotonic time in milliseconds, for measuring intervals.
+pub fn now_ms() -> u64 {
+    return time::precise_time_ns() / 1000000;
+}
+
+/// Sleep the current task for the milliseconds.
+pub fn sleep_ms(ms: u64) {
+    if ms == 0 {
+        return;
+    }
+    match Timer::new() {
+        Some(mut timer) => timer.sleep(ms),
+        None => ()
+    }
+}
+
+
+#[test]
+fn test_now() {
+    println( fmt!("%? %?", now_secs(), now_ms()) );
+    assert!(now_ms() <= now_ms());
+}
+
+#[test]
+fn test_sleep() {
+    let start = now_ms();
+    sleep_ms(20);
+    println( fmt!("slept %? ms", now_ms() - start) );
+    assert!(now_ms() - start >= 20);
+}
+
@@ -28,9 +28,11 @@
 extern mod extra;
 
 use std::result::Result;
+use std::cmp;
 use std::str;
 use std::to_str::ToStr;
 use std::vec;
+use std::hashmap::HashMap;
 use extra::json;
 use extra::json::Json;
 use extra::json::ToJson;
@@ -40,11 +42,38 @@ use extra::digest::Digest;
 use common::strutil;
 use common::netutil;
 use common::ioutil;
+use rustymem_lib::memkey;
+use rustymem_lib::compress;
+use rustymem_lib::metrics;
+use rustymem_lib::chunk::ChunkManifest;
+use rustymem_lib::namespace::NsVersion;
+use rustymem_lib::l1cache::L1Cache;
+use rustymem_lib::health::HealthTracker;
 use rustymem_lib::ascii_conn::AsciiConnection;
 use rustymem_lib::binary_conn::BinaryConnection;
+use rustymem_lib::timeout::TimeoutConnection;
 
 // Re-export
-pub use rustymem_lib::proto::ProtoConnection;
+pub use rustymem_lib::proto::{ProtoConnection, PipelineCmd, PIPE_STORE, PIPE_INCR, PIPE_DECR, PIPE_TOUCH, PIPE_DELETE};
+pub use rustymem_lib::flags::{ValueType, FlagScheme};
+pub use rustymem_lib::flags::{VT_UNKNOWN, VT_BYTES, VT_STR, VT_INT, VT_JSON, VT_COMPRESSED, VT_SERIALIZED, VT_NEGATIVE, VT_TAGGED};
+pub use rustymem_lib::compress::{CompressCodec, COMPRESS_DEFLATE, COMPRESS_ZLIB};
+pub use rustymem_lib::codec::{ValueCodec, JsonCodec, EbmlCodec, BytesCodec};
+pub use rustymem_lib::lock::MemLock;
+pub use rustymem_lib::prefixed::PrefixedMem;
+pub use rustymem_lib::ratelimit::RateLimit;
+pub use rustymem_lib::l1cache::CacheStats;
+pub use rustymem_lib::health::ServerHealth;
+pub use rustymem_lib::topology::RebalanceEstimate;
+pub use rustymem_lib::pool::{MemPool, PooledMem, PoolStats};
+pub use rustymem_lib::asyncmem::AsyncMem;
+pub use rustymem_lib::stats::{ServerStats, ClusterStats, SlabStats, SlabItemStats};
+pub use rustymem_lib::metadump::{MetaDump, MetaEntry, NamespaceUsage};
+pub use rustymem_lib::dump::{DumpSummary, RestoreSummary};
+pub use rustymem_lib::migrate::{MigratingMem, MigrationSwitch, MigrationStats, MigrationPhase};
+pub use rustymem_lib::migrate::{MIGRATE_WARMING, MIGRATE_DUAL_WRITE, MIGRATE_NEW_ONLY};
+pub use rustymem_lib::metrics::{MetricsSink, MemMetrics, HistogramSink, MetricsSnapshot, OpMetric, OpStats, LATENCY_BUCKETS_US};
+pub use rustymem_lib::prometheus::serve_prometheus;
 
 
 // Configure the modules in this crate
@@ -52,12 +81,37 @@ mod rustymem_lib {
     pub mod proto;
     pub mod ascii_conn;
     pub mod binary_conn;
+    pub mod memkey;
+    pub mod prefixed;
+    pub mod namespace;
+    pub mod flags;
+    pub mod compress;
+    pub mod chunk;
+    pub mod codec;
+    pub mod update;
+    pub mod readthrough;
+    pub mod lock;
+    pub mod ratelimit;
+    pub mod l1cache;
+    pub mod health;
+    pub mod topology;
+    pub mod pool;
+    pub mod asyncmem;
+    pub mod stats;
+    pub mod metadump;
+    pub mod dump;
+    pub mod migrate;
+    pub mod tags;
+    pub mod metrics;
+    pub mod prometheus;
+    pub mod timeout;
 }
 mod common {
     pub mod apputil;
     pub mod strutil;
     pub mod netutil;
     pub mod ioutil;
+    pub mod timeutil;
 }
 
 
@@ -74,25 +128,41 @@ static DEFAULT_PORT : u16   = 11211u16; // default Memcached server port
 /// connect("127.0.0.1 127.0.0.2:11212 127.0.0.3:11213");
 pub fn connect(server_addrs: &str) -> RustyMem  {
     // defaul to use the newer binary protocol.
-    return connect_with( MemParams { servers: server_addrs.to_owned(), protocol: P_BINARY, shard: HASH_MOD } );
+    return connect_with( MemParams::new(server_addrs, P_BINARY) );
 }
 
 
 /// Create a new RustyMem, passing in one server address or a list of servers for cluster.
 /// Pass in the Memcached protocol to use.  Note: all servers need to support the same protocol.
-/// connect_with( MemParams { servers: ~"127.0.0.1", protocol: P_BINARY, shard: HASH_MOD } )
+/// connect_with( MemParams::new("127.0.0.1", P_BINARY) )
+/// let mut params = MemParams::new("127.0.0.1", P_BINARY);
+/// params.key_prefix = ~"app1:";
+/// connect_with(params)
 pub fn connect_with(params: MemParams) -> RustyMem  {
     debug!( fmt!("connect_with() enter, %?", params) );
 
     let addrs = strutil::clean_split(params.servers, ' ');
-    let connections = addrs.iter().map( |addr| new_protocol_connection(*addr, params.protocol) ).collect::<~[~ProtoConnection]>();
+    let connections = addrs.iter().map( |addr| new_server_connection(*addr, &params) ).collect::<~[~ProtoConnection]>();
     let conn_addrs = connections.iter().map( |conn| conn.p_get_server_addr() ).collect::<~[~str]>();
     debug!( fmt!("server_addrs : %?", conn_addrs) );
 
-    RustyMem {
+    let l1 = L1Cache::new(params.l1_max_bytes, params.l1_ttl_ms);
+    let health = HealthTracker::new(conn_addrs);
+
+    let mut rm = RustyMem {
         params: params,
         connections: connections,
+        ns_versions: HashMap::new(),
+        l1: l1,
+        l1_bypass: false,
+        health: health,
+    };
+    for i in range(0, rm.get_connection_count()) {
+        if !rm.connections[i].p_is_connected() {
+            rm.connection_failed(i, "connect failed");
+        }
     }
+    return rm;
 }
 
 fn new_protocol_connection(server_addr: &str, protocol: MemProtocol) -> ~ProtoConnection {
@@ -104,10 +174,24 @@ fn new_protocol_connection(server_addr: &str, protocol: MemProtocol) -> ~ProtoCo
     }
 }
 
+// Protocol connection with the I/O timeout of the params, recording its calls to params.metrics, if set.
+fn new_server_connection(server_addr: &str, params: &MemParams) -> ~ProtoConnection {
+    let conn = if params.io_timeout_ms > 0 {
+        ~TimeoutConnection::new_connection(server_addr, params.protocol, params.io_timeout_ms) as ~ProtoConnection
+    } else {
+        new_protocol_connection(server_addr, params.protocol)
+    };
+    return metrics::metered_connection(conn, &params.metrics);
+}
+
 
 pub struct RustyMem {
     params:         MemParams,
-    connections:    ~[~ProtoConnection]
+    connections:    ~[~ProtoConnection],
+    ns_versions:    HashMap<~str, NsVersion>,
+    l1:             L1Cache,
+    l1_bypass:      bool,
+    health:         HealthTracker,
 }
 
 /// Main entry for the Memcached API
@@ -115,12 +199,12 @@ impl RustyMem {
 
     /// Set data bytes at key in memcached, with the expiration exptime in seconds.  Setting exptime to 0 for no expiration.
     pub fn set_bytes(&mut self, key: &str, exptime: uint, data_bytes: &[u8]) -> MemResult<u64> {
-        return self.conn(key).p_set(key, data_bytes, 0, 0, exptime, false);
+        return self.store_cmd(OP_SET, key, data_bytes, 0, VT_BYTES, exptime);
     }
 
     /// Set data str at key in memcached, with the expiration exptime in seconds.  Setting exptime to 0 for no expiration.
     pub fn set_str(&mut self, key: &str, exptime: uint, data_str: &str) -> MemResult<u64> {
-        return self.conn(key).p_set(key, data_str.as_bytes(), 0, 0, exptime, false);
+        return self.store_cmd(OP_SET, key, data_str.as_bytes(), 0, VT_STR, exptime);
     }
 
     /// Set data value as string at key in memcached, with the expiration exptime in seconds.  Setting exptime to 0 for no expiration.
@@ -129,23 +213,29 @@ impl RustyMem {
         return self.set_str(key, exptime, value.to_str());
     }
 
+    /// Set integer value at key in memcached, with the expiration exptime in seconds.  Setting exptime to 0 for no expiration.
+    /// The value is stored as decimal string so it can be used with incr/decr.
+    pub fn set_int(&mut self, key: &str, exptime: uint, value: u64) -> MemResult<u64> {
+        return self.store_cmd(OP_SET, key, value.to_str().as_bytes(), 0, VT_INT, exptime);
+    }
+
     /// Set data value as JSON string at key in memcached, with the expiration exptime in seconds.  Setting exptime to 0 for no expiration.
     pub fn set_json<T: ToJson>(&mut self, key: &str, exptime: uint, data_json: &T) -> MemResult<u64> {
         let json_str = data_json.to_json().to_str();
-        return self.conn(key).p_set(key, json_str.as_bytes(), 0, 0, exptime, false);
+        return self.store_cmd(OP_SET, key, json_str.as_bytes(), 0, VT_JSON, exptime);
     }
 
 
     /// Check and set data bytes at key in memcached, with the expiration exptime in seconds.  Setting exptime to 0 for no expiration.
     /// Pass in the last retrieved MemData.cas to check.
     pub fn cas_bytes(&mut self, key: &str, cas: u64, exptime: uint, data_bytes: &[u8]) -> MemResult<u64> {
-        return self.conn(key).p_cas(key, data_bytes, cas, 0, exptime, false);
+        return self.store_cmd(OP_CAS, key, data_bytes, cas, VT_BYTES, exptime);
     }
 
     /// Check and set data str at key in memcached, with the expiration exptime in seconds.  Setting exptime to 0 for no expiration.
     /// Pass in the last retrieved MemData.cas to check.
     pub fn cas_str(&mut self, key: &str, cas: u64, exptime: uint, data_str: &str) -> MemResult<u64> {
-        return self.conn(key).p_cas(key, data_str.as_bytes(), cas, 0, exptime, false);
+        return self.store_cmd(OP_CAS, key, data_str.as_bytes(), cas, VT_STR, exptime);
     }
 
     /// Check and set data value as string at key in memcached, with the expiration exptime in seconds.  Setting exptime to 0 for no expiration.
@@ -160,16 +250,16 @@ impl RustyMem {
     pub fn cas_json<T: ToJson>(&mut self, key: &str, cas: u64, exptime: uint, data_json: &T) -> MemResult<u64> {
         let json = data_json.to_json();
         let json_str = json.to_str();
-        return self.conn(key).p_cas(key, json_str.as_bytes(), cas, 0, exptime, false);
+        return self.store_cmd(OP_CAS, key, json_str.as_bytes(), cas, VT_JSON, exptime);
     }
 
 
     pub fn add_bytes(&mut self, key: &str, exptime: uint, data_bytes: &[u8]) -> MemResult<u64> {
-        return self.conn(key).p_add(key, data_bytes, 0, 0, exptime, false);
+        return self.store_cmd(OP_ADD, key, data_bytes, 0, VT_BYTES, exptime);
     }
 
     pub fn add_str(&mut self, key: &str, exptime: uint, data_str: &str) -> MemResult<u64> {
-        return self.conn(key).p_add(key, data_str.as_bytes(), 0, 0, exptime, false);
+        return self.store_cmd(OP_ADD, key, data_str.as_bytes(), 0, VT_STR, exptime);
     }
 
     pub fn add_as<T: ToStr>(&mut self, key: &str, exptime: uint, value: &T) -> MemResult<u64> {
@@ -178,16 +268,16 @@ impl RustyMem {
 
     pub fn add_json<T: ToJson>(&mut self, key: &str, exptime: uint, data_json: &T) -> MemResult<u64> {
         let json_str = data_json.to_json().to_str();
-        return self.conn(key).p_add(key, json_str.as_bytes(), 0, 0, exptime, false);
+        return self.store_cmd(OP_ADD, key, json_str.as_bytes(), 0, VT_JSON, exptime);
     }
 
 
     pub fn replace_bytes(&mut self, key: &str, cas: u64, exptime: uint, data_bytes: &[u8]) -> MemResult<u64> {
-        return self.conn(key).p_replace(key, data_bytes, cas, 0, exptime, false);
+        return self.store_cmd(OP_REPLACE, key, data_bytes, cas, VT_BYTES, exptime);
     }
 
     pub fn replace_str(&mut self, key: &str, cas: u64, exptime: uint, data_str: &str) -> MemResult<u64> {
-        return self.conn(key).p_replace(key, data_str.as_bytes(), cas, 0, exptime, false);
+        return self.store_cmd(OP_REPLACE, key, data_str.as_bytes(), cas, VT_STR, exptime);
     }
 
     pub fn replace_as<T: ToStr>(&mut self, key: &str, cas: u64, exptime: uint, value: &T) -> MemResult<u64> {
@@ -196,46 +286,71 @@ impl RustyMem {
 
     pub fn replace_json<T: ToJson>(&mut self, key: &str, cas: u64, exptime: uint, data_json: &T) -> MemResult<u64> {
         let json_str = data_json.to_json().to_str();
-        return self.conn(key).p_replace(key, json_str.as_bytes(), cas, 0, exptime, false);
+        return self.store_cmd(OP_REPLACE, key, json_str.as_bytes(), cas, VT_JSON, exptime);
     }
 
 
     pub fn append_bytes(&mut self, key: &str, data_bytes: &[u8]) -> MemResult<u64> {
-        return self.conn(key).p_append(key, data_bytes, false);
+        return self.store_cmd(OP_APPEND, key, data_bytes, 0, VT_BYTES, 0);
     }
 
     pub fn prepend_bytes(&mut self, key: &str, data_bytes: &[u8]) -> MemResult<u64> {
-        return self.conn(key).p_prepend(key, data_bytes, false);
+        return self.store_cmd(OP_PREPEND, key, data_bytes, 0, VT_BYTES, 0);
     }
 
 
     /// Get the data item as MemData at key from memcached.  Return None if no data found or error.
     /// MemData has all the info about the data item.
     pub fn get_data(&mut self, key: &str) -> Option<MemData> {
-        let md_list = self.conn(key).p_gets([key]);
+        let wkey = match self.wire_key(key) {
+            Ok(k)   => k,
+            Err(_)  => return None
+        };
+        if self.l1.is_enabled() && !self.l1_bypass {
+            match self.l1.get(wkey) {
+                Some(mut md) => {
+                    md.key = key.to_owned();
+                    return Some(md);
+                },
+                None => ()
+            }
+        }
+        let md_list = self.get_replicated(wkey);
+        let mut md_list = self.join_chunks(md_list);
         if md_list.len() == 0 {
+            self.l1.stats.l2_misses += 1;
             None
         } else {
-            Some(md_list[0])
+            self.l1.stats.l2_hits += 1;
+            let mut md = md_list.shift();
+            self.params.decode_data(&mut md);
+            self.l1.put(wkey, &md, 0);
+            md.key = key.to_owned();
+            Some(md)
         }
     }
 
-    /// Get data bytes at key from memcached.  Return None if no data found or error.
+    /// Get data bytes at key from memcached.  Return None if no data found, error, or the data failed to decompress.
     pub fn get_bytes(&mut self, key: &str) -> Option<~[u8]> {
         match self.get_data(key) {
-            Some(md) => Some(md.as_bytes()),
+            Some(md) => md.try_bytes().ok(),
             None => None
         }
     }
 
-    /// Get data str at key from memcached.  Return None if no data found or error.
+    /// Get data str at key from memcached.  Return None if no data found, error, or the data is not a string.
     pub fn get_str(&mut self, key: &str) -> Option<~str> {
         match self.get_data(key) {
-            Some(md) => Some(md.as_str()),
+            Some(md) => md.try_str().ok(),
             None => None
         }
     }
 
+    /// Get integer value at key from memcached.  Return None if no data found, error, or the data is not an integer.
+    pub fn get_int(&mut self, key: &str) -> Option<u64> {
+        return self.get_as::<u64>(key);
+    }
+
     /// Get data value as type from string at key from memcached.  Return None if no data found or error.
     pub fn get_as<T: FromStr>(&mut self, key: &str) -> Option<T> {
         match self.get_data(key) {
@@ -257,38 +372,75 @@ impl RustyMem {
 
 
     /// Get the list of data as MemData of the list of keys.  Return empty list if no data found or error.
+    /// Invalid keys are skipped.
     pub fn get_bulk_data(&mut self, keys: &[&str]) -> ~[MemData] {
-        if self.get_connection_count() == 1 {
-            let conn = self.get_connection(0);
-            return conn.p_gets(keys);
+        // Map the wire keys back to the caller's keys for the returned MemData.key.
+        let mut key_map = HashMap::<~str, ~str>::new();
+        let mut wire_keys : ~[~str] = ~[];
+        for key in keys.iter() {
+            match self.wire_key(*key) {
+                Ok(wkey) => {
+                    if !key_map.contains_key(&wkey) {
+                        key_map.insert(wkey.clone(), key.to_owned());
+                        wire_keys.push(wkey);
+                    }
+                },
+                Err(_) => ()
+            }
         }
 
-        let mut result : ~[MemData] = ~[];
-        let key_arrays : ~[~[~str]] = RustyMem::distribute_keys(keys, self.get_connection_count(), RustyMem::md5_mod_indexer);
-        for i in range(0, self.get_connection_count()) {
-            if key_arrays[i].len() > 0 {
-                let key_array : &~[~str] = &key_arrays[i];
-                let key_ref_array : ~[&str] = key_array.iter().map(|k| k.as_slice()).to_owned_vec();
-                let ref_of_key_ref_array : &[&str] = key_ref_array.as_slice();
-                let conn = self.get_connection(i);
-                let conn_result = conn.p_gets(ref_of_key_ref_array);
-                result.push_all_move(conn_result);
+        let mut l1_result : ~[MemData] = ~[];
+        if self.l1.is_enabled() && !self.l1_bypass {
+            let mut l1_missed : ~[~str] = ~[];
+            for wkey in wire_keys.move_iter() {
+                match self.l1.get(wkey) {
+                    Some(md) => l1_result.push(md),
+                    None => l1_missed.push(wkey)
+                }
             }
+            wire_keys = l1_missed;
         }
-        return result;
 
+        let mut result = ~[];
+        if wire_keys.len() > 0 {
+            result = self.get_bulk_wire(wire_keys);
+            result = self.join_chunks(result);
+            self.l1.stats.l2_hits += result.len() as u64;
+            self.l1.stats.l2_misses += (wire_keys.len() - result.len()) as u64;
+            for md in result.mut_iter() {
+                self.params.decode_data(md);
+                self.l1.put(md.key, &*md, 0);
+            }
+        }
+        result.push_all_move(l1_result);
+
+        for md in result.mut_iter() {
+            match key_map.find(&md.key) {
+                Some(key) => md.key = key.clone(),
+                None => ()
+            }
+        }
+        return result;
     }
 
     /// Get the list of data as bytes of the list of keys.  Return empty list if no data found or error.
+    /// Data that failed to decompress are skipped.
     pub fn get_bulk_bytes(&mut self, keys: &[&str]) -> ~[(~str, ~[u8])] {
         let md_list = self.get_bulk_data(keys);
-        return md_list.iter().map(|md| ( md.key.clone(), md.as_bytes() ) ).collect::<~[(~str, ~[u8])]>();
+        return md_list.iter().filter_map(|md| match md.try_bytes() {
+                Ok(bytes)   => Some(( md.key.clone(), bytes )),
+                Err(_)      => None
+            }).collect::<~[(~str, ~[u8])]>();
     }
 
     /// Get the list of data as str of the list of keys.  Return empty list if no data found or error.
+    /// Data that are not string are skipped.
     pub fn get_bulk_str(&mut self, keys: &[&str]) -> ~[(~str, ~str)] {
         let md_list = self.get_bulk_data(keys);
-        return md_list.iter().map(|md| ( md.key.clone(), md.as_str() ) ).collect::<~[(~str, ~str)]>();
+        return md_list.iter().filter_map(|md| match md.try_str() {
+                Ok(s)   => Some(( md.key.clone(), s )),
+                Err(_)  => None
+            } ).collect::<~[(~str, ~str)]>();
     }
 
     /// Get the list of data value from string of the list of keys.  Return empty list if no data found or error.
@@ -309,25 +461,72 @@ impl RustyMem {
     /// Update a cached entry's expiration time.  If entry exists, return TOUCHED.  If entry not exists, return NOT_FOUND.
     /// Note: touch command is not supported in the current Memcached version.
     pub fn touch(&mut self, key: &str, exptime: uint) -> MemStatus {
-        return self.conn(key).p_touch(key, exptime, false);
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return status
+        };
+        self.l1.remove(wkey);
+        return self.run_replicated(wkey, |conn| conn.p_touch(wkey, exptime, false));
     }
 
     pub fn delete(&mut self, key: &str) -> MemStatus {
-        return self.conn(key).p_delete(key, false);
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return status
+        };
+        self.l1.remove(wkey);
+        return self.run_replicated(wkey, |conn| conn.p_delete(wkey, false));
+    }
+
+    /// Delete the entry only if it has not been changed since it was read with the cas value.
+    /// Return Key_Exists if it has been changed.
+    pub fn delete_cas(&mut self, key: &str, cas: u64) -> MemStatus {
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return status
+        };
+        self.l1.remove(wkey);
+        // The cas is checked on the first server up, and the other replicas follow.
+        let mut status = Network_Error;
+        for index in self.replica_indexes(wkey).move_iter() {
+            match status {
+                Success         => { self.run_on(index, |conn| conn.p_delete(wkey, false)); },
+                Network_Error   => status = self.run_on(index, |conn| conn.p_delete_cas(wkey, cas, false)),
+                _               => break
+            }
+        }
+        return status;
     }
 
     // Increment the existing 64-bit integer at the key by the inc_amount.
+    // A missing counter is created with init_value and expiration exptime, unless exptime is 0xFFFFFFFF.  Return the new value.
     pub fn incr(&mut self, key: &str, inc_amount: u64, init_value: u64, exptime: uint) -> MemResult<u64> {
-        return self.conn(key).p_incr(key, inc_amount, init_value, exptime, false);
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return MemResult { status: status, value: 0 }
+        };
+        self.l1.remove(wkey);
+        let result = self.first_replicated(wkey, |conn| conn.p_incr(wkey, inc_amount, init_value, exptime, false));
+        self.copy_counter(wkey, &result, exptime);
+        return result;
     }
 
     // Decrement the existing 64-bit integer at the key by the dec_amount.
+    // A missing counter is created with init_value and expiration exptime, unless exptime is 0xFFFFFFFF.  Return the new value.
     pub fn decr(&mut self, key: &str, dec_amount: u64, init_value: u64, exptime: uint) -> MemResult<u64> {
-        return self.conn(key).p_decr(key, dec_amount, init_value, exptime, false);
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return MemResult { status: status, value: 0 }
+        };
+        self.l1.remove(wkey);
+        let result = self.first_replicated(wkey, |conn| conn.p_decr(wkey, dec_amount, init_value, exptime, false));
+        self.copy_counter(wkey, &result, exptime);
+        return result;
     }
 
 
     pub fn flush(&mut self, delay_in_seconds: uint) -> ~[MemStatus] {
+        self.l1.clear();
         return self.connections.mut_iter().map( |conn| {
                 conn.p_flush(delay_in_seconds, false)
             } ).collect::<~[MemStatus]>();
@@ -369,17 +568,403 @@ impl RustyMem {
         return &mut self.connections[index];
     }
 
+    /// Run the block with the L1 cache bypassed for reads, which go to memcached and refresh L1 with what they read.
+    /// let flag = do rm.with_l1_bypass |rm2| { rm2.get_str("feature1") };
+    pub fn with_l1_bypass<T>(&mut self, blk: &fn(&mut RustyMem) -> T) -> T {
+        let saved_bypass = self.l1_bypass;
+        self.l1_bypass = true;
+        let result = blk(self);
+        self.l1_bypass = saved_bypass;
+        return result;
+    }
+
+    /// Hit and miss counts of the L1 cache and memcached reads.
+    pub fn cache_stats(&self) -> CacheStats {
+        return self.l1.stats.clone();
+    }
+
+    pub fn reset_cache_stats(&mut self) {
+        self.l1.stats = CacheStats::new();
+    }
+
+
+    // Turn the caller's key into the key used on the wire, with the key prefix added.  The key is checked
+    // for both protocols since a space or newline in a key would break the ASCII command line.
+    fn wire_key(&self, key: &str) -> Result<~str, MemStatus> {
+        return memkey::to_wire_key(self.params.key_prefix, key, self.params.key_mode);
+    }
+
+    // Run a storage command at the connection of the key.
+    // The value type is recorded in the flags, according to the flag scheme.
+    fn store_cmd(&mut self, op: StoreOp, key: &str, data: &[u8], cas: u64, value_type: ValueType, exptime: uint) -> MemResult<u64> {
+        let flags = self.params.flag_scheme.to_flags(value_type);
+        return self.store_flags_cmd(op, key, data, cas, flags, exptime);
+    }
 
-    // Pick a connection based on key value.  Simple hash % N algorithm for now.
-    fn conn<'r>(&'r mut self, key: &str) -> &'r mut ~ProtoConnection {
-        let mut index;
-        if self.get_connection_count() == 1 {
-            index = 0;
+    // Run a storage command with the flags already made.
+    fn store_flags_cmd(&mut self, op: StoreOp, key: &str, data: &[u8], cas: u64, flags: u32, exptime: uint) -> MemResult<u64> {
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return MemResult { status: status, value: 0 }
+        };
+        let value_flags = flags;
+        let value_data = data;
+        let mut flags = flags;
+        let compressed = self.params.compress_data(op, data);
+        let data = match compressed {
+            Some(ref cdata) => {
+                flags = flags | self.params.flag_scheme.compressed_flag;
+                cdata.as_slice()
+            },
+            None => data
+        };
+        let chunked = match op {
+            OP_SET | OP_ADD | OP_REPLACE | OP_CAS   => self.params.large_values && data.len() > self.params.max_item_size,
+            OP_APPEND | OP_PREPEND                  => false
+        };
+        let result = if chunked {
+            self.store_chunks(op, wkey, data, cas, flags, exptime)
         } else {
-            // TODO: check self.params.shard
-            index = RustyMem::md5_mod_indexer(key, self.connections.len());
+            self.store_replicated(op, wkey, data, cas, flags, exptime)
+        };
+        self.l1_update(op, wkey, value_data, value_flags, exptime, &result);
+        return result;
+    }
+
+    // Keep the L1 entry of the stored key in sync.  A successfully set value replaces the entry; the
+    // other storage commands change the item in ways not known locally, so the entry is dropped.  The
+    // ASCII protocol doesn't return the cas of a store, and an entry without it would hand a cas of 0
+    // to the reads, so the entry is dropped for the next read to fetch the item with its cas.
+    fn l1_update(&mut self, op: StoreOp, wkey: &str, data: &[u8], flags: u32, exptime: uint, result: &MemResult<u64>) {
+        if !self.l1.is_enabled() {
+            return;
+        }
+        if result.value == 0 {
+            self.l1.remove(wkey);
+            return;
+        }
+        match (op, result.status) {
+            (OP_SET, Success) | (OP_CAS, Success) | (OP_ADD, Success) | (OP_REPLACE, Success) => {
+                let md = MemData {
+                    key:        wkey.to_owned(),
+                    data:       data.to_owned(),
+                    cas:        result.value,
+                    flags:      flags,
+                    value_type: self.params.flag_scheme.to_value_type(flags)
+                };
+                self.l1.put(wkey, &md, exptime);
+            },
+            _ => self.l1.remove(wkey)
+        }
+    }
+
+    // Run the storage command at the servers of the key.  The command runs on the first server up, the primary
+    // unless it's down, and once stored there, the value is copied to the rest of the replicas.  The cas is
+    // only valid on the server it was read from, so the copies are plain sets.
+    fn store_replicated(&mut self, op: StoreOp, wkey: &str, data: &[u8], cas: u64, flags: u32, exptime: uint) -> MemResult<u64> {
+        let mut result = MemResult { status: Network_Error, value: 0 };
+        for index in self.replica_indexes(wkey).move_iter() {
+            match result.status {
+                Success => {
+                    let copy_op = match op {
+                        OP_APPEND | OP_PREPEND  => op,
+                        _                       => OP_SET
+                    };
+                    let copy_result = self.store_on(index, copy_op, wkey, data, 0, flags, exptime);
+                    if copy_result.status != Success {
+                        debug!( fmt!("copy of %? to replica %? failed: %?", wkey, index, copy_result.status) );
+                    }
+                },
+                Network_Error   => result = self.store_on(index, op, wkey, data, cas, flags, exptime),
+                // Rejected by a server up, e.g. Key_Exists
+                _               => break
+            }
+        }
+        return result;
+    }
+
+    // Run the storage command on the connection at the index.
+    fn store_on(&mut self, index: uint, op: StoreOp, wkey: &str, data: &[u8], cas: u64, flags: u32, exptime: uint) -> MemResult<u64> {
+        do self.run_on(index) |conn| {
+            match op {
+                OP_SET      => conn.p_set(wkey, data, cas, flags, exptime, false),
+                OP_CAS      => conn.p_cas(wkey, data, cas, flags, exptime, false),
+                OP_ADD      => conn.p_add(wkey, data, cas, flags, exptime, false),
+                OP_REPLACE  => conn.p_replace(wkey, data, cas, flags, exptime, false),
+                OP_APPEND   => conn.p_append(wkey, data, false),
+                OP_PREPEND  => conn.p_prepend(wkey, data, false),
+            }
+        }
+    }
+
+    // Get the key from the first of its servers up.
+    fn get_replicated(&mut self, wkey: &str) -> ~[MemData] {
+        for index in self.replica_indexes(wkey).move_iter() {
+            let md_list = self.run_on(index, |conn| conn.p_gets([wkey]));
+            if self.connections[index].p_is_connected() {
+                return md_list;
+            }
+        }
+        return ~[];
+    }
+
+    // Run the command on all the servers of the key.  Return the status from the first server up.
+    fn run_replicated(&mut self, wkey: &str, cmd: &fn(&mut ~ProtoConnection) -> MemStatus) -> MemStatus {
+        let mut status = Network_Error;
+        for index in self.replica_indexes(wkey).move_iter() {
+            let conn_status = self.run_on(index, |conn| cmd(conn));
+            if status == Network_Error && self.connections[index].p_is_connected() {
+                status = conn_status;
+            }
+        }
+        return status;
+    }
+
+    // Run the command on the first of the key's servers up.
+    fn first_replicated(&mut self, wkey: &str, cmd: &fn(&mut ~ProtoConnection) -> MemResult<u64>) -> MemResult<u64> {
+        for index in self.replica_indexes(wkey).move_iter() {
+            let result = self.run_on(index, |conn| cmd(conn));
+            if self.connections[index].p_is_connected() {
+                return result;
+            }
+        }
+        return MemResult { status: Network_Error, value: 0 };
+    }
+
+    // Copy the counter value updated on the first server up to the rest of the replicas, so they don't drift apart.
+    fn copy_counter(&mut self, wkey: &str, result: &MemResult<u64>, exptime: uint) {
+        if result.status != Success || self.params.replication <= 1 {
+            return;
+        }
+        let value_str = result.value.to_str();
+        let exptime = if exptime as u32 == 0xFFFFFFFF { 0 } else { exptime };
+        let indexes = self.replica_indexes(wkey);
+        let mut copying = false;
+        for index in indexes.move_iter() {
+            if copying {
+                self.store_on(index, OP_SET, wkey, value_str.as_bytes(), 0, 0, exptime);
+            } else if self.connections[index].p_is_connected() {
+                // The first server up, where the counter was updated.
+                copying = true;
+            }
+        }
+    }
+
+    // Store the large data in chunks under derived keys, and then the manifest of the chunks at the key.
+    // The manifest is written last so it never refers to chunks not written yet, and with the storage command,
+    // so add, replace and cas apply to the value as a whole.  The chunks of a failed command are left to expire.
+    fn store_chunks(&mut self, op: StoreOp, wkey: &str, data: &[u8], cas: u64, flags: u32, exptime: uint) -> MemResult<u64> {
+        let chunk_size = self.params.max_item_size;
+        if chunk_size == 0 {
+            return MemResult { status: Invalid_Arguments, value: 0 };
+        }
+        let chunk_flags = self.params.flag_scheme.to_flags(VT_BYTES);
+        let manifest = ChunkManifest::new(data, chunk_size);
+        for i in range(0, manifest.count) {
+            let begin = i * chunk_size;
+            let end = cmp::min(begin + chunk_size, data.len());
+            let ckey = manifest.chunk_key(wkey, i);
+            let result = self.store_replicated(OP_SET, ckey, data.slice(begin, end), 0, chunk_flags, exptime);
+            if result.status != Success {
+                return result;
+            }
+        }
+        let manifest_flags = flags | self.params.flag_scheme.chunked_flag;
+        return self.store_replicated(op, wkey, manifest.encode(), cas, manifest_flags, exptime);
+    }
+
+    // Replace the chunk manifests in the retrieved data with the reassembled values.
+    // The chunks of all the manifests are retrieved with one bulk get.  Values with missing or
+    // inconsistent chunks are dropped, as not found.
+    fn join_chunks(&mut self, md_list: ~[MemData]) -> ~[MemData] {
+        let chunked_flag = self.params.flag_scheme.chunked_flag;
+        if chunked_flag == 0 || !md_list.iter().any(|md| (md.flags & chunked_flag) != 0) {
+            return md_list;
+        }
+
+        let mut result : ~[MemData] = ~[];
+        let mut manifests : ~[(MemData, ChunkManifest)] = ~[];
+        let mut chunk_keys : ~[~str] = ~[];
+        for md in md_list.move_iter() {
+            if (md.flags & chunked_flag) == 0 {
+                result.push(md);
+            } else {
+                match ChunkManifest::decode(md.data) {
+                    Some(manifest) => {
+                        chunk_keys.push_all_move(manifest.chunk_keys(md.key));
+                        manifests.push((md, manifest));
+                    },
+                    None => debug!( fmt!("invalid chunk manifest at %?", md.key) )
+                }
+            }
+        }
+
+        let mut chunks = HashMap::<~str, ~[u8]>::new();
+        for cmd in self.get_bulk_wire(chunk_keys).move_iter() {
+            let MemData { key: ckey, data: cdata, _ } = cmd;
+            chunks.insert(ckey, cdata);
+        }
+
+        for entry in manifests.move_iter() {
+            let (mut md, manifest) = entry;
+            match manifest.join(md.key, &chunks) {
+                Some(data) => {
+                    md.data = data;
+                    md.flags = md.flags & !chunked_flag;
+                    result.push(md);
+                },
+                None => ()
+            }
+        }
+        return result;
+    }
+
+    // Get the wire keys from their servers, with one multi-get per server.
+    // The keys of a server down are retried on their next replicas.
+    fn get_bulk_wire(&mut self, wire_keys: &[~str]) -> ~[MemData] {
+        let mut result : ~[MemData] = ~[];
+        let connection_count = self.get_connection_count();
+        let key_indexes = self.wire_key_indexes(wire_keys);
+        let mut pending_keys : ~[~str] = wire_keys.to_owned();
+        let mut replica = 0;
+        while replica < self.replica_count() {
+            // Keys with no more servers to try are dropped, as not found.
+            pending_keys.retain(|k| key_indexes.get(k).len() > replica);
+            if pending_keys.len() == 0 {
+                break;
+            }
+            let key_arrays : ~[~[~str]] = RustyMem::distribute_keys(pending_keys, connection_count, |key, _| {
+                    key_indexes.find_equiv(&key).unwrap()[replica]
+                });
+            pending_keys = ~[];
+            for i in range(0, connection_count) {
+                if key_arrays[i].len() > 0 {
+                    let key_ref_array : ~[&str] = key_arrays[i].iter().map(|k| k.as_slice()).to_owned_vec();
+                    let conn_result = self.run_on(i, |conn| conn.p_gets(key_ref_array));
+                    if self.connections[i].p_is_connected() {
+                        result.push_all_move(conn_result);
+                    } else {
+                        pending_keys.push_all(key_arrays[i]);
+                    }
+                }
+            }
+            replica += 1;
+        }
+        return result;
+    }
+
+    // Set the items, (wire key, data, flags, exptime), with one pipeline per server for the items of all its
+    // replicas.  An item is set if any of its servers up has stored it.  The items are not compressed or
+    // chunked here, and their L1 entries are dropped.
+    fn set_bulk_wire(&mut self, items: &[(~str, ~[u8], u32, uint)]) -> ~[MemStatus] {
+        let connection_count = self.get_connection_count();
+        let mut server_items : ~[~[uint]] = vec::from_elem(connection_count, ~[]);
+        for (i, &(ref wkey, _, _, _)) in items.iter().enumerate() {
+            for index in self.replica_indexes(*wkey).move_iter() {
+                server_items[index].push(i);
+            }
+            self.l1.remove(*wkey);
+        }
+        let mut statuses = vec::from_elem(items.len(), Network_Error);
+        for index in range(0, connection_count) {
+            if server_items[index].len() == 0 {
+                continue;
+            }
+            let cmds = server_items[index].iter().map(|&i| {
+                    match items[i] {
+                        (ref wkey, ref data, flags, exptime) => PIPE_STORE(OP_SET, wkey.clone(), data.clone(), 0, flags, exptime)
+                    }
+                }).collect::<~[PipelineCmd]>();
+            let results = self.run_on(index, |conn| conn.p_pipeline(cmds));
+            if !self.connections[index].p_is_connected() {
+                continue;
+            }
+            for (&i, result) in server_items[index].iter().zip(results.iter()) {
+                if statuses[i] != Success && result.status != Network_Error {
+                    statuses[i] = result.status;
+                }
+            }
+        }
+        return statuses;
+    }
+
+    // Connection indexes of the servers storing each of the wire keys
+    fn wire_key_indexes(&mut self, wire_keys: &[~str]) -> HashMap<~str, ~[uint]> {
+        let mut key_indexes = HashMap::<~str, ~[uint]>::new();
+        for wkey in wire_keys.iter() {
+            let indexes = self.replica_indexes(*wkey);
+            key_indexes.insert(wkey.clone(), indexes);
+        }
+        return key_indexes;
+    }
+
+    // Number of servers storing each key
+    fn replica_count(&self) -> uint {
+        return cmp::max(cmp::min(self.params.replication, self.get_connection_count()), 1);
+    }
+
+    // Indexes of the connections of the servers storing the key: the primary picked by the key value, and its
+    // successors on the ring of servers.  Simple hash % N algorithm for the primary for now.
+    // Ejected servers are skipped.  If all of them are ejected, the key is rehashed onto the servers left
+    // with params.eject_rehash, or has no server.
+    fn replica_indexes(&mut self, key: &str) -> ~[uint] {
+        self.check_probes();
+        let connection_count = self.get_connection_count();
+        // TODO: check self.params.shard
+        let primary = RustyMem::md5_mod_indexer(key, connection_count);
+        let indexes = range(0, self.replica_count()).map(|i| (primary + i) % connection_count)
+            .filter(|i| !self.health.is_ejected(*i)).collect::<~[uint]>();
+        if indexes.len() == 0 && self.params.eject_rehash {
+            let live = range(0, connection_count).filter(|i| !self.health.is_ejected(*i)).collect::<~[uint]>();
+            if live.len() > 0 {
+                return ~[ live[RustyMem::md5_mod_indexer(key, live.len())] ];
+            }
+        }
+        return indexes;
+    }
+
+    // Run the command on the connection at the index, keeping track of the health of its server.
+    // A down connection is reconnected first if its retry is due.
+    fn run_on<T>(&mut self, index: uint, cmd: &fn(&mut ~ProtoConnection) -> T) -> T {
+        if !self.connections[index].p_is_connected() && self.health.is_retry_due(index) {
+            if !self.connections[index].p_reconnect() {
+                self.connection_failed(index, "reconnect failed");
+            }
+        }
+        let was_connected = self.connections[index].p_is_connected();
+        let result = cmd(&mut self.connections[index]);
+        if self.connections[index].p_is_connected() {
+            self.health.record_success(index);
+        } else if was_connected {
+            self.connection_failed(index, "I/O error");
+        }
+        return result;
+    }
+
+    // Record the failure of the connection, and start probing its server if it's ejected.
+    fn connection_failed(&mut self, index: uint, error: &str) {
+        let ejected = self.health.record_failure(index, error, self.params.retry_down_ms, self.params.eject_after_failures);
+        if ejected {
+            let server_addr = self.connections[index].p_get_server_addr();
+            debug!( fmt!("eject server %s", server_addr) );
+            self.health.start_probe(server_addr, self.params.protocol, self.params.probe_interval_ms);
+        }
+    }
+
+    // Put back the ejected servers found up by the probes.
+    fn check_probes(&mut self) {
+        for server_addr in self.health.take_recovered().move_iter() {
+            for i in range(0, self.get_connection_count()) {
+                if self.connections[i].p_get_server_addr() == server_addr && self.health.is_ejected(i) {
+                    if self.connections[i].p_reconnect() {
+                        debug!( fmt!("server %s is back", server_addr) );
+                        self.health.record_success(i);
+                    } else {
+                        self.health.start_probe(server_addr, self.params.protocol, self.params.probe_interval_ms);
+                    }
+                }
+            }
         }
-        return &mut self.connections[index];
     }
 
     // Compute connection index of a key based on md5(key) mod connection.len()
@@ -398,22 +983,12 @@ impl RustyMem {
 
     // Distribute the keys to N partitions according to its indexer function.
     // Build a key array for each partition for the belonging keys.
-    fn distribute_keys(keys: &[&str], partition_count: uint, indexer: &fn(&str, uint)->uint) -> ~[~[~str]] {
+    fn distribute_keys(keys: &[~str], partition_count: uint, indexer: &fn(&str, uint)->uint) -> ~[~[~str]] {
         let mut array_of_keys_for_partition : ~[~[~str]] = vec::from_elem::<~[~str]>(partition_count, ~[]);
 
-        // if partition_count == 1 {
-        //     let array_at_index = &mut array_of_keys_for_partition[0];
-        //     for i in range(0, keys.len()) {
-        //         let key = keys[i].to_owned();
-        //     array_at_index.push(key);
-        // }
-
-        let connection_index_of_key : ~[uint] = keys.iter().map(|key| indexer(*key, partition_count)).collect::<~[uint]>();
-        for i in range(0, partition_count) {
-            let index = connection_index_of_key[i];
-            let key = keys[i].to_owned();
-            let array_at_index = &mut array_of_keys_for_partition[index];
-            array_at_index.push(key);
+        for key in keys.iter() {
+            let index = indexer(*key, partition_count);
+            array_of_keys_for_partition[index].push(key.clone());
         }
 
         return array_of_keys_for_partition;
@@ -422,17 +997,149 @@ impl RustyMem {
 }
 
 
+/// Storage commands going through RustyMem::store_cmd(), and of the stores in ProtoConnection.p_pipeline()
+#[deriving(Clone)]
+pub enum StoreOp {
+    OP_SET,
+    OP_CAS,
+    OP_ADD,
+    OP_REPLACE,
+    OP_APPEND,
+    OP_PREPEND,
+}
+
+
 //
 // Public defs
 //
 
 
+#[deriving(Clone)]
 pub struct MemParams {
     servers:    ~str,
     protocol:   MemProtocol,
-    shard:      ShardMethod
+    shard:      ShardMethod,
+    key_mode:   KeyMode,
+    /// Namespace prepended to every key, e.g. "app1:".  It's stripped from the returned MemData.key.
+    key_prefix: ~str,
+    /// Milliseconds to cache a namespace version locally before reading it again.
+    ns_cache_ms: uint,
+    /// Layout of the value type tags in MemData.flags
+    flag_scheme: FlagScheme,
+    /// Compress values of this size or larger.  0 to disable compression.
+    compress_threshold: uint,
+    compress_codec: CompressCodec,
+    /// Store values over max_item_size in chunks, for values larger than the item size limit of the server.
+    /// Applies to set, add, replace and cas; append and prepend are never chunked, and fail on the server
+    /// when the result is over its item size limit.
+    large_values: bool,
+    /// Max size of the data in one item, within the item size limit of the server (1MB by default).
+    /// Must be over 0 with large_values; the stores to chunk fail with Invalid_Arguments otherwise.
+    max_item_size: uint,
+    /// Max attempts of an update when other clients keep changing the value.
+    cas_attempts: uint,
+    /// Base milliseconds of the random backoff between update attempts, doubling on each attempt.
+    cas_backoff_ms: uint,
+    /// Max total bytes of the keys and data in the in-process L1 cache.  0 to disable the L1 cache.
+    l1_max_bytes: uint,
+    /// Milliseconds an entry stays in the L1 cache, bounding how stale an L1 read can be.
+    l1_ttl_ms: uint,
+    /// Number of servers storing each key: its primary server and the successors on the ring of servers.
+    /// Reads go to the first of them up, a server being down when its connection has failed or timed out.
+    /// Set io_timeout_ms to detect a hung server.  1 for no replication.
+    replication: uint,
+    /// Milliseconds to wait for a server to connect or answer a call, after which its connection is down and
+    /// the reads fail over to the replicas.  Each connection then runs in a task of its own.  0 for no timeout.
+    io_timeout_ms: uint,
+    /// Milliseconds to wait before reconnecting a failed connection.
+    retry_down_ms: uint,
+    /// Eject a server from the shard map after this many consecutive failures.  0 to never eject.
+    eject_after_failures: uint,
+    /// Rehash the keys of ejected servers onto the remaining servers, instead of failing them.
+    eject_rehash: bool,
+    /// Milliseconds between the probes of an ejected server.
+    probe_interval_ms: uint,
+    /// Clients opened by a MemPool at start, and kept open when broken ones are closed.
+    pool_min: uint,
+    /// Max clients of a MemPool, each with one connection per server.
+    pool_max: uint,
+    /// Milliseconds a checkout waits for a client when pool_max clients are in use.  0 to fail right away.
+    pool_wait_ms: uint,
+    /// Validate a pooled client idle for this many milliseconds before handing it out.  0 to not validate.
+    pool_validate_idle_ms: uint,
+    /// Record the latency, bytes and status of every call to the servers.  None to not record.
+    metrics: Option<MemMetrics>
 }
 
+impl MemParams {
+    /// Create the params with the servers and protocol, and the default settings for the rest.
+    pub fn new(servers: &str, protocol: MemProtocol) -> MemParams {
+        return MemParams {
+            servers:    servers.to_owned(),
+            protocol:   protocol,
+            shard:      HASH_MOD,
+            key_mode:   KEY_STRICT,
+            key_prefix: ~"",
+            ns_cache_ms: 1000,
+            flag_scheme: FlagScheme::rustymem(),
+            compress_threshold: 0,
+            compress_codec: COMPRESS_ZLIB,
+            large_values: false,
+            max_item_size: 1000 * 1000,
+            cas_attempts: 10,
+            cas_backoff_ms: 2,
+            l1_max_bytes: 0,
+            l1_ttl_ms: 1000,
+            replication: 1,
+            io_timeout_ms: 0,
+            retry_down_ms: 1000,
+            eject_after_failures: 3,
+            eject_rehash: false,
+            probe_interval_ms: 1000,
+            pool_min: 1,
+            pool_max: 8,
+            pool_wait_ms: 1000,
+            pool_validate_idle_ms: 30000,
+            metrics: None
+        };
+    }
+
+    // Compress the data if it's over the compress threshold and it's a whole value.  Appending to
+    // a compressed value would corrupt it, so append/prepend data are never compressed.
+    // Return None if not compressed, or compression doesn't make it smaller.
+    fn compress_data(&self, op: StoreOp, data: &[u8]) -> Option<~[u8]> {
+        let threshold = self.compress_threshold;
+        if threshold == 0 || data.len() < threshold || self.flag_scheme.compressed_flag == 0 {
+            return None;
+        }
+        match op {
+            OP_APPEND | OP_PREPEND  => return None,
+            _                       => ()
+        }
+        let cdata = compress::compress(self.compress_codec, data);
+        if cdata.len() < data.len() { Some(cdata) } else { None }
+    }
+
+    // Decode the value type of the retrieved data from its flags, and decompress compressed data.
+    // Data not compressed, e.g. written by clients without compression, are left as is.
+    fn decode_data(&self, md: &mut MemData) {
+        let scheme = &self.flag_scheme;
+        md.value_type = scheme.to_value_type(md.flags);
+        if md.value_type == VT_COMPRESSED {
+            match compress::decompress(md.data) {
+                Some(data) => {
+                    md.data = data;
+                    md.flags = md.flags & !scheme.compressed_flag;
+                    md.value_type = scheme.to_value_type(md.flags);
+                },
+                // Left as VT_COMPRESSED, which fails the type checks.
+                None => ()
+            }
+        }
+    }
+}
+
+#[deriving(Clone)]
 pub enum MemProtocol {
     /// Use Memcached ASCII protocol
     P_ASCII,
@@ -440,13 +1147,23 @@ pub enum MemProtocol {
     P_BINARY,
 }
 
+#[deriving(Clone)]
 pub enum ShardMethod {
     /// Use MD5 to hash key then mod by the server count.
     HASH_MOD,
 }
 
+#[deriving(Clone)]
+pub enum KeyMode {
+    /// Reject keys over 250 bytes or with space or control characters, with the Invalid_Key status.
+    KEY_STRICT,
+    /// Replace keys over 250 bytes or with space or control characters with "rmh:" + sha1 of the key, after the key prefix.
+    KEY_HASH_LONG,
+}
+
 
 /// Response codes of Memcached calls
+#[deriving(Eq, Clone)]
 pub enum MemStatus {
     // Ok
     Success = 0x0000,
@@ -472,6 +1189,9 @@ pub enum MemStatus {
     Network_Error = 0x0200,
     Unknown_Response = 0x0201,
     Not_Implemented = 0x0202,
+    Invalid_Key = 0x0203,
+    Type_Mismatch = 0x0204,
+    Pool_Exhausted = 0x0205,
 }
 
 impl MemStatus {
@@ -494,6 +1214,7 @@ impl MemStatus {
             "NOT_FOUND"     => Key_Not_Found,
             "DELETED"       => Success,
             "TOUCHED"       => Success,
+            "RESET"         => Success,
             "ERROR"         => Unknown_Command,
             "CLIENT_ERROR"  => Invalid_Arguments,
             "SERVER_ERROR"  => Internal_Error,
@@ -523,6 +1244,9 @@ impl MemStatus {
             0x0200 => Network_Error,
             0x0201 => Unknown_Response,
             0x0202 => Not_Implemented,
+            0x0203 => Invalid_Key,
+            0x0204 => Type_Mismatch,
+            0x0205 => Pool_Exhausted,
 
             _ => Unknown_Response
         }
@@ -531,12 +1255,14 @@ impl MemStatus {
 }
 
 
+#[deriving(Clone)]
 pub struct MemResult<T> {
     status:     MemStatus,
     value:      T
 }
 
 /// The returned result of the Get query from Memcached.
+#[deriving(Clone)]
 pub struct MemData {
     /// Key of the returned data
     key:        ~str,
@@ -545,7 +1271,9 @@ pub struct MemData {
     /// The CAS value for the next cas operation to ensure no one has changed the data in the memcached server
     cas:        u64,
     /// Flags associated with the data.
-    flags:      u32
+    flags:      u32,
+    /// The value type decoded from the flags, VT_UNKNOWN if not decoded.
+    value_type: ValueType
 }
 
 impl MemData {
@@ -559,19 +1287,42 @@ impl MemData {
         return self.data.clone();
     }
 
+    /// Return the retrieved data as bytes.  Return Type_Mismatch if the data is still compressed, i.e. failed to decompress.
+    pub fn try_bytes(&self) -> Result<~[u8], MemStatus> {
+        if self.value_type == VT_COMPRESSED {
+            return Err(Type_Mismatch);
+        }
+        return Ok(self.as_bytes());
+    }
+
     /// Return the retrieved data as str
     pub fn as_str(&self) -> ~str {
         return str::from_utf8(self.data);
     }
 
+    /// Return the retrieved data as str.  Return Type_Mismatch if the data is not stored as text.
+    pub fn try_str(&self) -> Result<~str, MemStatus> {
+        if !self.is_type_of([VT_BYTES, VT_STR, VT_INT, VT_JSON]) || !str::is_utf8(self.data) {
+            return Err(Type_Mismatch);
+        }
+        return Ok(self.as_str());
+    }
+
     /// Return the retrieved data as Json
     pub fn as_json(&self) -> Result<Json, json::Error> {
+        if !self.is_type_of([VT_BYTES, VT_STR, VT_INT, VT_JSON]) {
+            return Err(json::Error { line: 0, col: 0, msg: @~"Type_Mismatch, data is not stored as JSON" });
+        }
         return json::from_str(self.as_str());
     }
 
     /// Convert the return data string into any type that can converted from FromStr.
     /// e.g. as_type::<int>(), as_type::<bool>()
+    /// Return None if the data is not stored as string or integer.
     pub fn as_type<T: FromStr>(&self) -> Option<T> {
+        if !self.is_type_of([VT_BYTES, VT_STR, VT_INT]) {
+            return None;
+        }
         // Clean up string
         let s = self.as_str();
         let ts = s.trim();
@@ -588,6 +1339,21 @@ impl MemData {
         }
     }
 
+    /// Check the data is stored as the value type.  Untagged bytes can be read as any of the text types.
+    pub fn check_type(&self, value_type: ValueType) -> MemStatus {
+        let accepted = match value_type {
+            VT_STR | VT_JSON    => self.is_type_of([VT_BYTES, VT_STR, VT_INT, VT_JSON]),
+            VT_INT              => self.is_type_of([VT_BYTES, VT_STR, VT_INT]),
+            VT_BYTES            => self.value_type != VT_COMPRESSED,
+            _                   => self.is_type_of([value_type]),
+        };
+        return if accepted { Success } else { Type_Mismatch };
+    }
+
+    fn is_type_of(&self, value_types: &[ValueType]) -> bool {
+        return self.value_type == VT_UNKNOWN || value_types.contains(&self.value_type);
+    }
+
 }
 
 impl ToStr for MemData {
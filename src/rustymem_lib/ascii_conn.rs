@@ -19,6 +19,7 @@ use std::result::Result;
 use std::vec;
 use std::rt::io::net::tcp::TcpStream;
 use std::rt::io::{Reader, Writer};
+use std::rt::io::io_error;
 
 
 use common::strutil;
@@ -29,10 +30,15 @@ use super::super::MemStatus;
 use super::super::MemResult;
 use super::super::MemData;
 use super::super::MemcachedStat;
+use super::flags::VT_UNKNOWN;
 use super::super::Success;
+use super::super::Key_Not_Found;
+use super::super::Item_Not_Stored;
 
 
+use super::super::{OP_SET, OP_CAS, OP_ADD, OP_REPLACE, OP_APPEND, OP_PREPEND};
 use super::proto::ProtoConnection;
+use super::proto::{PipelineCmd, PIPE_STORE, PIPE_INCR, PIPE_DECR, PIPE_TOUCH, PIPE_DELETE};
 
 
 //
@@ -122,24 +128,12 @@ impl ProtoConnection for AsciiConnection {
         return self.ascii_send_simple_request(req, noreply);
     }
 
-    fn p_incr(&mut self, key: &str, inc_amount: u64, _ /*init_value*/: u64, _ /*exptime*/: uint, noreply: bool) -> MemResult<u64> {
-        // TODO: if response is NOT_FOUND, call p_set with init_value, return the init_value
-        let req = format!("incr {} {} {}\r\n", key, inc_amount, (if noreply { "noreply" } else { "" }) );
-        // TODO: get back the current value as an emulation
-        return MemResult {
-            status: self.ascii_send_simple_request(req, noreply),
-            value: 0
-        };
+    fn p_incr(&mut self, key: &str, inc_amount: u64, init_value: u64, exptime: uint, noreply: bool) -> MemResult<u64> {
+        return self.ascii_incr_cmd("incr", key, inc_amount, init_value, exptime, noreply);
     }
 
-    fn p_decr(&mut self, key: &str, dec_amount: u64, _ /*init_value*/: u64, _ /*exptime*/: uint, noreply: bool) -> MemResult<u64> {
-        // TODO: if response is NOT_FOUND, call p_set with init_value, return the init_value
-        let req = format!("decr {} {} {}\r\n", key, dec_amount, (if noreply { "noreply" } else { "" }) );
-        // TODO: get back the current value as an emulation
-        return MemResult {
-            status: self.ascii_send_simple_request(req, noreply),
-            value: 0
-        };
+    fn p_decr(&mut self, key: &str, dec_amount: u64, init_value: u64, exptime: uint, noreply: bool) -> MemResult<u64> {
+        return self.ascii_incr_cmd("decr", key, dec_amount, init_value, exptime, noreply);
     }
 
 
@@ -148,6 +142,16 @@ impl ProtoConnection for AsciiConnection {
         return self.ascii_send_simple_request(req, noreply);
     }
 
+    fn p_delete_cas(&mut self, key: &str, cas_unique: u64, noreply: bool) -> MemStatus {
+        if cas_unique == 0 {
+            return self.p_delete(key, noreply);
+        }
+        // No delete with cas in the ASCII protocol.  Emulate it with a cas of an empty item with a negative
+        // exptime, which expires the item immediately.
+        let req = format!("cas {} 0 -1 0 {} {}\r\n", key, cas_unique, (if noreply { "noreply" } else { "" }) );
+        return self.ascii_send_store_request(req, [], noreply);
+    }
+
 
     //// Retrieval command
 
@@ -162,6 +166,63 @@ impl ProtoConnection for AsciiConnection {
     }
 
 
+    //// Pipelining
+
+    // The requests are written back to back, then the response lines read in order.  An incr/decr of a missing
+    // counter is then completed with the add of ascii_incr_cmd(), one at a time.
+    fn p_pipeline(&mut self, cmds: &[PipelineCmd]) -> ~[MemResult<u64>] {
+        for cmd in cmds.iter() {
+            let req = match *cmd {
+                PIPE_STORE(op, ref key, ref data, cas, flags, exptime) => {
+                    match op {
+                        OP_SET if cas == 0  => self.ascii_format_store_cmd("set", *key, *data, flags, exptime, false),
+                        OP_SET | OP_CAS     => self.ascii_format_cas_cmd(*key, *data, cas, flags, exptime, false),
+                        OP_ADD              => self.ascii_format_store_cmd("add", *key, *data, flags, exptime, false),
+                        OP_REPLACE          => self.ascii_format_store_cmd("replace", *key, *data, flags, exptime, false),
+                        OP_APPEND           => self.ascii_format_store_cmd("append", *key, *data, 0, 0, false),
+                        OP_PREPEND          => self.ascii_format_store_cmd("prepend", *key, *data, 0, 0, false),
+                    }
+                },
+                PIPE_INCR(ref key, amount, _, _)    => format!("incr {} {}\r\n", *key, amount),
+                PIPE_DECR(ref key, amount, _, _)    => format!("decr {} {}\r\n", *key, amount),
+                PIPE_TOUCH(ref key, exptime)        => format!("touch {} {}\r\n", *key, exptime),
+                PIPE_DELETE(ref key)                => format!("delete {}\r\n", *key),
+            };
+            debug!(req);
+            self.ascii_write_data(req.as_bytes());
+            match *cmd {
+                PIPE_STORE(_, _, ref data, _, _, _) => {
+                    self.ascii_write_data(*data);
+                    self.ascii_write_data(bytes!("\r\n"));
+                },
+                _ => ()
+            }
+        }
+
+        let mut results : ~[MemResult<u64>] = ~[];
+        for cmd in cmds.iter() {
+            let result = match *cmd {
+                PIPE_INCR(*) | PIPE_DECR(*) => self.ascii_read_incr_response(),
+                _ => MemResult { status: MemStatus::ascii_to_status(self.ascii_read_line()), value: 0 }
+            };
+            results.push(result);
+        }
+
+        for (cmd, result) in cmds.iter().zip(results.mut_iter()) {
+            match *cmd {
+                PIPE_INCR(ref key, amount, init_value, exptime) if result.status == Key_Not_Found => {
+                    *result = self.ascii_incr_missing("incr", *key, amount, init_value, exptime, false);
+                },
+                PIPE_DECR(ref key, amount, init_value, exptime) if result.status == Key_Not_Found => {
+                    *result = self.ascii_incr_missing("decr", *key, amount, init_value, exptime, false);
+                },
+                _ => ()
+            }
+        }
+        return results;
+    }
+
+
     //// Other commands
 
     fn p_version(&mut self) -> Result<~str, ~str> {
@@ -180,18 +241,27 @@ impl ProtoConnection for AsciiConnection {
     }
 
     fn p_stats(&mut self) -> ~[MemcachedStat] {
-        self.ascii_write_data(bytes!("stats\r\n"));
+        return self.p_stats_group("");
+    }
+
+    fn p_stats_group(&mut self, group: &str) -> ~[MemcachedStat] {
+        let req = if group.len() == 0 { ~"stats\r\n" } else { "stats " + group + "\r\n" };
+        self.ascii_write_data(req.as_bytes());
 
         let mut stats : ~[MemcachedStat] = ~[];
         loop {
-            let stat_line = self.ascii_read_line().unwrap();
+            let stat_line = match self.ascii_read_line() {
+                Ok(line) => line,
+                Err(_)   => break
+            };
             //debug!( fmt!("stat_line: %?", stat_line) );
             let tokens = strutil::clean_split(stat_line, ' ');
             match tokens[0] {
                 "STAT" if tokens.len() >= 3  => {
+                    // Values of some groups have spaces, e.g. the state of a connection in "stats conns".
                     stats.push(MemcachedStat {
                             name:   tokens[1].to_owned(),
-                            value:  tokens[2].to_owned()
+                            value:  tokens.slice(2, tokens.len()).connect(" ")
                         });
                 },
                 "END"   =>  break,
@@ -201,6 +271,10 @@ impl ProtoConnection for AsciiConnection {
         return stats;
     }
 
+    fn p_stats_reset(&mut self) -> MemStatus {
+        return self.ascii_send_simple_request( "stats reset\r\n", false );
+    }
+
     fn p_quit(&mut self) -> MemStatus {
         return self.ascii_send_simple_request( "quit\r\n", false );
     }
@@ -211,6 +285,15 @@ impl ProtoConnection for AsciiConnection {
         return self.server_addr.to_str();
     }
 
+    fn p_is_connected(&self) -> bool {
+        return self.stream.is_some();
+    }
+
+    fn p_reconnect(&mut self) -> bool {
+        self.stream = AsciiConnection::connect_stream(&self.server_addr);
+        return self.stream.is_some();
+    }
+
 
 }
 
@@ -222,10 +305,7 @@ impl AsciiConnection {
 
         debug!("new_connection() enter");
 
-        let stream = TcpStream::connect(server_addr.get_sock_addr());
-        if stream.is_none() {
-            fail!("connect() failed")
-        }
+        let stream = AsciiConnection::connect_stream(&server_addr);
         //debug!( fmt!("stream = %?", stream) );
 
         //let mut stream = BufferedStream::new(stream);
@@ -254,10 +334,55 @@ impl AsciiConnection {
 
     }
 
+    // Start dumping the metadata of the items with "lru_crawler metadump", of the slab class or of all of them.
+    // The lines of the dump are read with ascii_read_metadump().
+    pub fn ascii_start_metadump(&mut self, slab_class: Option<uint>) {
+        let req = match slab_class {
+            Some(slab_class)    => format!("lru_crawler metadump {}\r\n", slab_class),
+            None                => ~"lru_crawler metadump all\r\n"
+        };
+        self.ascii_write_data(req.as_bytes());
+    }
+
+    // Read the next item line of the metadump, "key=... exp=... la=... cas=... fetch=... cls=... size=...".
+    // Return None at the end, or the line of the server if it can't dump, e.g. BUSY while another crawl is running.
+    pub fn ascii_read_metadump(&mut self) -> Result<Option<~str>, ~str> {
+        let line = match self.ascii_read_line() {
+            Ok(line)    => line,
+            Err(e)      => return Err(e)
+        };
+        if line.starts_with("key=") {
+            return Ok(Some(line));
+        }
+        if line.as_slice() == "END" {
+            return Ok(None);
+        }
+        return Err(line);
+    }
+
     fn ascii_get_server_addr(&self) -> ~str {
         return self.server_addr.to_str();
     }
 
+    // Connect to the server.  Return None if it can't be reached, leaving the connection down.
+    fn connect_stream(server_addr: &netutil::HostAddr) -> Option<TcpStream> {
+        let mut failed = false;
+        let stream = do io_error::cond.trap(|_| failed = true).inside {
+            TcpStream::connect(server_addr.get_sock_addr())
+        };
+        if failed || stream.is_none() {
+            debug!( fmt!("connect() failed, %s", server_addr.to_str()) );
+            return None;
+        }
+        return stream;
+    }
+
+    // The connection is dropped on an I/O error.  All following commands fail with Network_Error.
+    fn io_failed(&mut self) {
+        debug!( fmt!("I/O error, closing connection to %s", self.server_addr.to_str()) );
+        self.stream = None;
+    }
+
 
     fn ascii_format_store_cmd(&self, cmd: &str, key: &str, data: &[u8], flags: u32, exptime: uint, noreply: bool) -> ~str {
         return format!("{} {} {} {} {} {}\r\n", cmd, key, flags, exptime, data.len(), (if noreply { "noreply" } else { "" }) );
@@ -280,6 +405,55 @@ impl AsciiConnection {
         }
     }
 
+    // incr/decr with the binary protocol's semantics: a missing counter is created with init_value and exptime,
+    // by add, unless exptime is 0xFFFFFFFF.  The new value is returned.
+    fn ascii_incr_cmd(&mut self, cmd: &str, key: &str, amount: u64, init_value: u64, exptime: uint, noreply: bool) -> MemResult<u64> {
+        let req = format!("{} {} {} {}\r\n", cmd, key, amount, (if noreply { "noreply" } else { "" }) );
+        let result = self.ascii_send_incr_request(req, noreply);
+        if result.status != Key_Not_Found {
+            return result;
+        }
+        return self.ascii_incr_missing(cmd, key, amount, init_value, exptime, noreply);
+    }
+
+    // Create the missing counter of an incr/decr found NOT_FOUND with init_value, unless exptime is 0xFFFFFFFF.
+    fn ascii_incr_missing(&mut self, cmd: &str, key: &str, amount: u64, init_value: u64, exptime: uint, noreply: bool) -> MemResult<u64> {
+        if exptime as u32 == 0xFFFFFFFF {
+            return MemResult { status: Key_Not_Found, value: 0 };
+        }
+        let req = format!("{} {} {} {}\r\n", cmd, key, amount, (if noreply { "noreply" } else { "" }) );
+        let init_str = init_value.to_str();
+        let add_req = self.ascii_format_store_cmd("add", key, init_str.as_bytes(), 0, exptime, noreply);
+        match self.ascii_send_store_request(add_req, init_str.as_bytes(), noreply) {
+            Success => MemResult { status: Success, value: init_value },
+            // Created by another client in the meantime.
+            Item_Not_Stored => self.ascii_send_incr_request(req, noreply),
+            status => MemResult { status: status, value: 0 }
+        }
+    }
+
+    // The response of incr/decr is the new value, or an error status.
+    fn ascii_send_incr_request(&mut self, request: &str, noreply: bool) -> MemResult<u64> {
+        debug!(request);
+        self.ascii_write_data(request.as_bytes());
+        if noreply {
+            return MemResult { status: Success, value: 0 };
+        }
+        return self.ascii_read_incr_response();
+    }
+
+    fn ascii_read_incr_response(&mut self) -> MemResult<u64> {
+        let line = self.ascii_read_line();
+        let value = match line {
+            Ok(ref s) => from_str::<u64>(s.trim()),
+            Err(_)    => None
+        };
+        match value {
+            Some(v) => MemResult { status: Success, value: v },
+            None    => MemResult { status: MemStatus::ascii_to_status(line), value: 0 }
+        }
+    }
+
     fn ascii_send_simple_request(&mut self, request: &str, noreply: bool) -> MemStatus {
         debug!(request);
         self.ascii_write_data(request.as_bytes());
@@ -298,7 +472,10 @@ impl AsciiConnection {
         let mut mdata_list : ~[MemData] = ~[];
         let mut dummy = [0u8, ..2];
         loop {
-            let value_line = self.ascii_read_line().unwrap();
+            let value_line = match self.ascii_read_line() {
+                Ok(line) => line,
+                Err(_)   => break
+            };
             //debug!( fmt!("value_line: %?", value_line) );
             let tokens = strutil::clean_split(value_line, ' ');
             match tokens[0] {
@@ -308,10 +485,14 @@ impl AsciiConnection {
                         key:        tokens[1].to_owned(),
                         flags:      from_str::<u32>(tokens[2]).unwrap(),
                         cas:        if tokens.len() >= 5 { from_str::<u64>(tokens[4]).unwrap() } else { 0u64 },
-                        data:       vec::from_elem(bytes as uint, 0u8)
+                        data:       vec::from_elem(bytes as uint, 0u8),
+                        value_type: VT_UNKNOWN
                     };
-                    self.stream.read(mdata.data);
-                    self.stream.read(dummy);
+                    self.ascii_read_exact(mdata.data);
+                    self.ascii_read_exact(dummy);
+                    if self.stream.is_none() {
+                        break;
+                    }
                     //debug!( fmt!("mdata: %?", mdata) );
                     mdata_list.push(mdata);
                 },
@@ -324,7 +505,37 @@ impl AsciiConnection {
 
 
     fn ascii_write_data(&mut self, data: &[u8]) {
-        self.stream.write(data);
+        if self.stream.is_none() {
+            return;
+        }
+        let mut failed = false;
+        do io_error::cond.trap(|_| failed = true).inside {
+            self.stream.write(data);
+        }
+        if failed {
+            self.io_failed();
+        }
+    }
+
+    // Fill the buffer from the stream.  Return false if the connection failed.
+    fn ascii_read_exact(&mut self, buf: &mut [u8]) -> bool {
+        let len = buf.len();
+        let mut total_read = 0u;
+        while total_read < len {
+            if self.stream.is_none() {
+                return false;
+            }
+            let mut failed = false;
+            let read_len = do io_error::cond.trap(|_| failed = true).inside {
+                self.stream.read(buf.mut_slice(total_read, len))
+            };
+            match read_len {
+                Some(read_len) if !failed => total_read = total_read + read_len,
+                // EOF or error in the middle of a response
+                _ => self.io_failed()
+            }
+        }
+        return true;
     }
 
     fn ascii_read_line(&mut self) -> Result<~str, ~str> {
@@ -332,10 +543,14 @@ impl AsciiConnection {
         let mut line = ~"";
         let mut buf = [0u8, ..1];
         loop {
-            self.stream.read(buf);
+            if !self.ascii_read_exact(buf) {
+                return Err(~"Network_Error");
+            }
             match buf[0] {
                 CR => {
-                    self.stream.read(buf);
+                    if !self.ascii_read_exact(buf) {
+                        return Err(~"Network_Error");
+                    }
                     if buf[0] == LF {
                         break;
                     } else {
@@ -0,0 +1,644 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::cmp;
+use std::task;
+use std::util;
+use std::cell::Cell;
+use std::comm::{stream, oneshot, Port, PortOne, ChanOne, SharedChan, GenericChan, GenericPort, Peekable};
+use std::hashmap::HashMap;
+use std::to_str::ToStr;
+use extra::future::Future;
+use extra::json;
+use extra::json::Json;
+use extra::json::ToJson;
+
+
+use common::strutil;
+use common::timeutil;
+use super::memkey;
+use super::chunk::ChunkManifest;
+
+
+use super::super::RustyMem;
+use super::super::MemParams;
+use super::super::MemProtocol;
+use super::super::MemData;
+use super::super::MemResult;
+use super::super::MemStatus;
+use super::super::{Success, Network_Error, Invalid_Arguments};
+use super::super::ProtoConnection;
+use super::super::{StoreOp, OP_SET, OP_CAS, OP_ADD, OP_REPLACE, OP_APPEND, OP_PREPEND};
+use super::super::{PipelineCmd, PIPE_STORE, PIPE_INCR, PIPE_DECR, PIPE_TOUCH, PIPE_DELETE};
+use super::super::{ValueType, VT_BYTES, VT_STR, VT_INT, VT_JSON};
+use super::super::new_protocol_connection;
+use super::metrics::{MemMetrics, metered_connection};
+
+
+
+//
+// Asynchronous client
+//
+// Each server has a task owning its connection, serving the requests sent to it in order.  The calls of
+// AsyncMem send a request to the task of the key's server and return a Future of the result right away.
+// The requests queued up at a server task are taken in one batch.  Consecutive gets in a batch, from any
+// of the callers, are sent as one multi-get, and the other consecutive requests are pipelined: all written
+// before their responses are read, in order, and each result handed to its caller.  Keys, flags, compression
+// and chunked values are handled as in RustyMem.  The chunks of a value are stored right away, and a task
+// waits for them before storing the manifest; the chunks of a retrieved manifest are fetched with a second
+// multi-get when the future is waited on.  Replication, server ejection, namespaces and the L1 cache are
+// not supported; a down connection is retried after retry_down_ms.
+//
+
+// Max requests taken in one batch
+static MAX_BATCH: uint  = 128;
+
+
+// Request to a server task, with the channel for its result
+enum AsyncRequest {
+    REQ_GET(~[~str], ChanOne<~[MemData]>),
+    REQ_STORE(StoreOp, ~str, ~[u8], u64, u32, uint, ChanOne<MemResult<u64>>),
+    REQ_INCR(~str, u64, u64, uint, ChanOne<MemResult<u64>>),
+    REQ_DECR(~str, u64, u64, uint, ChanOne<MemResult<u64>>),
+    REQ_TOUCH(~str, uint, ChanOne<MemStatus>),
+    REQ_DELETE(~str, ChanOne<MemStatus>),
+}
+
+
+/// Asynchronous client, to be cloned and shared between tasks.  The server tasks stop when all the clones are dropped.
+/// let am = AsyncMem::new(MemParams::new("127.0.0.1", P_BINARY));
+/// let f1 = am.get_str("key1");
+/// let f2 = am.get_str("key2");
+/// println( fmt!("%? %?", f1.unwrap(), f2.unwrap()) );
+#[deriving(Clone)]
+pub struct AsyncMem {
+    priv params:    MemParams,
+    priv servers:   ~[SharedChan<AsyncRequest>],
+}
+
+impl AsyncMem {
+
+    /// Create the client, starting a task with a connection for each server in the params.
+    pub fn new(params: MemParams) -> AsyncMem {
+        let servers = strutil::clean_split(params.servers, ' ').iter().map(|addr| {
+                let (port, chan) = stream::<AsyncRequest>();
+                let port = Cell::new(port);
+                let server_addr = Cell::new(addr.to_owned());
+                let protocol = params.protocol;
+                let retry_down_ms = params.retry_down_ms;
+                let metrics = Cell::new(params.metrics.clone());
+                do task::spawn {
+                    serve_connection(server_addr.take(), protocol, retry_down_ms, metrics.take(), port.take());
+                }
+                SharedChan::new(chan)
+            }).collect::<~[SharedChan<AsyncRequest>]>();
+        return AsyncMem {
+            params:     params,
+            servers:    servers,
+        };
+    }
+
+
+    pub fn set_bytes(&self, key: &str, exptime: uint, data_bytes: &[u8]) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_SET, key, data_bytes, 0, VT_BYTES, exptime);
+    }
+
+    pub fn set_str(&self, key: &str, exptime: uint, data_str: &str) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_SET, key, data_str.as_bytes(), 0, VT_STR, exptime);
+    }
+
+    pub fn set_as<T: ToStr>(&self, key: &str, exptime: uint, value: &T) -> Future<MemResult<u64>> {
+        return self.set_str(key, exptime, value.to_str());
+    }
+
+    pub fn set_int(&self, key: &str, exptime: uint, value: u64) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_SET, key, value.to_str().as_bytes(), 0, VT_INT, exptime);
+    }
+
+    pub fn set_json<T: ToJson>(&self, key: &str, exptime: uint, data_json: &T) -> Future<MemResult<u64>> {
+        let json_str = data_json.to_json().to_str();
+        return self.store_cmd(OP_SET, key, json_str.as_bytes(), 0, VT_JSON, exptime);
+    }
+
+    pub fn cas_bytes(&self, key: &str, cas: u64, exptime: uint, data_bytes: &[u8]) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_CAS, key, data_bytes, cas, VT_BYTES, exptime);
+    }
+
+    pub fn cas_str(&self, key: &str, cas: u64, exptime: uint, data_str: &str) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_CAS, key, data_str.as_bytes(), cas, VT_STR, exptime);
+    }
+
+    pub fn cas_as<T: ToStr>(&self, key: &str, cas: u64, exptime: uint, value: &T) -> Future<MemResult<u64>> {
+        return self.cas_str(key, cas, exptime, value.to_str());
+    }
+
+    pub fn cas_json<T: ToJson>(&self, key: &str, cas: u64, exptime: uint, data_json: &T) -> Future<MemResult<u64>> {
+        let json_str = data_json.to_json().to_str();
+        return self.store_cmd(OP_CAS, key, json_str.as_bytes(), cas, VT_JSON, exptime);
+    }
+
+    pub fn add_bytes(&self, key: &str, exptime: uint, data_bytes: &[u8]) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_ADD, key, data_bytes, 0, VT_BYTES, exptime);
+    }
+
+    pub fn add_str(&self, key: &str, exptime: uint, data_str: &str) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_ADD, key, data_str.as_bytes(), 0, VT_STR, exptime);
+    }
+
+    pub fn add_as<T: ToStr>(&self, key: &str, exptime: uint, value: &T) -> Future<MemResult<u64>> {
+        return self.add_str(key, exptime, value.to_str());
+    }
+
+    pub fn add_json<T: ToJson>(&self, key: &str, exptime: uint, data_json: &T) -> Future<MemResult<u64>> {
+        let json_str = data_json.to_json().to_str();
+        return self.store_cmd(OP_ADD, key, json_str.as_bytes(), 0, VT_JSON, exptime);
+    }
+
+    pub fn replace_bytes(&self, key: &str, cas: u64, exptime: uint, data_bytes: &[u8]) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_REPLACE, key, data_bytes, cas, VT_BYTES, exptime);
+    }
+
+    pub fn replace_str(&self, key: &str, cas: u64, exptime: uint, data_str: &str) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_REPLACE, key, data_str.as_bytes(), cas, VT_STR, exptime);
+    }
+
+    pub fn replace_as<T: ToStr>(&self, key: &str, cas: u64, exptime: uint, value: &T) -> Future<MemResult<u64>> {
+        return self.replace_str(key, cas, exptime, value.to_str());
+    }
+
+    pub fn replace_json<T: ToJson>(&self, key: &str, cas: u64, exptime: uint, data_json: &T) -> Future<MemResult<u64>> {
+        let json_str = data_json.to_json().to_str();
+        return self.store_cmd(OP_REPLACE, key, json_str.as_bytes(), cas, VT_JSON, exptime);
+    }
+
+    pub fn append_bytes(&self, key: &str, data_bytes: &[u8]) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_APPEND, key, data_bytes, 0, VT_BYTES, 0);
+    }
+
+    pub fn prepend_bytes(&self, key: &str, data_bytes: &[u8]) -> Future<MemResult<u64>> {
+        return self.store_cmd(OP_PREPEND, key, data_bytes, 0, VT_BYTES, 0);
+    }
+
+
+    /// Get the data item as MemData at key.  The result is None if no data found or error.
+    pub fn get_data(&self, key: &str) -> Future<Option<MemData>> {
+        let result = Cell::new(self.get_bulk_data([key]));
+        return do Future::from_fn {
+            let mut md_list = result.take().unwrap();
+            if md_list.len() > 0 { Some(md_list.shift()) } else { None }
+        };
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Future<Option<~[u8]>> {
+        let result = Cell::new(self.get_data(key));
+        return do Future::from_fn {
+            match result.take().unwrap() {
+                Some(md) => md.try_bytes().ok(),
+                None => None
+            }
+        };
+    }
+
+    pub fn get_str(&self, key: &str) -> Future<Option<~str>> {
+        let result = Cell::new(self.get_data(key));
+        return do Future::from_fn {
+            match result.take().unwrap() {
+                Some(md) => md.try_str().ok(),
+                None => None
+            }
+        };
+    }
+
+    pub fn get_int(&self, key: &str) -> Future<Option<u64>> {
+        let result = Cell::new(self.get_data(key));
+        return do Future::from_fn {
+            match result.take().unwrap() {
+                Some(md) => md.as_type::<u64>(),
+                None => None
+            }
+        };
+    }
+
+    pub fn get_as<T: FromStr>(&self, key: &str) -> Future<Option<T>> {
+        let result = Cell::new(self.get_data(key));
+        return do Future::from_fn {
+            match result.take().unwrap() {
+                Some(md) => md.as_type::<T>(),
+                None => None
+            }
+        };
+    }
+
+    pub fn get_json(&self, key: &str) -> Future<Option<Json>> {
+        let result = Cell::new(self.get_data(key));
+        return do Future::from_fn {
+            match result.take().unwrap() {
+                Some(md) => md.as_json().ok(),
+                None => None
+            }
+        };
+    }
+
+
+    /// Get the list of data as MemData of the list of keys, with one request per server.  Invalid keys are skipped.
+    pub fn get_bulk_data(&self, keys: &[&str]) -> Future<~[MemData]> {
+        let mut key_map = HashMap::<~str, ~str>::new();
+        let mut wire_keys : ~[~str] = ~[];
+        for key in keys.iter() {
+            match self.wire_key(*key) {
+                Ok(wkey) => {
+                    if !key_map.contains_key(&wkey) {
+                        key_map.insert(wkey.clone(), key.to_owned());
+                        wire_keys.push(wkey);
+                    }
+                },
+                Err(_) => ()
+            }
+        }
+
+        let ports = Cell::new(send_gets(self.servers, wire_keys));
+        let servers = self.servers.clone();
+        let params = self.params.clone();
+        return do Future::from_fn {
+            let result = recv_gets(ports.take());
+            let mut result = join_chunks(servers, params.flag_scheme.chunked_flag, result);
+            for md in result.mut_iter() {
+                params.decode_data(md);
+                match key_map.find(&md.key) {
+                    Some(key) => md.key = key.clone(),
+                    None => ()
+                }
+            }
+            result
+        };
+    }
+
+    pub fn get_bulk_bytes(&self, keys: &[&str]) -> Future<~[(~str, ~[u8])]> {
+        let result = Cell::new(self.get_bulk_data(keys));
+        return do Future::from_fn {
+            result.take().unwrap().iter().filter_map(|md| match md.try_bytes() {
+                    Ok(bytes)   => Some(( md.key.clone(), bytes )),
+                    Err(_)      => None
+                }).collect::<~[(~str, ~[u8])]>()
+        };
+    }
+
+    /// Data that are not string are skipped.
+    pub fn get_bulk_str(&self, keys: &[&str]) -> Future<~[(~str, ~str)]> {
+        let result = Cell::new(self.get_bulk_data(keys));
+        return do Future::from_fn {
+            result.take().unwrap().iter().filter_map(|md| match md.try_str() {
+                    Ok(s)   => Some(( md.key.clone(), s )),
+                    Err(_)  => None
+                } ).collect::<~[(~str, ~str)]>()
+        };
+    }
+
+    pub fn get_bulk_as<T: FromStr>(&self, keys: &[&str]) -> Future<~[(~str, Option<T>)]> {
+        let result = Cell::new(self.get_bulk_data(keys));
+        return do Future::from_fn {
+            result.take().unwrap().iter().map(|md| ( md.key.clone(), md.as_type::<T>() ) ).collect::<~[(~str, Option<T>)]>()
+        };
+    }
+
+    pub fn get_bulk_json(&self, keys: &[&str]) -> Future<~[(~str, Result<Json, json::Error>)]> {
+        let result = Cell::new(self.get_bulk_data(keys));
+        return do Future::from_fn {
+            result.take().unwrap().iter().map(|md| ( md.key.clone(), md.as_json() ) ).collect::<~[(~str, Result<Json, json::Error>)]>()
+        };
+    }
+
+
+    /// Update a cached entry's expiration time.
+    pub fn touch(&self, key: &str, exptime: uint) -> Future<MemStatus> {
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return Future::from_value(status)
+        };
+        let (port, chan) = oneshot::<MemStatus>();
+        self.server_of(wkey).send(REQ_TOUCH(wkey, exptime, chan));
+        return status_future(port);
+    }
+
+    pub fn delete(&self, key: &str) -> Future<MemStatus> {
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return Future::from_value(status)
+        };
+        let (port, chan) = oneshot::<MemStatus>();
+        self.server_of(wkey).send(REQ_DELETE(wkey, chan));
+        return status_future(port);
+    }
+
+    /// Increment the existing 64-bit integer at the key by the inc_amount, as in RustyMem.incr().
+    pub fn incr(&self, key: &str, inc_amount: u64, init_value: u64, exptime: uint) -> Future<MemResult<u64>> {
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return Future::from_value(MemResult { status: status, value: 0 })
+        };
+        let (port, chan) = oneshot::<MemResult<u64>>();
+        self.server_of(wkey).send(REQ_INCR(wkey, inc_amount, init_value, exptime, chan));
+        return result_future(port);
+    }
+
+    /// Decrement the existing 64-bit integer at the key by the dec_amount, as in RustyMem.decr().
+    pub fn decr(&self, key: &str, dec_amount: u64, init_value: u64, exptime: uint) -> Future<MemResult<u64>> {
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return Future::from_value(MemResult { status: status, value: 0 })
+        };
+        let (port, chan) = oneshot::<MemResult<u64>>();
+        self.server_of(wkey).send(REQ_DECR(wkey, dec_amount, init_value, exptime, chan));
+        return result_future(port);
+    }
+
+
+    fn wire_key(&self, key: &str) -> Result<~str, MemStatus> {
+        return memkey::to_wire_key(self.params.key_prefix, key, self.params.key_mode);
+    }
+
+    fn server_of<'a>(&'a self, wkey: &str) -> &'a SharedChan<AsyncRequest> {
+        return &self.servers[RustyMem::md5_mod_indexer(wkey, self.servers.len())];
+    }
+
+    // Send a storage command to the server of the key, with the value type in the flags and the data
+    // compressed and chunked as in RustyMem.
+    fn store_cmd(&self, op: StoreOp, key: &str, data: &[u8], cas: u64, value_type: ValueType, exptime: uint) -> Future<MemResult<u64>> {
+        let wkey = match self.wire_key(key) {
+            Ok(k)       => k,
+            Err(status) => return Future::from_value(MemResult { status: status, value: 0 })
+        };
+        let mut flags = self.params.flag_scheme.to_flags(value_type);
+        let data = match self.params.compress_data(op, data) {
+            Some(cdata) => {
+                flags = flags | self.params.flag_scheme.compressed_flag;
+                cdata
+            },
+            None => data.to_owned()
+        };
+        let chunked = match op {
+            OP_SET | OP_ADD | OP_REPLACE | OP_CAS   => self.params.large_values && data.len() > self.params.max_item_size,
+            OP_APPEND | OP_PREPEND                  => false
+        };
+        let (port, chan) = oneshot::<MemResult<u64>>();
+        if chunked {
+            self.store_chunks(op, wkey, data, cas, flags, exptime, chan);
+        } else {
+            self.server_of(wkey).send(REQ_STORE(op, wkey, data, cas, flags, exptime, chan));
+        }
+        return result_future(port);
+    }
+
+    // Send the chunks of the large data to their servers, and have a task store the manifest at the key once
+    // they are all stored, so the manifest never refers to chunks not written yet even if the future is dropped.
+    // The result of the first failed chunk, or of the manifest, is sent to the chan.
+    fn store_chunks(&self, op: StoreOp, wkey: ~str, data: ~[u8], cas: u64, flags: u32, exptime: uint, chan: ChanOne<MemResult<u64>>) {
+        let chunk_size = self.params.max_item_size;
+        if chunk_size == 0 {
+            chan.send(MemResult { status: Invalid_Arguments, value: 0 });
+            return;
+        }
+        let chunk_flags = self.params.flag_scheme.to_flags(VT_BYTES);
+        let manifest = ChunkManifest::new(data, chunk_size);
+        let mut chunk_ports : ~[PortOne<MemResult<u64>>] = ~[];
+        for i in range(0, manifest.count) {
+            let begin = i * chunk_size;
+            let end = cmp::min(begin + chunk_size, data.len());
+            let ckey = manifest.chunk_key(wkey, i);
+            let (cport, cchan) = oneshot::<MemResult<u64>>();
+            self.server_of(ckey).send(REQ_STORE(OP_SET, ckey, data.slice(begin, end).to_owned(), 0, chunk_flags, exptime, cchan));
+            chunk_ports.push(cport);
+        }
+
+        let server = self.server_of(wkey).clone();
+        let manifest_flags = flags | self.params.flag_scheme.chunked_flag;
+        let request = Cell::new((wkey, manifest.encode(), chan));
+        let chunk_ports = Cell::new(chunk_ports);
+        do task::spawn {
+            let (wkey, manifest_data, chan) = request.take();
+            let mut result = MemResult { status: Success, value: 0 };
+            for cport in chunk_ports.take().move_iter() {
+                result = match cport.try_recv() {
+                    Some(result) => result,
+                    None => MemResult { status: Network_Error, value: 0 }
+                };
+                if result.status != Success {
+                    break;
+                }
+            }
+            if result.status != Success {
+                chan.send(result);
+            } else {
+                server.send(REQ_STORE(op, wkey, manifest_data, cas, manifest_flags, exptime, chan));
+            }
+        }
+    }
+
+}
+
+
+// Send a multi-get of the wire keys to each of their servers.
+fn send_gets(servers: &[SharedChan<AsyncRequest>], wire_keys: &[~str]) -> ~[PortOne<~[MemData]>] {
+    let key_arrays = RustyMem::distribute_keys(wire_keys, servers.len(), |key, count| RustyMem::md5_mod_indexer(key, count));
+    let mut ports : ~[PortOne<~[MemData]>] = ~[];
+    for (i, server_keys) in key_arrays.move_iter().enumerate() {
+        if server_keys.len() > 0 {
+            let (port, chan) = oneshot::<~[MemData]>();
+            servers[i].send(REQ_GET(server_keys, chan));
+            ports.push(port);
+        }
+    }
+    return ports;
+}
+
+// Data retrieved by the multi-gets.  The keys of a server task failed are not found.
+fn recv_gets(ports: ~[PortOne<~[MemData]>]) -> ~[MemData] {
+    let mut result : ~[MemData] = ~[];
+    for port in ports.move_iter() {
+        match port.try_recv() {
+            Some(md_list) => result.push_all_move(md_list),
+            None => ()
+        }
+    }
+    return result;
+}
+
+// Replace the chunk manifests in the retrieved data with the reassembled values, as in RustyMem.
+// Values with missing or inconsistent chunks are dropped, as not found.
+fn join_chunks(servers: &[SharedChan<AsyncRequest>], chunked_flag: u32, md_list: ~[MemData]) -> ~[MemData] {
+    if chunked_flag == 0 || !md_list.iter().any(|md| (md.flags & chunked_flag) != 0) {
+        return md_list;
+    }
+
+    let mut result : ~[MemData] = ~[];
+    let mut manifests : ~[(MemData, ChunkManifest)] = ~[];
+    let mut chunk_keys : ~[~str] = ~[];
+    for md in md_list.move_iter() {
+        if (md.flags & chunked_flag) == 0 {
+            result.push(md);
+        } else {
+            match ChunkManifest::decode(md.data) {
+                Some(manifest) => {
+                    chunk_keys.push_all_move(manifest.chunk_keys(md.key));
+                    manifests.push((md, manifest));
+                },
+                None => debug!( fmt!("invalid chunk manifest at %?", md.key) )
+            }
+        }
+    }
+
+    let mut chunks = HashMap::<~str, ~[u8]>::new();
+    for cmd in recv_gets(send_gets(servers, chunk_keys)).move_iter() {
+        let MemData { key: ckey, data: cdata, _ } = cmd;
+        chunks.insert(ckey, cdata);
+    }
+
+    for entry in manifests.move_iter() {
+        let (mut md, manifest) = entry;
+        match manifest.join(md.key, &chunks) {
+            Some(data) => {
+                md.data = data;
+                md.flags = md.flags & !chunked_flag;
+                result.push(md);
+            },
+            None => ()
+        }
+    }
+    return result;
+}
+
+// Result of a request, or Network_Error if its server task has failed.
+fn result_future(port: PortOne<MemResult<u64>>) -> Future<MemResult<u64>> {
+    let port = Cell::new(port);
+    return do Future::from_fn {
+        match port.take().try_recv() {
+            Some(result) => result,
+            None => MemResult { status: Network_Error, value: 0 }
+        }
+    };
+}
+
+fn status_future(port: PortOne<MemStatus>) -> Future<MemStatus> {
+    let port = Cell::new(port);
+    return do Future::from_fn {
+        match port.take().try_recv() {
+            Some(status) => status,
+            None => Network_Error
+        }
+    };
+}
+
+
+// Serve the requests to a server until all the AsyncMem clones are dropped.
+fn serve_connection(server_addr: ~str, protocol: MemProtocol, retry_down_ms: uint, metrics: Option<MemMetrics>, port: Port<AsyncRequest>) {
+    let mut conn = metered_connection(new_protocol_connection(server_addr, protocol), &metrics);
+    let mut retry_at_ms = if conn.p_is_connected() { 0 } else { timeutil::now_ms() + retry_down_ms as u64 };
+    loop {
+        let mut batch : ~[AsyncRequest] = match port.try_recv() {
+            Some(request) => ~[request],
+            None => break
+        };
+        while batch.len() < MAX_BATCH && port.peek() {
+            batch.push(port.recv());
+        }
+        if !conn.p_is_connected() && timeutil::now_ms() >= retry_at_ms {
+            conn.p_reconnect();
+        }
+        run_batch(&mut conn, batch);
+        if !conn.p_is_connected() && timeutil::now_ms() >= retry_at_ms {
+            debug!( fmt!("async connection to %s is down", server_addr) );
+            retry_at_ms = timeutil::now_ms() + retry_down_ms as u64;
+        }
+    }
+    conn.p_quit();
+}
+
+// Channel for the result of a pipelined request
+enum PipelineReply {
+    REPLY_RESULT(ChanOne<MemResult<u64>>),
+    REPLY_STATUS(ChanOne<MemStatus>),
+}
+
+// Run the requests in order, sending each run of consecutive gets as one multi-get, and each run of the
+// other requests as one pipeline.
+fn run_batch(conn: &mut ~ProtoConnection, batch: ~[AsyncRequest]) {
+    let mut gets : ~[(~[~str], ChanOne<~[MemData]>)] = ~[];
+    let mut cmds : ~[PipelineCmd] = ~[];
+    let mut replies : ~[PipelineReply] = ~[];
+    for request in batch.move_iter() {
+        let (cmd, reply) = match request {
+            REQ_GET(keys, chan) => {
+                if cmds.len() > 0 {
+                    run_pipeline(conn, util::replace(&mut cmds, ~[]), util::replace(&mut replies, ~[]));
+                }
+                gets.push((keys, chan));
+                continue;
+            },
+            REQ_STORE(op, wkey, data, cas, flags, exptime, chan) => (PIPE_STORE(op, wkey, data, cas, flags, exptime), REPLY_RESULT(chan)),
+            REQ_INCR(wkey, amount, init_value, exptime, chan)    => (PIPE_INCR(wkey, amount, init_value, exptime), REPLY_RESULT(chan)),
+            REQ_DECR(wkey, amount, init_value, exptime, chan)    => (PIPE_DECR(wkey, amount, init_value, exptime), REPLY_RESULT(chan)),
+            REQ_TOUCH(wkey, exptime, chan)                       => (PIPE_TOUCH(wkey, exptime), REPLY_STATUS(chan)),
+            REQ_DELETE(wkey, chan)                               => (PIPE_DELETE(wkey), REPLY_STATUS(chan)),
+        };
+        if gets.len() > 0 {
+            run_gets(conn, util::replace(&mut gets, ~[]));
+        }
+        cmds.push(cmd);
+        replies.push(reply);
+    }
+    if gets.len() > 0 {
+        run_gets(conn, gets);
+    }
+    if cmds.len() > 0 {
+        run_pipeline(conn, cmds, replies);
+    }
+}
+
+// Write all the requests before reading their responses, and hand each result to its request.
+fn run_pipeline(conn: &mut ~ProtoConnection, cmds: ~[PipelineCmd], replies: ~[PipelineReply]) {
+    let results = conn.p_pipeline(cmds);
+    for (reply, result) in replies.move_iter().zip(results.move_iter()) {
+        match reply {
+            REPLY_RESULT(chan) => { chan.try_send(result); },
+            REPLY_STATUS(chan) => { chan.try_send(result.status); }
+        }
+    }
+}
+
+// Get the keys of all the get requests with one multi-get, and hand each request the data of its keys.
+fn run_gets(conn: &mut ~ProtoConnection, gets: ~[(~[~str], ChanOne<~[MemData]>)]) {
+    let mut found = HashMap::<~str, MemData>::new();
+    {
+        let mut all_keys : ~[&str] = ~[];
+        for &(ref keys, _) in gets.iter() {
+            for key in keys.iter() {
+                if !all_keys.contains(&key.as_slice()) {
+                    all_keys.push(key.as_slice());
+                }
+            }
+        }
+        for md in conn.p_gets(all_keys).move_iter() {
+            found.insert(md.key.clone(), md);
+        }
+    }
+    for (keys, chan) in gets.move_iter() {
+        let md_list = keys.iter().filter_map(|key| found.find(key).map(|md| md.clone())).collect::<~[MemData]>();
+        chan.try_send(md_list);
+    }
+}
+
@@ -20,6 +20,7 @@ use std::str;
 use std::vec;
 use std::rt::io::net::tcp::TcpStream;
 use std::rt::io::{Reader, Writer};
+use std::rt::io::io_error;
 use std::unstable::intrinsics;
 
 
@@ -31,7 +32,12 @@ use super::super::MemStatus;
 use super::super::MemResult;
 use super::super::MemData;
 use super::super::MemcachedStat;
+use super::super::Network_Error;
+use super::super::Success;
+use super::super::{OP_SET, OP_CAS, OP_ADD, OP_REPLACE, OP_APPEND, OP_PREPEND};
+use super::flags::VT_UNKNOWN;
 use super::proto::ProtoConnection;
+use super::proto::{PipelineCmd, PIPE_STORE, PIPE_INCR, PIPE_DECR, PIPE_TOUCH, PIPE_DELETE};
 
 
 
@@ -148,24 +154,8 @@ impl ProtoConnection for BinaryConnection {
     //// Data command
     
     fn p_touch(&mut self, key: &str, exptime: uint, _ /*noreply*/: bool) -> MemStatus {
-        let key_bytes = key.as_bytes();
-        let mut header = BinaryConnection::new_req_header(BP_OP_Touch, key_bytes.len() as u16, 4u8, 0, 0);
-        debug!( fmt!("  req: %?", header) );
-
-        let mut body = vec::from_elem(header.body_len as uint, 0u8);
-        let mut offset = 0;
-        offset = ioutil::pack_u32_be(body, offset, exptime as u32);
-        ioutil::copy_bytes(body, offset, key_bytes, 0, key_bytes.len());
-
-        self.write_header(&header);
-        self.write_data(body);
-
-        self.read_header(&mut header);
-        debug!( fmt!("  res: %?", header) );
-        let buf = self.read_upto(header.get_data_len());
-        debug!( fmt!("  data: %?", str::from_utf8(buf)) );
-
-        return MemStatus::map_status(header.status_vbucket);
+        self.bc_send_touch(key, exptime, 0);
+        return self.bc_recv_result().status;
     }
 
     fn p_incr(&mut self, key: &str, inc_amount: u64, init_value: u64, exptime: uint, noreply: bool) -> MemResult<u64> {
@@ -177,23 +167,13 @@ impl ProtoConnection for BinaryConnection {
     }
 
 
-    fn p_delete(&mut self, key: &str, _ /*noreply*/: bool) -> MemStatus {
-        let key_bytes = key.as_bytes();
-        let mut header = BinaryConnection::new_req_header(BP_OP_Delete, key_bytes.len() as u16, 0, 0, 0);
-        debug!( fmt!("  req: %?", header) );
-
-        let mut body = vec::from_elem(header.body_len as uint, 0u8);
-        ioutil::copy_bytes(body, 0, key_bytes, 0, key_bytes.len());
-
-        self.write_header(&header);
-        self.write_data(body);
-
-        self.read_header(&mut header);
-        debug!( fmt!("  res: %?", header) );
-        let buf = self.read_upto(header.get_data_len());
-        debug!( fmt!("  data: %?", str::from_utf8(buf)) );
+    fn p_delete(&mut self, key: &str, noreply: bool) -> MemStatus {
+        return self.p_delete_cas(key, 0, noreply);
+    }
 
-        return MemStatus::map_status(header.status_vbucket);
+    fn p_delete_cas(&mut self, key: &str, cas_unique: u64, _ /*noreply*/: bool) -> MemStatus {
+        self.bc_send_delete(key, cas_unique, 0);
+        return self.bc_recv_result().status;
     }
 
 
@@ -237,6 +217,9 @@ impl ProtoConnection for BinaryConnection {
         loop {
             self.read_header(&mut header);
             debug!( fmt!("  res: %?", header) );
+            if self.stream.is_none() {
+                break;
+            }
             let extra   = self.read_upto(header.extra_len as uint);
             let key     = self.read_upto(header.key_len as uint);
             let data    = self.read_upto(header.get_data_len());
@@ -245,7 +228,8 @@ impl ProtoConnection for BinaryConnection {
                     key:        str::from_utf8(key),
                     flags:      ioutil::unpack_u32_be(extra, 0),
                     cas:        header.cas,
-                    data:       data
+                    data:       data,
+                    value_type: VT_UNKNOWN
                 };
                 mdata_list.push(mdata);
             }
@@ -259,6 +243,43 @@ impl ProtoConnection for BinaryConnection {
     }
 
 
+    //// Pipelining
+
+    // The requests are written with their index as opaque, and the responses matched back by it.
+    fn p_pipeline(&mut self, cmds: &[PipelineCmd]) -> ~[MemResult<u64>] {
+        for (i, cmd) in cmds.iter().enumerate() {
+            let opaque = i as u32;
+            match *cmd {
+                PIPE_STORE(op, ref key, ref data, cas, flags, exptime) => {
+                    match op {
+                        OP_SET | OP_CAS => self.bc_send_store(BP_OP_Set, *key, *data, cas, flags, exptime, opaque),
+                        OP_ADD          => self.bc_send_store(BP_OP_Add, *key, *data, cas, flags, exptime, opaque),
+                        OP_REPLACE      => self.bc_send_store(BP_OP_Replace, *key, *data, cas, flags, exptime, opaque),
+                        OP_APPEND       => self.bc_send_append(BP_OP_Append, *key, *data, opaque),
+                        OP_PREPEND      => self.bc_send_append(BP_OP_Prepend, *key, *data, opaque),
+                    }
+                },
+                PIPE_INCR(ref key, amount, init_value, exptime) => self.bc_send_inc(BP_OP_Increment, *key, exptime, amount, init_value, opaque),
+                PIPE_DECR(ref key, amount, init_value, exptime) => self.bc_send_inc(BP_OP_Decrement, *key, exptime, amount, init_value, opaque),
+                PIPE_TOUCH(ref key, exptime)                    => self.bc_send_touch(*key, exptime, opaque),
+                PIPE_DELETE(ref key)                            => self.bc_send_delete(*key, 0, opaque),
+            }
+        }
+
+        let mut results = vec::from_elem(cmds.len(), MemResult { status: Network_Error, value: 0u64 });
+        for _ in range(0, cmds.len()) {
+            let (opaque, result) = self.bc_recv_opaque_result();
+            if self.stream.is_none() {
+                break;
+            }
+            if (opaque as uint) < results.len() {
+                results[opaque as uint] = result;
+            }
+        }
+        return results;
+    }
+
+
     //// Other commands
 
     fn p_version(&mut self) -> Result<~str, ~str> {
@@ -268,6 +289,9 @@ impl ProtoConnection for BinaryConnection {
         self.read_header(&mut header);
         debug!( fmt!("  res: %?", header) );
         let buf = self.read_upto(header.get_data_len());
+        if self.stream.is_none() {
+            return Err(~"Network_Error");
+        }
         Ok(str::from_utf8(buf))
     }
 
@@ -286,7 +310,7 @@ impl ProtoConnection for BinaryConnection {
         let buf = self.read_upto(header.get_data_len());
         debug!( fmt!("  data: %?", str::from_utf8(buf)) );
 
-        return MemStatus::map_status(header.status_vbucket);
+        return self.bc_status(&header);
     }
 
     fn p_flush(&mut self, delay_in_seconds: uint, _ /*noreply*/: bool) -> MemStatus {
@@ -302,14 +326,21 @@ impl ProtoConnection for BinaryConnection {
         self.read_header(&mut header);
         debug!( fmt!("  res: %?", header) );
 
-        return MemStatus::map_status(header.status_vbucket);
+        return self.bc_status(&header);
     }
 
     fn p_stats(&mut self) -> ~[MemcachedStat] {
-        let mut header = BinaryConnection::new_req_header(BP_OP_Stat, 0, 0, 0, 0);
+        return self.p_stats_group("");
+    }
+
+    // The group goes in the key of the Stat request.  The stats come back one per response packet, until one with no key.
+    fn p_stats_group(&mut self, group: &str) -> ~[MemcachedStat] {
+        let key_bytes = group.as_bytes();
+        let mut header = BinaryConnection::new_req_header(BP_OP_Stat, key_bytes.len() as u16, 0, 0, 0);
         debug!( fmt!("  req: %?", header) );
 
         self.write_header(&header);
+        self.write_data(key_bytes);
 
         let mut stats : ~[MemcachedStat] = ~[];
         loop {
@@ -318,6 +349,11 @@ impl ProtoConnection for BinaryConnection {
             if header.key_len == 0 && header.get_data_len() == 0 {
                 break;
             }
+            if self.bc_status(&header) != Success {
+                // Unknown group, with the error message in the data
+                self.read_upto(header.body_len as uint);
+                break;
+            }
             let name  = self.read_upto(header.key_len as uint);
             let value = self.read_upto(header.get_data_len());
             //debug!( fmt!("  stat: %? = %?", str::from_utf8(name), str::from_utf8(value)) );
@@ -330,13 +366,26 @@ impl ProtoConnection for BinaryConnection {
         return stats;
     }
 
+    fn p_stats_reset(&mut self) -> MemStatus {
+        let key_bytes = "reset".as_bytes();
+        let mut header = BinaryConnection::new_req_header(BP_OP_Stat, key_bytes.len() as u16, 0, 0, 0);
+        debug!( fmt!("  req: %?", header) );
+        self.write_header(&header);
+        self.write_data(key_bytes);
+        self.read_header(&mut header);
+        debug!( fmt!("  res: %?", header) );
+        // The body is empty, or the error message.
+        self.read_upto(header.body_len as uint);
+        return self.bc_status(&header);
+    }
+
     fn p_quit(&mut self) -> MemStatus {
         let mut header = BinaryConnection::new_req_header(BP_OP_Quit, 0, 0, 0, 0);
         debug!( fmt!("  req: %?", header) );
         self.write_header(&header);
         self.read_header(&mut header);
         debug!( fmt!("  res: %?", header) );
-        return MemStatus::map_status(header.status_vbucket);
+        return self.bc_status(&header);
     }
 
     // Server config
@@ -344,6 +393,15 @@ impl ProtoConnection for BinaryConnection {
         return self.server_addr.to_str();
     }
 
+    fn p_is_connected(&self) -> bool {
+        return self.stream.is_some();
+    }
+
+    fn p_reconnect(&mut self) -> bool {
+        self.stream = BinaryConnection::connect_stream(&self.server_addr);
+        return self.stream.is_some();
+    }
+
 
 }
 
@@ -353,10 +411,7 @@ impl BinaryConnection {
     pub fn new_connection(server_addr: netutil::HostAddr) -> BinaryConnection {
         debug!("new_connection() enter");
 
-        let stream = TcpStream::connect(server_addr.get_sock_addr());
-        if stream.is_none() {
-            fail!("connect() failed")
-        }
+        let stream = BinaryConnection::connect_stream(&server_addr);
 
         return BinaryConnection {
             server_addr:    server_addr,
@@ -365,9 +420,55 @@ impl BinaryConnection {
     }
 
 
+    // Connect to the server.  Return None if it can't be reached, leaving the connection down.
+    fn connect_stream(server_addr: &netutil::HostAddr) -> Option<TcpStream> {
+        let mut failed = false;
+        let stream = do io_error::cond.trap(|_| failed = true).inside {
+            TcpStream::connect(server_addr.get_sock_addr())
+        };
+        if failed || stream.is_none() {
+            debug!( fmt!("connect() failed, %s", server_addr.to_str()) );
+            return None;
+        }
+        return stream;
+    }
+
+    // Status of the response.  A header read from a broken connection is Network_Error, not its zeroed status.
+    fn bc_status(&self, header: &PacketHeader) -> MemStatus {
+        if self.stream.is_none() {
+            return Network_Error;
+        }
+        return MemStatus::map_status(header.status_vbucket);
+    }
+
+    // The connection is dropped on an I/O error.  All following commands fail with Network_Error.
+    fn io_failed(&mut self) {
+        debug!( fmt!("I/O error, closing connection to %s", self.server_addr.to_str()) );
+        self.stream = None;
+    }
+
     fn bc_store_cmd(&mut self,  opcode: u8,  key: &str,  data: &[u8], cas: u64,  flags: u32,  exptime: uint,  _ /*noreply*/: bool) -> MemResult<u64> {
+        self.bc_send_store(opcode, key, data, cas, flags, exptime, 0);
+        return self.bc_recv_result();
+    }
+
+    fn bc_append_cmd(&mut self,  opcode: u8,  key: &str,  data: &[u8], _ /*noreply*/: bool) -> MemResult<u64> {
+        self.bc_send_append(opcode, key, data, 0);
+        return self.bc_recv_result();
+    }
+
+    fn bc_inc_cmd(&mut self,  opcode: u8,  key: &str,  exptime: uint,  inc_amount: u64, init_value: u64,  _ /*noreply*/: bool) -> MemResult<u64> {
+        self.bc_send_inc(opcode, key, exptime, inc_amount, init_value, 0);
+        return self.bc_recv_result();
+    }
+
+
+    // The requests are sent with an opaque value, returned in the client_ctx of their responses.
+
+    fn bc_send_store(&mut self,  opcode: u8,  key: &str,  data: &[u8], cas: u64,  flags: u32,  exptime: uint,  opaque: u32) {
         let key_bytes = key.as_bytes();
         let mut header = BinaryConnection::new_req_header(opcode, key_bytes.len() as u16, 4u8 + 4, data.len(), cas);
+        header.client_ctx = opaque;
         debug!( fmt!("  req: %?", header) );
 
         let mut body = vec::from_elem(header.body_len as uint, 0u8);
@@ -379,21 +480,12 @@ impl BinaryConnection {
 
         self.write_header(&header);
         self.write_data(body);
-
-        self.read_header(&mut header);
-        debug!( fmt!("  res: %?", header) );
-        let buf = self.read_upto(header.get_data_len());
-        debug!( fmt!("  data: %?", str::from_utf8(buf)) );
-
-        return MemResult::<u64> {
-            status:     MemStatus::map_status(header.status_vbucket),
-            value:      header.cas
-        };
     }
 
-    fn bc_append_cmd(&mut self,  opcode: u8,  key: &str,  data: &[u8], _ /*noreply*/: bool) -> MemResult<u64> {
+    fn bc_send_append(&mut self,  opcode: u8,  key: &str,  data: &[u8], opaque: u32) {
         let key_bytes = key.as_bytes();
         let mut header = BinaryConnection::new_req_header(opcode, key_bytes.len() as u16, 0, data.len(), 0);
+        header.client_ctx = opaque;
         debug!( fmt!("  req: %?", header) );
 
         let mut body = vec::from_elem(header.body_len as uint, 0u8);
@@ -403,21 +495,12 @@ impl BinaryConnection {
 
         self.write_header(&header);
         self.write_data(body);
-
-        self.read_header(&mut header);
-        debug!( fmt!("  res: %?", header) );
-        let buf = self.read_upto(header.get_data_len());
-        debug!( fmt!("  data: %?", str::from_utf8(buf)) );
-
-        return MemResult::<u64> {
-            status:     MemStatus::map_status(header.status_vbucket),
-            value:      header.cas
-        };
     }
 
-    fn bc_inc_cmd(&mut self,  opcode: u8,  key: &str,  exptime: uint,  inc_amount: u64, init_value: u64,  _ /*noreply*/: bool) -> MemResult<u64> {
+    fn bc_send_inc(&mut self,  opcode: u8,  key: &str,  exptime: uint,  inc_amount: u64, init_value: u64,  opaque: u32) {
         let key_bytes = key.as_bytes();
         let mut header = BinaryConnection::new_req_header(opcode, key_bytes.len() as u16, 8u8 + 8 + 4, 0, 0);
+        header.client_ctx = opaque;
         debug!( fmt!("  req: %?", header) );
 
         let mut body = vec::from_elem(header.body_len as uint, 0u8);
@@ -429,17 +512,60 @@ impl BinaryConnection {
 
         self.write_header(&header);
         self.write_data(body);
+    }
 
+    fn bc_send_touch(&mut self, key: &str, exptime: uint, opaque: u32) {
+        let key_bytes = key.as_bytes();
+        let mut header = BinaryConnection::new_req_header(BP_OP_Touch, key_bytes.len() as u16, 4u8, 0, 0);
+        header.client_ctx = opaque;
+        debug!( fmt!("  req: %?", header) );
+
+        let mut body = vec::from_elem(header.body_len as uint, 0u8);
+        let mut offset = 0;
+        offset = ioutil::pack_u32_be(body, offset, exptime as u32);
+        ioutil::copy_bytes(body, offset, key_bytes, 0, key_bytes.len());
+
+        self.write_header(&header);
+        self.write_data(body);
+    }
+
+    fn bc_send_delete(&mut self, key: &str, cas_unique: u64, opaque: u32) {
+        let key_bytes = key.as_bytes();
+        let mut header = BinaryConnection::new_req_header(BP_OP_Delete, key_bytes.len() as u16, 0, 0, cas_unique);
+        header.client_ctx = opaque;
+        debug!( fmt!("  req: %?", header) );
+
+        let mut body = vec::from_elem(header.body_len as uint, 0u8);
+        ioutil::copy_bytes(body, 0, key_bytes, 0, key_bytes.len());
+
+        self.write_header(&header);
+        self.write_data(body);
+    }
+
+    // Read the response of a store, append, incr/decr, touch or delete.  The value is the new value of an
+    // incr/decr, and the cas for the others.
+    fn bc_recv_result(&mut self) -> MemResult<u64> {
+        let (_, result) = self.bc_recv_opaque_result();
+        return result;
+    }
+
+    // Read the response with the opaque value of its request.
+    fn bc_recv_opaque_result(&mut self) -> (u32, MemResult<u64>) {
+        let mut header = BinaryConnection::new_req_header(0, 0, 0, 0, 0);
         self.read_header(&mut header);
         debug!( fmt!("  res: %?", header) );
-        let buf = self.read_upto(header.get_data_len());
-        debug!( fmt!("  data: %?", str::from_utf8(buf)) );
-        let new_value = if buf.len() == 8 && header.status_vbucket == 0 { ioutil::unpack_u64_be(buf, 0) } else { 0 };
-
-        return MemResult::<u64> {
-            status:     MemStatus::map_status(header.status_vbucket),
-            value:      new_value
+        let body = self.read_upto(header.body_len as uint);
+        let data = body.slice(header.extra_len as uint + header.key_len as uint, body.len());
+        debug!( fmt!("  data: %?", str::from_utf8(data)) );
+
+        let status = self.bc_status(&header);
+        let value = match header.opcode {
+            BP_OP_Increment | BP_OP_Decrement => {
+                if data.len() == 8 && status == Success { ioutil::unpack_u64_be(data, 0) } else { 0 }
+            },
+            _ => header.cas
         };
+        return (header.client_ctx, MemResult::<u64> { status: status, value: value });
     }
 
 
@@ -460,7 +586,16 @@ impl BinaryConnection {
 
     fn write_data(&mut self, data: &[u8]) {
         //debug!( fmt!("write data: %?", data) );
-        self.stream.write(data);
+        if self.stream.is_none() {
+            return;
+        }
+        let mut failed = false;
+        do io_error::cond.trap(|_| failed = true).inside {
+            self.stream.write(data);
+        }
+        if failed {
+            self.io_failed();
+        }
     }
 
     fn write_header(&mut self, header: &PacketHeader) {
@@ -476,7 +611,8 @@ impl BinaryConnection {
     }
 
     fn read_any(&mut self, buf: &mut [u8]) {
-        self.stream.read(buf);
+        let len = buf.len();
+        self.read_buf_upto(buf, 0, len);
     }
 
     fn read_upto(&mut self, len_to_read: uint) -> ~[u8] {
@@ -487,14 +623,19 @@ impl BinaryConnection {
 
     fn read_buf_upto(&mut self, buf: &mut [u8], offset: uint, len_to_read: uint) {
         let mut total_read = 0u;
-        while total_read < len_to_read {
+        while total_read < len_to_read && self.stream.is_some() {
             let remaining_len = len_to_read - total_read;
             let begin = offset + total_read;
             let end   = offset + total_read + remaining_len;
             let slice_buf = buf.mut_slice(begin, end);
-            match self.stream.read(slice_buf) {
-                Some(read_len) => total_read = total_read + read_len,
-                None => break // { println( fmt!("read_upto error: %?", s) ); break; }
+            let mut failed = false;
+            let read_len = do io_error::cond.trap(|_| failed = true).inside {
+                self.stream.read(slice_buf)
+            };
+            match read_len {
+                Some(read_len) if !failed => total_read = total_read + read_len,
+                // EOF or error in the middle of a response
+                _ => self.io_failed()
             }
         }
     }
@@ -0,0 +1,138 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::rand;
+use std::str;
+use std::hashmap::HashMap;
+use extra::md5::Md5;
+use extra::digest::Digest;
+
+
+use common::strutil;
+use super::memkey;
+
+
+
+//
+// Chunked storage of large values
+//
+// A value over MemParams.max_item_size is split into chunks stored at derived keys, "key:chunk:gen:index".
+// The key itself holds a manifest with the chunk count, total length, and MD5 checksum of the value.
+// The generation makes the chunks of each write unique, so a reader never mixes the chunks of two writes.
+// Chunks of the previous writes are left to expire or be evicted.
+//
+
+static MANIFEST_MAGIC: &'static str = "RMCHUNK1";
+
+
+/// Manifest of a value stored in chunks
+pub struct ChunkManifest {
+    generation:     ~str,
+    count:          uint,
+    total_len:      uint,
+    checksum:       ~str,
+}
+
+impl ChunkManifest {
+
+    /// Create the manifest of the data, split in chunks of chunk_size.  chunk_size must be over 0.
+    pub fn new(data: &[u8], chunk_size: uint) -> ChunkManifest {
+        return ChunkManifest {
+            generation: rand::random::<u64>().to_str_radix(16),
+            count:      (data.len() + chunk_size - 1) / chunk_size,
+            total_len:  data.len(),
+            checksum:   md5_hex(data),
+        };
+    }
+
+    /// Parse the manifest.  Return None if it's not a valid manifest.
+    pub fn decode(data: &[u8]) -> Option<ChunkManifest> {
+        if !str::is_utf8(data) {
+            return None;
+        }
+        let manifest_str = str::from_utf8(data);
+        let tokens = strutil::clean_split(manifest_str, ' ');
+        if tokens.len() != 5 || tokens[0] != MANIFEST_MAGIC {
+            return None;
+        }
+        let count = from_str::<uint>(tokens[2]);
+        let total_len = from_str::<uint>(tokens[3]);
+        if count.is_none() || total_len.is_none() {
+            return None;
+        }
+        return Some(ChunkManifest {
+                generation: tokens[1].to_owned(),
+                count:      count.unwrap(),
+                total_len:  total_len.unwrap(),
+                checksum:   tokens[4].to_owned(),
+            });
+    }
+
+    pub fn encode(&self) -> ~[u8] {
+        let manifest_str = format!("{} {} {} {} {}", MANIFEST_MAGIC, self.generation, self.count, self.total_len, self.checksum);
+        return manifest_str.into_bytes();
+    }
+
+    /// Wire key of the chunk at index, derived from the wire key of the value.
+    pub fn chunk_key(&self, wire_key: &str, index: uint) -> ~str {
+        let ckey = format!("{}:chunk:{}:{}", wire_key, self.generation, index);
+        return if memkey::is_valid_key(ckey) { ckey } else { memkey::hash_key(ckey) };
+    }
+
+    /// Wire keys of all the chunks
+    pub fn chunk_keys(&self, wire_key: &str) -> ~[~str] {
+        return range(0, self.count).map(|i| self.chunk_key(wire_key, i)).collect::<~[~str]>();
+    }
+
+    /// Reassemble the value from the retrieved chunks, mapped by their wire keys.
+    /// Return None if any chunk is missing or the value doesn't match the length and checksum.
+    pub fn join(&self, wire_key: &str, chunks: &HashMap<~str, ~[u8]>) -> Option<~[u8]> {
+        let mut data : ~[u8] = ~[];
+        for ckey in self.chunk_keys(wire_key).iter() {
+            match chunks.find(ckey) {
+                Some(chunk) => data.push_all(*chunk),
+                None => {
+                    debug!( fmt!("missing chunk %? of %?", ckey, wire_key) );
+                    return None;
+                }
+            }
+        }
+        if data.len() != self.total_len || md5_hex(data) != self.checksum {
+            debug!( fmt!("inconsistent chunks of %?", wire_key) );
+            return None;
+        }
+        return Some(data);
+    }
+
+}
+
+
+/// Whether the key is the key of a chunk, "key:chunk:gen:index".  Chunk keys too long and hashed can't be told.
+pub fn is_chunk_key(key: &str) -> bool {
+    let tokens = key.split_iter(':').collect::<~[&str]>();
+    let n = tokens.len();
+    return n >= 4 && tokens[n - 3] == "chunk"
+        && tokens[n - 2].len() > 0 && tokens[n - 2].iter().all(|c| c.is_digit_radix(16))
+        && tokens[n - 1].len() > 0 && tokens[n - 1].iter().all(|c| c.is_digit());
+}
+
+fn md5_hex(data: &[u8]) -> ~str {
+    let mut digest = Md5::new();
+    digest.input(data);
+    return digest.result_str();
+}
+
@@ -0,0 +1,209 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::io;
+use std::str;
+use std::task;
+use std::cell::Cell;
+use std::result::Result;
+use extra::json;
+use extra::ebml;
+use extra::serialize::{Encodable, Decodable};
+
+
+use super::super::RustyMem;
+use super::super::MemData;
+use super::super::MemResult;
+use super::super::MemStatus;
+use super::super::Success;
+use super::super::Type_Mismatch;
+use super::super::OP_SET;
+use super::flags;
+use super::flags::{ValueType, VT_BYTES, VT_JSON, VT_SERIALIZED};
+
+
+
+//
+// Pluggable value codecs
+//
+
+
+/// Codec to encode a value of type T into bytes to store, and decode it back.
+/// The codec id is recorded in MemData.flags so the data is decoded with the same codec.
+pub trait ValueCodec<T> {
+    /// Id of the codec, 1 to 255.  0 for data not needing a codec to read, e.g. raw bytes.
+    fn codec_id(&self) -> u32;
+
+    /// Value type recorded in the flags with the codec id.
+    fn value_type(&self) -> ValueType;
+
+    fn encode(&self, value: &T) -> ~[u8];
+
+    /// Decode the data.  Return None if the data can't be decoded.
+    fn decode(&self, data: &[u8]) -> Option<T>;
+}
+
+
+/// JSON codec for types implementing Encodable/Decodable for JSON.  The data can be read with get_json() too.
+/// Data not decoding to the type, e.g. JSON of another structure, is a Type_Mismatch in try_value().
+pub struct JsonCodec;
+
+impl<T: Send + Encodable<json::Encoder> + Decodable<json::Decoder>> ValueCodec<T> for JsonCodec {
+
+    fn codec_id(&self) -> u32 { 1 }
+
+    fn value_type(&self) -> ValueType { VT_JSON }
+
+    fn encode(&self, value: &T) -> ~[u8] {
+        let json_str = do io::with_str_writer |wr| {
+            let mut encoder = json::Encoder::new(wr);
+            value.encode(&mut encoder);
+        };
+        return json_str.into_bytes();
+    }
+
+    fn decode(&self, data: &[u8]) -> Option<T> {
+        if !str::is_utf8(data) {
+            return None;
+        }
+        let json = match json::from_str(str::from_utf8(data)) {
+            Ok(json) => Cell::new(json),
+            Err(_) => return None
+        };
+        // The decoder fails the task when the JSON doesn't have the shape of T.
+        return task::try(|| {
+                let mut decoder = json::Decoder::new(json.take());
+                Decodable::decode(&mut decoder)
+            }).ok();
+    }
+}
+
+
+/// Compact binary codec using EBML, for types implementing Encodable/Decodable for EBML.
+/// Floats are stored in full precision.
+pub struct EbmlCodec;
+
+impl<T: Send + Encodable<ebml::writer::Encoder> + Decodable<ebml::reader::Decoder>> ValueCodec<T> for EbmlCodec {
+
+    fn codec_id(&self) -> u32 { 2 }
+
+    fn value_type(&self) -> ValueType { VT_SERIALIZED }
+
+    fn encode(&self, value: &T) -> ~[u8] {
+        return do io::with_bytes_writer |wr| {
+            let mut encoder = ebml::writer::Encoder(wr);
+            value.encode(&mut encoder);
+        };
+    }
+
+    fn decode(&self, data: &[u8]) -> Option<T> {
+        if data.len() == 0 {
+            return None;
+        }
+        // The decoder fails the task on data not encoding a T.
+        let data = Cell::new(data.to_owned());
+        return task::try(|| {
+                let doc = ebml::reader::Doc(@data.take());
+                let mut decoder = ebml::reader::Decoder(doc);
+                Decodable::decode(&mut decoder)
+            }).ok();
+    }
+}
+
+
+/// Raw bytes codec, storing the bytes as is.
+pub struct BytesCodec;
+
+impl ValueCodec<~[u8]> for BytesCodec {
+
+    fn codec_id(&self) -> u32 { 0 }
+
+    fn value_type(&self) -> ValueType { VT_BYTES }
+
+    fn encode(&self, value: &~[u8]) -> ~[u8] {
+        return value.clone();
+    }
+
+    fn decode(&self, data: &[u8]) -> Option<~[u8]> {
+        return Some(data.to_owned());
+    }
+}
+
+
+impl MemData {
+
+    /// Decode the data with the codec.  Return Type_Mismatch if the data was written by another codec,
+    /// or the data can't be decoded.
+    pub fn try_value<T>(&self, codec: &ValueCodec<T>) -> Result<T, MemStatus> {
+        if !self.is_codec_of(codec) {
+            return Err(Type_Mismatch);
+        }
+        match codec.decode(self.data) {
+            Some(value) => Ok(value),
+            None => Err(Type_Mismatch)
+        }
+    }
+
+    // Data written by a codec must match its codec id.  Data not written by a codec, e.g. by set_json(),
+    // is checked by the value type of the codec.
+    fn is_codec_of<T>(&self, codec: &ValueCodec<T>) -> bool {
+        let codec_id = flags::codec_id_of(self.flags);
+        if codec_id != 0 {
+            return codec_id == codec.codec_id();
+        }
+        return self.check_type(codec.value_type()) == Success;
+    }
+
+}
+
+
+impl RustyMem {
+
+    /// Set the value encoded by the codec at key in memcached, with the expiration exptime in seconds.  Setting exptime to 0 for no expiration.
+    /// rm.set_value("user1", 0, &EbmlCodec, &user)
+    pub fn set_value<T, C: ValueCodec<T>>(&mut self, key: &str, exptime: uint, codec: &C, value: &T) -> MemResult<u64> {
+        let flags = self.params.flag_scheme.to_codec_flags(codec.value_type(), codec.codec_id());
+        return self.store_flags_cmd(OP_SET, key, codec.encode(value), 0, flags, exptime);
+    }
+
+    /// Get the value at key decoded by the codec.  Return None if no data found, error, or the data was written by another codec.
+    /// let user : Option<User> = rm.get_value("user1", &EbmlCodec);
+    pub fn get_value<T, C: ValueCodec<T>>(&mut self, key: &str, codec: &C) -> Option<T> {
+        match self.get_data(key) {
+            Some(md) => md.try_value(codec as &ValueCodec<T>).ok(),
+            None => None
+        }
+    }
+
+    /// Get the value at key, decoded by the codec that wrote it out of the list of codecs.
+    /// let user : Option<User> = rm.get_value_any("user1", [&JsonCodec as &ValueCodec<User>, &EbmlCodec as &ValueCodec<User>]);
+    pub fn get_value_any<T>(&mut self, key: &str, codecs: &[&ValueCodec<T>]) -> Option<T> {
+        let md = match self.get_data(key) {
+            Some(md) => md,
+            None => return None
+        };
+        for codec in codecs.iter() {
+            match md.try_value(*codec) {
+                Ok(value) => return Some(value),
+                Err(_) => ()
+            }
+        }
+        return None;
+    }
+
+}
+
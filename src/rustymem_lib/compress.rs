@@ -0,0 +1,103 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::vec;
+use std::task;
+use std::cell::Cell;
+use extra::flate;
+
+
+use common::ioutil;
+
+
+
+//
+// Value compression
+//
+
+// zlib header for deflate with 32K window and default compression level
+static ZLIB_CMF: u8     = 0x78u8;
+static ZLIB_FLG: u8     = 0x9Cu8;
+
+
+/// Compression codec for values over MemParams.compress_threshold
+#[deriving(Clone)]
+pub enum CompressCodec {
+    /// Raw deflate stream
+    COMPRESS_DEFLATE,
+    /// Deflate in the zlib format, header and Adler-32 trailer.  Readable by zlib based clients like pylibmc.
+    COMPRESS_ZLIB,
+}
+
+
+/// Compress the data with the codec.
+pub fn compress(codec: CompressCodec, data: &[u8]) -> ~[u8] {
+    let deflated = flate::deflate_bytes(data);
+    match codec {
+        COMPRESS_DEFLATE => deflated,
+        COMPRESS_ZLIB    => {
+            let mut buf = vec::from_elem(2 + deflated.len() + 4, 0u8);
+            let mut offset = 0;
+            offset = ioutil::pack_u8_be(buf, offset, ZLIB_CMF);
+            offset = ioutil::pack_u8_be(buf, offset, ZLIB_FLG);
+            offset = ioutil::copy_bytes(buf, offset, deflated, 0, deflated.len());
+            ioutil::pack_u32_be(buf, offset, ioutil::adler32(data));
+            buf
+        }
+    }
+}
+
+/// Decompress the data.  Data in the zlib format is detected by its header and checked against its checksum,
+/// and other data are inflated as a raw deflate stream, so data compressed by either codec can be read whatever
+/// the codec configured.  Return None if the data is corrupted or in another format, e.g. gzip written by spymemcached.
+pub fn decompress(data: &[u8]) -> Option<~[u8]> {
+    if is_zlib(data) {
+        match try_inflate(data.slice(2, data.len() - 4)) {
+            Some(inflated) => {
+                if ioutil::adler32(inflated) == ioutil::unpack_u32_be(data, data.len() - 4) {
+                    return Some(inflated);
+                }
+            },
+            None => ()
+        }
+        // Not zlib after all, a raw deflate stream can start like a zlib header by chance.
+    }
+    if data.len() == 0 {
+        return None;
+    }
+    return try_inflate(data);
+}
+
+// Inflate the deflate stream in a task of its own, since flate::inflate_bytes fails the task on bad input.
+fn try_inflate(deflated: &[u8]) -> Option<~[u8]> {
+    let deflated = Cell::new(deflated.to_owned());
+    match task::try(|| flate::inflate_bytes(deflated.take())) {
+        Ok(inflated) => Some(inflated),
+        Err(_) => None
+    }
+}
+
+// Check for the zlib header: deflate method, 32K window, no preset dictionary, and the header checksum.
+fn is_zlib(data: &[u8]) -> bool {
+    if data.len() < 2 + 4 {
+        return false;
+    }
+    let cmf = data[0] as uint;
+    let flg = data[1] as uint;
+    return (cmf & 0x0F) == 8 && (cmf >> 4) <= 7 && (flg & 0x20) == 0 && (cmf * 256 + flg) % 31 == 0;
+}
+
@@ -0,0 +1,345 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::io;
+use std::str;
+use std::vec;
+use std::util;
+use std::result::Result;
+use std::hashmap::HashMap;
+use extra::md5::Md5;
+use extra::digest::Digest;
+
+
+use common::ioutil;
+use common::timeutil;
+
+
+use super::super::RustyMem;
+use super::super::MemData;
+use super::super::MemStatus;
+use super::super::Success;
+use super::super::OP_SET;
+use super::flags::VT_UNKNOWN;
+use super::chunk;
+
+
+
+//
+// Cache dump and restore
+//
+// The keys are enumerated per server with the metadump, and their items are read with bulk gets in batches,
+// decompressed and with their chunks joined.  The dump file is:
+//
+//   header:  "RMDUMP", version u16, dump time u64 (Unix seconds)
+//   entry:   tag 1, key length u16, key, flags u32, cas u64, remaining TTL u32 (0 for none), data length u32, data
+//   end:     tag 0, entry count u64, MD5 of all the bytes before it (16 bytes)
+//
+// Integers are big-endian.  The keys are the caller's keys, without the key prefix of the RustyMem, so a dump
+// can be restored under another prefix.  The chunks of large values are items of their own in the metadump,
+// and are skipped since their values are dumped joined.
+// The restore checks the whole file before writing anything, and sets the entries with the TTL left after
+// the time since the dump, skipping the ones expired since.  The entries are set in batches, with one
+// pipeline of sets per server, except the large values to chunk which are set one by one.
+//
+
+static DUMP_MAGIC: &'static str     = "RMDUMP";
+static DUMP_VERSION: u16            = 1;
+static TAG_END: u8                  = 0;
+static TAG_ENTRY: u8                = 1;
+static HEADER_SIZE: uint            = 6 + 2 + 8;
+static END_SIZE: uint               = 1 + 8 + 16;
+
+// Keys read per bulk get
+static DUMP_BATCH: uint             = 100;
+
+// Memcached takes an exptime over 30 days as a Unix time.
+static MAX_RELATIVE_EXPTIME: u64    = 60 * 60 * 24 * 30;
+
+
+/// Result of a dump
+pub struct DumpSummary {
+    entries:    uint,
+    /// Bytes of the data dumped
+    bytes:      u64,
+    /// Keys enumerated but gone or expired by the time they were read
+    missed:     uint,
+    /// Servers which couldn't be enumerated, with their errors
+    errors:     ~[(~str, ~str)],
+}
+
+/// Result of a restore
+pub struct RestoreSummary {
+    restored:   uint,
+    /// Entries expired since the dump, not restored
+    expired:    uint,
+    /// Entries failed to set, e.g. with their servers down
+    failed:     uint,
+}
+
+
+impl RustyMem {
+
+    /// Dump the items whose keys start with the prefix to the writer.
+    pub fn dump(&mut self, writer: @io::Writer, prefix: &str) -> DumpSummary {
+        let mut out = DumpWriter { writer: writer, digest: Md5::new() };
+        let mut header = vec::from_elem(HEADER_SIZE, 0u8);
+        let mut offset = ioutil::pack_str(header, 0, DUMP_MAGIC);
+        offset = ioutil::pack_u16_be(header, offset, DUMP_VERSION);
+        ioutil::pack_u64_be(header, offset, timeutil::now_secs());
+        out.put(header);
+
+        let mut summary = DumpSummary { entries: 0, bytes: 0, missed: 0, errors: ~[] };
+        let mut dump = self.metadump(prefix, None);
+        loop {
+            // Key and its expiration time from the metadump, for the remaining TTL.
+            let mut batch = HashMap::<~str, i64>::new();
+            while batch.len() < DUMP_BATCH {
+                match dump.next() {
+                    Some(entry) => {
+                        if !chunk::is_chunk_key(entry.key) {
+                            batch.insert(entry.key, entry.exptime);
+                        }
+                    },
+                    None => break
+                }
+            }
+            if batch.len() == 0 {
+                break;
+            }
+            let keys = batch.iter().map(|(key, _)| key.as_slice()).collect::<~[&str]>();
+            let md_list = do self.with_l1_bypass |rm| { rm.get_bulk_data(keys) };
+            let now = timeutil::now_secs() as i64;
+            for md in md_list.iter() {
+                let ttl = match batch.find(&md.key) {
+                    Some(&exptime) if exptime < 0   => 0,
+                    Some(&exptime) if exptime > now => (exptime - now) as u32,
+                    _ => {
+                        summary.missed += 1;
+                        continue;
+                    }
+                };
+                out.put(encode_entry(md, ttl));
+                summary.entries += 1;
+                summary.bytes += md.data.len() as u64;
+            }
+            summary.missed += batch.len() - md_list.len();
+        }
+        summary.errors = dump.errors();
+
+        let mut end = vec::from_elem(END_SIZE - 16, 0u8);
+        let offset = ioutil::pack_u8_be(end, 0, TAG_END);
+        ioutil::pack_u64_be(end, offset, summary.entries as u64);
+        out.put(end);
+        let checksum = out.digest_bytes();
+        out.writer.write(checksum);
+        out.writer.flush();
+        return summary;
+    }
+
+    /// Dump the items whose keys start with the prefix to the file.
+    pub fn dump_file(&mut self, path: &str, prefix: &str) -> Result<DumpSummary, ~str> {
+        match io::file_writer(&Path(path), [io::Create, io::Truncate]) {
+            Ok(writer)  => Ok(self.dump(writer, prefix)),
+            Err(e)      => Err(e)
+        }
+    }
+
+    /// Restore a dump into the servers of this RustyMem, under its key prefix.  Return an error without
+    /// restoring anything if the dump is not valid.
+    pub fn restore(&mut self, dump: &[u8]) -> Result<RestoreSummary, ~str> {
+        let entries = match decode_dump(dump) {
+            Ok(entries) => entries,
+            Err(e)      => return Err(e)
+        };
+        let dump_time = ioutil::unpack_u64_be(dump, 6 + 2);
+        let now = timeutil::now_secs();
+        let elapsed = if now > dump_time { now - dump_time } else { 0 };
+
+        let mut summary = RestoreSummary { restored: 0, expired: 0, failed: 0 };
+        let mut batch : ~[(~str, ~[u8], u32, uint)] = ~[];
+        for &(ref md, ttl) in entries.iter() {
+            let ttl = ttl as u64;
+            if ttl > 0 && ttl <= elapsed {
+                summary.expired += 1;
+                continue;
+            }
+            let remaining = if ttl == 0 { 0 } else { ttl - elapsed };
+            let exptime = (if remaining > MAX_RELATIVE_EXPTIME { now + remaining } else { remaining }) as uint;
+            let wkey = match self.wire_key(md.key) {
+                Ok(k)       => k,
+                Err(status) => {
+                    debug!( fmt!("restore of %? failed: %?", md.key, status) );
+                    summary.failed += 1;
+                    continue;
+                }
+            };
+            let mut flags = md.flags;
+            let data = match self.params.compress_data(OP_SET, md.data) {
+                Some(cdata) => {
+                    flags = flags | self.params.flag_scheme.compressed_flag;
+                    cdata
+                },
+                None => md.data.clone()
+            };
+            if self.params.large_values && data.len() > self.params.max_item_size {
+                let status = self.store_flags_cmd(OP_SET, md.key, md.data, 0, md.flags, exptime).status;
+                summary.count(md.key, status);
+                continue;
+            }
+            batch.push((wkey, data, flags, exptime));
+            if batch.len() >= DUMP_BATCH {
+                self.restore_batch(util::replace(&mut batch, ~[]), &mut summary);
+            }
+        }
+        if batch.len() > 0 {
+            self.restore_batch(batch, &mut summary);
+        }
+        return Ok(summary);
+    }
+
+    // Set the batch of entries, (wire key, data, flags, exptime), with one pipeline per server.
+    fn restore_batch(&mut self, batch: ~[(~str, ~[u8], u32, uint)], summary: &mut RestoreSummary) {
+        let statuses = self.set_bulk_wire(batch);
+        for (&(ref wkey, _, _, _), status) in batch.iter().zip(statuses.move_iter()) {
+            summary.count(*wkey, status);
+        }
+    }
+
+    /// Restore the dump in the file.
+    pub fn restore_file(&mut self, path: &str) -> Result<RestoreSummary, ~str> {
+        match io::read_whole_file(&Path(path)) {
+            Ok(dump)    => self.restore(dump),
+            Err(e)      => Err(e)
+        }
+    }
+
+}
+
+
+impl RestoreSummary {
+
+    fn count(&mut self, key: &str, status: MemStatus) {
+        match status {
+            Success => self.restored += 1,
+            status  => {
+                debug!( fmt!("restore of %? failed: %?", key, status) );
+                self.failed += 1;
+            }
+        }
+    }
+
+}
+
+
+// Writer computing the checksum of what's written
+struct DumpWriter {
+    writer:     @io::Writer,
+    digest:     Md5,
+}
+
+impl DumpWriter {
+
+    fn put(&mut self, bytes: &[u8]) {
+        self.digest.input(bytes);
+        self.writer.write(bytes);
+    }
+
+    fn digest_bytes(&mut self) -> ~[u8] {
+        let mut checksum = vec::from_elem(16, 0u8);
+        self.digest.result(checksum);
+        return checksum;
+    }
+
+}
+
+
+fn encode_entry(md: &MemData, ttl: u32) -> ~[u8] {
+    let key_bytes = md.key.as_bytes();
+    let mut buf = vec::from_elem(1 + 2 + key_bytes.len() + 4 + 8 + 4 + 4 + md.data.len(), 0u8);
+    let mut offset = ioutil::pack_u8_be(buf, 0, TAG_ENTRY);
+    offset = ioutil::pack_u16_be(buf, offset, key_bytes.len() as u16);
+    offset = ioutil::copy_bytes(buf, offset, key_bytes, 0, key_bytes.len());
+    offset = ioutil::pack_u32_be(buf, offset, md.flags);
+    offset = ioutil::pack_u64_be(buf, offset, md.cas);
+    offset = ioutil::pack_u32_be(buf, offset, ttl);
+    offset = ioutil::pack_u32_be(buf, offset, md.data.len() as u32);
+    ioutil::copy_bytes(buf, offset, md.data, 0, md.data.len());
+    return buf;
+}
+
+// Check the header, checksum and entry count of the dump, and decode its entries with their TTLs.
+fn decode_dump(dump: &[u8]) -> Result<~[(MemData, u32)], ~str> {
+    if dump.len() < HEADER_SIZE + END_SIZE || dump.slice(0, 6) != DUMP_MAGIC.as_bytes() {
+        return Err(~"not a dump");
+    }
+    let version = ioutil::unpack_u16_be(dump, 6);
+    if version != DUMP_VERSION {
+        return Err(fmt!("unsupported dump version %?", version));
+    }
+    let checked_len = dump.len() - 16;
+    let mut digest = Md5::new();
+    digest.input(dump.slice(0, checked_len));
+    let mut checksum = vec::from_elem(16, 0u8);
+    digest.result(checksum);
+    if dump.slice(checked_len, dump.len()) != checksum.as_slice() {
+        return Err(~"checksum mismatch");
+    }
+
+    let mut entries : ~[(MemData, u32)] = ~[];
+    let mut offset = HEADER_SIZE;
+    loop {
+        if offset >= checked_len {
+            return Err(~"missing end of dump");
+        }
+        let tag = ioutil::unpack_u8_be(dump, offset);
+        offset += 1;
+        if tag == TAG_END {
+            break;
+        }
+        if tag != TAG_ENTRY || offset + 2 > checked_len {
+            return Err(fmt!("invalid entry at %?", offset - 1));
+        }
+        let key_len = ioutil::unpack_u16_be(dump, offset) as uint;
+        offset += 2;
+        if offset + key_len + 4 + 8 + 4 + 4 > checked_len {
+            return Err(fmt!("truncated entry at %?", offset));
+        }
+        let key = dump.slice(offset, offset + key_len).to_owned();
+        offset += key_len;
+        let flags = ioutil::unpack_u32_be(dump, offset);
+        let cas = ioutil::unpack_u64_be(dump, offset + 4);
+        let ttl = ioutil::unpack_u32_be(dump, offset + 12);
+        let data_len = ioutil::unpack_u32_be(dump, offset + 16) as uint;
+        offset += 20;
+        if offset + data_len > checked_len {
+            return Err(fmt!("truncated entry at %?", offset));
+        }
+        let data = dump.slice(offset, offset + data_len).to_owned();
+        offset += data_len;
+        if !str::is_utf8(key) {
+            return Err(fmt!("invalid key at %?", offset));
+        }
+        entries.push((MemData { key: str::from_utf8(key), data: data, cas: cas, flags: flags, value_type: VT_UNKNOWN }, ttl));
+    }
+    if offset + 8 != checked_len || ioutil::unpack_u64_be(dump, offset) != entries.len() as u64 {
+        return Err(~"entry count mismatch");
+    }
+    return Ok(entries);
+}
+
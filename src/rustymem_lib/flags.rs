@@ -0,0 +1,179 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+//
+// Value type tags in MemData.flags
+//
+
+// The codec id of data written by a ValueCodec is in the top byte of the flags, for all schemes.
+static CODEC_ID_SHIFT: u32 = 24;
+
+
+/// Type of the value stored in memcached, recorded in MemData.flags according to a FlagScheme.
+#[deriving(Eq, Clone)]
+pub enum ValueType {
+    /// Not decoded from the flags, e.g. MemData returned by a ProtoConnection directly.  Read as anything.
+    VT_UNKNOWN,
+    /// Raw bytes.  Also the type of untagged data written by other clients or by incr/decr.
+    VT_BYTES,
+    /// UTF-8 string
+    VT_STR,
+    /// Integer in decimal string, compatible with incr/decr.
+    VT_INT,
+    /// JSON string
+    VT_JSON,
+    /// Compressed data
+    VT_COMPRESSED,
+    /// Data serialized by a codec
+    VT_SERIALIZED,
+    /// Cached absence of a value, the negative result of a loader in get_or_compute.
+    VT_NEGATIVE,
+    /// Data wrapped with the tag versions it was written under, by set_tagged.
+    VT_TAGGED,
+}
+
+
+/// Layout of the type tags in MemData.flags.  Use one matching the other clients sharing the cache.
+#[deriving(Clone)]
+pub struct FlagScheme {
+    /// Bits of the flags holding the type tag
+    type_mask:          u32,
+    bytes_flag:         u32,
+    str_flag:           u32,
+    int_flag:           u32,
+    /// Another tag read as an integer, for a scheme with two integer types.  The same as int_flag if none.
+    long_flag:          u32,
+    json_flag:          u32,
+    serialized_flag:    u32,
+    /// Bit marking the data as compressed, outside of type_mask.  0 if the scheme has none.
+    compressed_flag:    u32,
+    /// Bit marking the data as the manifest of a value stored in chunks, outside of type_mask.
+    chunked_flag:       u32,
+    /// Bit marking the data as a cached negative result, outside of type_mask.
+    negative_flag:      u32,
+    /// Bit marking the data as wrapped with tag versions, outside of type_mask.
+    tagged_flag:        u32,
+}
+
+impl FlagScheme {
+
+    /// RustyMem's own layout: type tag in the low byte, compressed bit at 0x100, chunked bit at 0x200, negative bit at 0x400,
+    /// tagged bit at 0x800.
+    pub fn rustymem() -> FlagScheme {
+        return FlagScheme {
+            type_mask:          0x00FF,
+            bytes_flag:         0,
+            str_flag:           1,
+            int_flag:           2,
+            long_flag:          2,
+            json_flag:          3,
+            serialized_flag:    4,
+            compressed_flag:    0x0100,
+            chunked_flag:       0x0200,
+            negative_flag:      0x0400,
+            tagged_flag:        0x0800,
+        };
+    }
+
+    /// pylibmc's layout: FLAG_PICKLE 1, FLAG_INTEGER 2, FLAG_LONG 4, FLAG_ZLIB 8, FLAG_TEXT 32.
+    /// Integers are written as FLAG_INTEGER, and both FLAG_INTEGER and FLAG_LONG are read as integers.
+    /// JSON is stored as text since pylibmc has no JSON flag.  Chunked values, negative results and tagged
+    /// data use the unused bits at 0x10000, 0x20000 and 0x40000.  Serialized data uses 0x80000 rather than
+    /// FLAG_PICKLE, so pylibmc reads it as raw bytes instead of unpickling it.
+    pub fn pylibmc() -> FlagScheme {
+        return FlagScheme {
+            type_mask:          0x80037,
+            bytes_flag:         0,
+            str_flag:           32,
+            int_flag:           2,
+            long_flag:          4,
+            json_flag:          32,
+            serialized_flag:    0x80000,
+            compressed_flag:    8,
+            chunked_flag:       0x10000,
+            negative_flag:      0x20000,
+            tagged_flag:        0x40000,
+        };
+    }
+
+    /// spymemcached's layout: SERIALIZED 1, COMPRESSED 2, and the special types at 0xff00,
+    /// with SPECIAL_LONG 3 << 8 and SPECIAL_BYTEARRAY 8 << 8.  Strings and JSON are untagged.
+    /// Chunked values, negative results and tagged data use the unused bits at 0x10000, 0x20000 and 0x40000.
+    pub fn spymemcached() -> FlagScheme {
+        return FlagScheme {
+            type_mask:          0xFF01,
+            bytes_flag:         0x0800,
+            str_flag:           0,
+            int_flag:           0x0300,
+            long_flag:          0x0300,
+            json_flag:          0,
+            serialized_flag:    1,
+            compressed_flag:    2,
+            chunked_flag:       0x10000,
+            negative_flag:      0x20000,
+            tagged_flag:        0x40000,
+        };
+    }
+
+    /// Flags to write for the value type
+    pub fn to_flags(&self, value_type: ValueType) -> u32 {
+        match value_type {
+            VT_STR          => self.str_flag,
+            VT_INT          => self.int_flag,
+            VT_JSON         => self.json_flag,
+            VT_SERIALIZED   => self.serialized_flag,
+            VT_COMPRESSED   => self.bytes_flag | self.compressed_flag,
+            VT_NEGATIVE     => self.bytes_flag | self.negative_flag,
+            VT_TAGGED       => self.bytes_flag | self.tagged_flag,
+            _               => self.bytes_flag,
+        }
+    }
+
+    /// Flags to write for the value type and the codec id of the ValueCodec encoding the data.
+    pub fn to_codec_flags(&self, value_type: ValueType, codec_id: u32) -> u32 {
+        return self.to_flags(value_type) | ((codec_id & 0xFF) << CODEC_ID_SHIFT);
+    }
+
+    /// Value type of the read flags.  Unknown tags are treated as raw bytes.
+    pub fn to_value_type(&self, flags: u32) -> ValueType {
+        if self.compressed_flag != 0 && (flags & self.compressed_flag) != 0 {
+            return VT_COMPRESSED;
+        }
+        if self.negative_flag != 0 && (flags & self.negative_flag) != 0 {
+            return VT_NEGATIVE;
+        }
+        if self.tagged_flag != 0 && (flags & self.tagged_flag) != 0 {
+            return VT_TAGGED;
+        }
+        let tag = flags & self.type_mask;
+        if tag == self.bytes_flag           { VT_BYTES }
+        else if tag == self.str_flag        { VT_STR }
+        else if tag == self.int_flag        { VT_INT }
+        else if tag == self.long_flag       { VT_INT }
+        else if tag == self.json_flag       { VT_JSON }
+        else if tag == self.serialized_flag { VT_SERIALIZED }
+        else                                { VT_BYTES }
+    }
+
+}
+
+
+/// Codec id of the ValueCodec encoding the data, 0 if not written by a codec.
+pub fn codec_id_of(flags: u32) -> u32 {
+    return flags >> CODEC_ID_SHIFT;
+}
+
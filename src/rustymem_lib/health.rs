@@ -0,0 +1,184 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::task;
+use std::cell::Cell;
+use std::comm::{stream, Port, SharedChan, GenericPort, GenericSmartChan, Peekable};
+use std::hashmap::HashSet;
+
+
+use common::timeutil;
+
+
+use super::super::RustyMem;
+use super::super::MemProtocol;
+use super::super::new_protocol_connection;
+
+
+
+//
+// Server health tracking
+//
+// A connection failing on I/O, or failing to connect, is down.  It's reconnected on its next use after
+// MemParams.retry_down_ms.  After MemParams.eject_after_failures consecutive failures, the server is ejected
+// from the shard map: its keys go to their replicas, or are rehashed onto the remaining servers with
+// MemParams.eject_rehash, or fail right away.  A background task probes an ejected server with a version
+// command on its own connection, and the server is put back when it answers.
+//
+
+
+/// Health of a server
+#[deriving(Clone)]
+pub struct ServerHealth {
+    server_addr:            ~str,
+    consecutive_failures:   uint,
+    /// Last error, empty if none.
+    last_error:             ~str,
+    /// Monotonic time in milliseconds until which a down connection is not retried.  0 if up.
+    down_until_ms:          u64,
+    /// Whether the server is ejected from the shard map.
+    ejected:                bool,
+}
+
+impl ServerHealth {
+    pub fn new(server_addr: &str) -> ServerHealth {
+        return ServerHealth {
+            server_addr:            server_addr.to_owned(),
+            consecutive_failures:   0,
+            last_error:             ~"",
+            down_until_ms:          0,
+            ejected:                false,
+        };
+    }
+}
+
+
+/// Health of the servers of a RustyMem, in the order of its connections
+pub struct HealthTracker {
+    servers:        ~[ServerHealth],
+    // Probe results, (server_addr, is_up), from the probe tasks
+    probe_port:     Port<(~str, bool)>,
+    probe_chan:     SharedChan<(~str, bool)>,
+    probing:        HashSet<~str>,
+}
+
+impl HealthTracker {
+
+    pub fn new(server_addrs: &[~str]) -> HealthTracker {
+        let (port, chan) = stream::<(~str, bool)>();
+        return HealthTracker {
+            servers:        server_addrs.iter().map(|addr| ServerHealth::new(*addr)).collect::<~[ServerHealth]>(),
+            probe_port:     port,
+            probe_chan:     SharedChan::new(chan),
+            probing:        HashSet::new(),
+        };
+    }
+
+    pub fn is_ejected(&self, index: uint) -> bool {
+        return self.servers[index].ejected;
+    }
+
+    /// Whether a down connection at the index can be tried again.
+    pub fn is_retry_due(&self, index: uint) -> bool {
+        let health = &self.servers[index];
+        return !health.ejected && health.down_until_ms <= timeutil::now_ms();
+    }
+
+    pub fn record_success(&mut self, index: uint) {
+        let health = &mut self.servers[index];
+        health.consecutive_failures = 0;
+        health.down_until_ms = 0;
+        health.ejected = false;
+    }
+
+    /// Record the failure of the server at the index.  Return true if the server is to be ejected.
+    pub fn record_failure(&mut self, index: uint, error: &str, retry_down_ms: uint, eject_after_failures: uint) -> bool {
+        let health = &mut self.servers[index];
+        health.consecutive_failures += 1;
+        health.last_error = error.to_owned();
+        health.down_until_ms = timeutil::now_ms() + retry_down_ms as u64;
+        debug!( fmt!("server %s failed %? times: %s", health.server_addr, health.consecutive_failures, error) );
+        if !health.ejected && eject_after_failures > 0 && health.consecutive_failures >= eject_after_failures {
+            health.ejected = true;
+            return true;
+        }
+        return false;
+    }
+
+    /// Start a background task probing the server every interval_ms milliseconds until it's up.
+    pub fn start_probe(&mut self, server_addr: &str, protocol: MemProtocol, interval_ms: uint) {
+        if self.probing.contains(&server_addr.to_owned()) {
+            return;
+        }
+        self.probing.insert(server_addr.to_owned());
+        let chan = Cell::new(self.probe_chan.clone());
+        let server_addr = Cell::new(server_addr.to_owned());
+        do task::spawn {
+            probe_server(server_addr.take(), protocol, interval_ms, chan.take());
+        }
+    }
+
+    /// Rebuild the health list for a new server list, keeping the health of the servers still in it.
+    pub fn retain_servers(&mut self, server_addrs: &[~str]) {
+        self.servers = server_addrs.iter().map(|addr| {
+                match self.servers.iter().find(|health| health.server_addr == *addr) {
+                    Some(health) => health.clone(),
+                    None => ServerHealth::new(*addr)
+                }
+            }).collect::<~[ServerHealth]>();
+    }
+
+    /// Take the addresses of the servers found up by the probes since the last call.
+    pub fn take_recovered(&mut self) -> ~[~str] {
+        let mut recovered : ~[~str] = ~[];
+        while self.probe_port.peek() {
+            let (server_addr, is_up) = self.probe_port.recv();
+            if is_up {
+                self.probing.remove(&server_addr);
+                recovered.push(server_addr);
+            }
+        }
+        return recovered;
+    }
+
+}
+
+
+// Probe the server until it answers the version command.  Every result is reported, so the task
+// stops when the RustyMem owning the port is gone.
+fn probe_server(server_addr: ~str, protocol: MemProtocol, interval_ms: uint, chan: SharedChan<(~str, bool)>) {
+    loop {
+        timeutil::sleep_ms(interval_ms as u64);
+        let mut conn = new_protocol_connection(server_addr, protocol);
+        let is_up = conn.p_is_connected() && conn.p_version().is_ok() && conn.p_is_connected();
+        debug!( fmt!("probe %s: %?", server_addr, is_up) );
+        if !chan.try_send((server_addr.clone(), is_up)) || is_up {
+            break;
+        }
+    }
+}
+
+
+impl RustyMem {
+
+    /// Current health of the servers, in the order of the connections.
+    pub fn health(&self) -> ~[ServerHealth] {
+        return self.health.servers.clone();
+    }
+
+}
+
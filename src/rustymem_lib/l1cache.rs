@@ -0,0 +1,195 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::cmp;
+use std::hashmap::HashMap;
+use extra::treemap::TreeMap;
+
+
+use common::timeutil;
+
+
+use super::super::MemData;
+
+
+
+//
+// In-process L1 cache in front of memcached
+//
+// Entries are the decoded MemData keyed by their wire keys, expiring after the L1 TTL, or earlier if the
+// item's own exptime is shorter.  The least recently used entries are evicted when the total size of the
+// keys and data goes over the max bytes.  Other processes' writes are not seen until the entry expires,
+// so the TTL bounds how stale an L1 read can be.
+//
+
+// Exptime over this is an absolute Unix time, as in memcached.
+static MAX_RELATIVE_EXPTIME: uint   = 60*60*24*30;
+
+
+/// Hit and miss counts of the two tiers.  L2 counts the reads going to memcached.
+#[deriving(Clone)]
+pub struct CacheStats {
+    l1_hits:        u64,
+    l1_misses:      u64,
+    l2_hits:        u64,
+    l2_misses:      u64,
+}
+
+impl CacheStats {
+    pub fn new() -> CacheStats {
+        return CacheStats { l1_hits: 0, l1_misses: 0, l2_hits: 0, l2_misses: 0 };
+    }
+}
+
+
+struct L1Entry {
+    md:         MemData,
+    size:       uint,
+    expire_ms:  u64,
+    // Last use, the key of the entry in the LRU order
+    tick:       u64,
+}
+
+
+/// LRU cache of the retrieved data
+pub struct L1Cache {
+    max_bytes:      uint,
+    ttl_ms:         uint,
+    total_bytes:    uint,
+    entries:        HashMap<~str, L1Entry>,
+    lru:            TreeMap<u64, ~str>,
+    tick:           u64,
+    stats:          CacheStats,
+}
+
+impl L1Cache {
+
+    /// Create the cache with the max total bytes of the entries, 0 to disable it, and the TTL of the entries.
+    pub fn new(max_bytes: uint, ttl_ms: uint) -> L1Cache {
+        return L1Cache {
+            max_bytes:      max_bytes,
+            ttl_ms:         ttl_ms,
+            total_bytes:    0,
+            entries:        HashMap::new(),
+            lru:            TreeMap::new(),
+            tick:           0,
+            stats:          CacheStats::new(),
+        };
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        return self.max_bytes > 0 && self.ttl_ms > 0;
+    }
+
+    pub fn len(&self) -> uint {
+        return self.entries.len();
+    }
+
+    /// Get a copy of the entry at the wire key, counting the hit or miss.
+    pub fn get(&mut self, wire_key: &str) -> Option<MemData> {
+        let now = timeutil::now_ms();
+        let wkey = wire_key.to_owned();
+        let (found, expired) = match self.entries.find(&wkey) {
+            Some(entry) => (true, entry.expire_ms <= now),
+            None        => (false, false)
+        };
+        if expired {
+            self.remove(wire_key);
+        }
+        if !found || expired {
+            self.stats.l1_misses += 1;
+            return None;
+        }
+
+        self.stats.l1_hits += 1;
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(&wkey);
+        self.lru.pop(&entry.tick);
+        self.lru.insert(tick, wkey.clone());
+        entry.tick = tick;
+        return Some(entry.md.clone());
+    }
+
+    /// Put a copy of the data at the wire key, expiring in the L1 TTL or the item's exptime, whichever is earlier.
+    /// Pass exptime 0 if the item's exptime is not known.
+    pub fn put(&mut self, wire_key: &str, md: &MemData, exptime: uint) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.remove(wire_key);
+        let size = wire_key.len() + md.data.len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let now = timeutil::now_ms();
+        let ttl_ms = match exptime {
+            0                                       => self.ttl_ms as u64,
+            t if t <= MAX_RELATIVE_EXPTIME          => cmp::min(self.ttl_ms as u64, t as u64 * 1000),
+            t                                       => {
+                let now_secs = timeutil::now_secs();
+                let remaining_ms = if t as u64 > now_secs { (t as u64 - now_secs) * 1000 } else { 0 };
+                cmp::min(self.ttl_ms as u64, remaining_ms)
+            }
+        };
+        if ttl_ms == 0 {
+            return;
+        }
+
+        self.tick += 1;
+        self.lru.insert(self.tick, wire_key.to_owned());
+        self.entries.insert(wire_key.to_owned(), L1Entry {
+                md:         md.clone(),
+                size:       size,
+                expire_ms:  now + ttl_ms,
+                tick:       self.tick,
+            });
+        self.total_bytes += size;
+        self.evict();
+    }
+
+    /// Remove the entry at the wire key, after its item is changed.
+    pub fn remove(&mut self, wire_key: &str) {
+        match self.entries.pop(&wire_key.to_owned()) {
+            Some(entry) => {
+                self.lru.pop(&entry.tick);
+                self.total_bytes -= entry.size;
+            },
+            None => ()
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.total_bytes = 0;
+    }
+
+    // Evict the least recently used entries until the total size is within the max bytes.
+    fn evict(&mut self) {
+        while self.total_bytes > self.max_bytes {
+            let oldest = match self.lru.iter().next() {
+                Some((_, wkey)) => wkey.clone(),
+                None            => break
+            };
+            self.remove(oldest);
+        }
+    }
+
+}
+
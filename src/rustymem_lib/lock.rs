@@ -0,0 +1,192 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::cmp;
+use std::rand;
+use std::result::Result;
+
+
+use common::timeutil;
+
+
+use super::super::RustyMem;
+use super::super::MemStatus;
+use super::super::Success;
+use super::super::Key_Exists;
+use super::super::Key_Not_Found;
+use super::super::Item_Not_Stored;
+use super::super::OP_ADD;
+use super::super::OP_CAS;
+use super::flags::VT_STR;
+
+
+
+//
+// Distributed lock
+//
+// A lock is an entry at "__lock:name" holding the unique token of its owner, created with add so only one
+// owner can create it.  The TTL bounds how long a crashed owner holds the lock.  Renewal and release
+// read the entry and write back with its cas, so they only take effect while the owner's token is still there,
+// not after the lock has expired and been acquired by another owner.
+//
+
+// Wait between attempts of a blocking acquire
+static LOCK_RETRY_MIN_MS: u64   = 10;
+static LOCK_RETRY_MAX_MS: u64   = 200;
+
+
+/// Guard of an acquired lock.  The lock is released when the guard is dropped.
+/// Use mem() to access the RustyMem while holding the lock.
+pub struct MemLock<'a> {
+    priv rm:        &'a mut RustyMem,
+    priv name:      ~str,
+    priv token:     ~str,
+    priv held:      bool,
+}
+
+impl<'a> MemLock<'a> {
+
+    pub fn name(&self) -> ~str {
+        return self.name.clone();
+    }
+
+    /// Unique token of the owner stored in the lock entry
+    pub fn token(&self) -> ~str {
+        return self.token.clone();
+    }
+
+    pub fn mem<'b>(&'b mut self) -> &'b mut RustyMem {
+        return &mut *self.rm;
+    }
+
+    /// Extend the lock to expire in ttl seconds from now.  Return Key_Exists if it's now owned by another owner,
+    /// or Key_Not_Found if it has expired.
+    pub fn renew(&mut self, ttl: uint) -> MemStatus {
+        return self.rm.lock_renew(self.name, self.token, ttl);
+    }
+
+    /// Release the lock if still owned.  Dropping the guard releases it too.
+    pub fn release(&mut self) -> MemStatus {
+        if !self.held {
+            return Key_Not_Found;
+        }
+        self.held = false;
+        return self.rm.lock_release(self.name, self.token);
+    }
+
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for MemLock<'a> {
+    fn drop(&mut self) {
+        if self.held {
+            let status = self.rm.lock_release(self.name, self.token);
+            debug!( fmt!("release lock %? on drop: %?", self.name, status) );
+        }
+    }
+}
+
+
+impl RustyMem {
+
+    /// Try to acquire the lock once, expiring in ttl seconds.  Return the guard releasing the lock on drop,
+    /// or Key_Exists if the lock is held by another owner.
+    /// match rm.try_lock("cron.daily", 300) { Ok(lock) => ..., Err(_) => ... }
+    pub fn try_lock<'a>(&'a mut self, name: &str, ttl: uint) -> Result<MemLock<'a>, MemStatus> {
+        let token = new_lock_token();
+        match self.lock_acquire(name, token, ttl) {
+            Success => Ok(MemLock { rm: self, name: name.to_owned(), token: token, held: true }),
+            status  => Err(status)
+        }
+    }
+
+    /// Acquire the lock, expiring in ttl seconds, waiting up to timeout_ms milliseconds for the other owner to release it.
+    /// Return the guard releasing the lock on drop, or Key_Exists if it's not released in time.
+    /// let lock = rm.lock("cron.daily", 300, 5000);
+    pub fn lock<'a>(&'a mut self, name: &str, ttl: uint, timeout_ms: uint) -> Result<MemLock<'a>, MemStatus> {
+        let token = new_lock_token();
+        let deadline = timeutil::now_ms() + timeout_ms as u64;
+        let mut wait_ms = LOCK_RETRY_MIN_MS;
+        loop {
+            match self.lock_acquire(name, token, ttl) {
+                Success     => break,
+                Key_Exists  => {
+                    let now = timeutil::now_ms();
+                    if now >= deadline {
+                        return Err(Key_Exists);
+                    }
+                    let jitter = rand::random::<u64>() % wait_ms;
+                    timeutil::sleep_ms(cmp::min(wait_ms / 2 + jitter, deadline - now));
+                    wait_ms = cmp::min(wait_ms * 2, LOCK_RETRY_MAX_MS);
+                },
+                status      => return Err(status)
+            }
+        }
+        return Ok(MemLock { rm: self, name: name.to_owned(), token: token, held: true });
+    }
+
+    /// Create the lock entry with the owner's token, expiring in ttl seconds.
+    /// Return Key_Exists if the lock is held by another owner.
+    pub fn lock_acquire(&mut self, name: &str, token: &str, ttl: uint) -> MemStatus {
+        match self.store_cmd(OP_ADD, lock_key(name), token.as_bytes(), 0, VT_STR, ttl).status {
+            // The ASCII protocol returns NOT_STORED for add on an existing entry.
+            Item_Not_Stored => Key_Exists,
+            status          => status
+        }
+    }
+
+    /// Reset the lock to expire in ttl seconds from now, if still owned by the token.
+    /// Return Key_Exists if it's owned by another token, or Key_Not_Found if it has expired.
+    pub fn lock_renew(&mut self, name: &str, token: &str, ttl: uint) -> MemStatus {
+        let key = lock_key(name);
+        match self.lock_owned_cas(key, token) {
+            Ok(cas)     => self.store_cmd(OP_CAS, key, token.as_bytes(), cas, VT_STR, ttl).status,
+            Err(status) => status
+        }
+    }
+
+    /// Delete the lock, if still owned by the token.
+    /// Return Key_Exists if it's owned by another token, or Key_Not_Found if it has expired.
+    pub fn lock_release(&mut self, name: &str, token: &str) -> MemStatus {
+        let key = lock_key(name);
+        match self.lock_owned_cas(key, token) {
+            Ok(cas)     => self.delete_cas(key, cas),
+            Err(status) => status
+        }
+    }
+
+    // Read the cas of the lock entry if it holds the token.
+    fn lock_owned_cas(&mut self, key: &str, token: &str) -> Result<u64, MemStatus> {
+        match self.with_l1_bypass(|rm| rm.get_data(key)) {
+            Some(md) => if md.data.as_slice() == token.as_bytes() { Ok(md.cas) } else { Err(Key_Exists) },
+            None     => Err(Key_Not_Found)
+        }
+    }
+
+}
+
+
+// Key of the lock entry
+fn lock_key(name: &str) -> ~str {
+    return "__lock:" + name;
+}
+
+// Unique token of a lock owner
+fn new_lock_token() -> ~str {
+    return format!("{}-{}", timeutil::now_secs().to_str_radix(16), rand::random::<u64>().to_str_radix(16));
+}
+
@@ -0,0 +1,83 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::result::Result;
+use extra::sha1::Sha1;
+use extra::digest::Digest;
+
+
+use super::super::MemStatus;
+use super::super::Invalid_Key;
+use super::super::KeyMode;
+use super::super::KEY_STRICT;
+use super::super::KEY_HASH_LONG;
+
+
+
+//
+// Key validation and hashing
+//
+
+/// Max key length accepted by the Memcached server.
+pub static MAX_KEY_LEN: uint = 250u;
+
+// Prefix of a key replaced by its digest, to tell them apart when reading the cache by hand.
+static HASHED_KEY_PREFIX: &'static str = "rmh:";
+
+
+/// Check the key against the Memcached key rules: 1 to 250 bytes, with no space or control characters.
+/// The ASCII protocol relies on these to delimit the command line; the binary protocol uses the same limits.
+pub fn is_valid_key(key: &str) -> bool {
+    let key_bytes = key.as_bytes();
+    if key_bytes.len() == 0 || key_bytes.len() > MAX_KEY_LEN {
+        return false;
+    }
+    return key_bytes.iter().all(|b| is_key_byte(*b));
+}
+
+// Space, control characters and DEL are not allowed in a key.
+fn is_key_byte(b: u8) -> bool {
+    return b > 0x20u8 && b != 0x7fu8;
+}
+
+/// Replace the key with a stable digest of it, "rmh:" + hex(sha1(key)).  The result is always a valid key.
+pub fn hash_key(key: &str) -> ~str {
+    let mut digest = Sha1::new();
+    digest.input(key.as_bytes());
+    return HASHED_KEY_PREFIX + digest.result_str();
+}
+
+/// Turn an application key into the key sent on the wire, with the key prefix added, according to the key mode.
+/// A hashed key keeps the prefix in front so the namespace of the key can still be seen.
+/// Return Invalid_Key if the key can't be used.
+pub fn to_wire_key(key_prefix: &str, key: &str, key_mode: KeyMode) -> Result<~str, MemStatus> {
+    if key.len() == 0 {
+        return Err(Invalid_Key);
+    }
+    let full_key = key_prefix + key;
+    if is_valid_key(full_key) {
+        return Ok(full_key);
+    }
+    match key_mode {
+        KEY_STRICT      => Err(Invalid_Key),
+        KEY_HASH_LONG   => {
+            let hashed_key = key_prefix + hash_key(key);
+            if is_valid_key(hashed_key) { Ok(hashed_key) } else { Err(Invalid_Key) }
+        }
+    }
+}
+
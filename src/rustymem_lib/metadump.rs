@@ -0,0 +1,222 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::iterator::Iterator;
+use extra::treemap::TreeMap;
+
+
+use common::strutil;
+use common::netutil;
+
+
+use super::super::RustyMem;
+use super::super::DEFAULT_PORT;
+use super::ascii_conn::AsciiConnection;
+
+
+
+//
+// Key enumeration with lru_crawler metadump
+//
+// The metadump of a server is read on a dedicated ASCII connection, opened when the dump gets to the server,
+// so it works with either protocol of the RustyMem and doesn't hold up its connections.  The servers are
+// dumped one after another, streaming the entries as they are read.  Keys are URL-encoded in the dump.
+// Servers which can't dump, e.g. older than memcached 1.4.31 or with another crawl running, are skipped
+// and recorded in MetaDump.errors().
+//
+
+
+/// Metadata of an item from the metadump
+#[deriving(Clone)]
+pub struct MetaEntry {
+    server_addr:    ~str,
+    /// URL-decoded key, with the key prefix of the RustyMem stripped
+    key:            ~str,
+    /// Unix time the item expires at, -1 if it never expires
+    exptime:        i64,
+    /// Unix time of the last access
+    last_access:    u64,
+    /// Bytes of the item, key and data
+    size:           uint,
+    slab_class:     uint,
+}
+
+impl MetaEntry {
+
+    /// Parse a line of the metadump, "key=... exp=... la=... cas=... fetch=... cls=... size=...".
+    /// Return None if it has no key.
+    pub fn parse(server_addr: &str, line: &str) -> Option<MetaEntry> {
+        let mut entry = MetaEntry {
+            server_addr:    server_addr.to_owned(),
+            key:            ~"",
+            exptime:        -1,
+            last_access:    0,
+            size:           0,
+            slab_class:     0,
+        };
+        for token in strutil::clean_split(line, ' ').iter() {
+            let (name, value) = match token.find('=') {
+                Some(pos)   => (token.slice_to(pos), token.slice_from(pos + 1)),
+                None        => continue
+            };
+            match name {
+                "key"   => entry.key = strutil::url_decode(value),
+                "exp"   => entry.exptime = strutil::to_num(value, -1i64),
+                "la"    => entry.last_access = strutil::to_num(value, 0u64),
+                "size"  => entry.size = strutil::to_num(value, 0u),
+                "cls"   => entry.slab_class = strutil::to_num(value, 0u),
+                _       => ()
+            }
+        }
+        return if entry.key.len() > 0 { Some(entry) } else { None };
+    }
+
+}
+
+
+/// Items and bytes of the keys sharing a namespace, the part of the key before the separator
+#[deriving(Clone)]
+pub struct NamespaceUsage {
+    namespace:  ~str,
+    items:      u64,
+    bytes:      u64,
+}
+
+
+/// Iterator over the metadump entries of all the servers
+pub struct MetaDump {
+    priv server_addrs:  ~[~str],
+    priv next_server:   uint,
+    priv conn:          Option<AsciiConnection>,
+    priv server_addr:   ~str,
+    priv slab_class:    Option<uint>,
+    // Key prefix of the RustyMem, stripped from the keys
+    priv key_prefix:    ~str,
+    // Key prefix of the RustyMem followed by the filter prefix
+    priv wire_prefix:   ~str,
+    priv errors:        ~[(~str, ~str)],
+}
+
+impl MetaDump {
+
+    /// Servers skipped so far, with their errors
+    pub fn errors(&self) -> ~[(~str, ~str)] {
+        return self.errors.clone();
+    }
+
+    // Read the next line of the current server, moving on to the next server at the end of the dump.
+    // Return None when all the servers are done.
+    fn next_line(&mut self) -> Option<~str> {
+        loop {
+            if self.conn.is_none() && !self.start_next_server() {
+                return None;
+            }
+            let result = self.conn.get_mut_ref().ascii_read_metadump();
+            match result {
+                Ok(Some(line))  => return Some(line),
+                Ok(None)        => self.conn = None,
+                Err(error)      => {
+                    debug!( fmt!("metadump of %s failed: %s", self.server_addr, error) );
+                    self.errors.push((self.server_addr.clone(), error));
+                    self.conn = None;
+                }
+            }
+        }
+    }
+
+    // Open a connection to the next server and start its dump.  Return false if there's no server left.
+    fn start_next_server(&mut self) -> bool {
+        while self.next_server < self.server_addrs.len() {
+            self.server_addr = self.server_addrs[self.next_server].clone();
+            self.next_server += 1;
+            let mut conn = AsciiConnection::new_connection(netutil::HostAddr::with_host_port(self.server_addr, DEFAULT_PORT));
+            if conn.stream.is_none() {
+                self.errors.push((self.server_addr.clone(), ~"connect failed"));
+            } else {
+                conn.ascii_start_metadump(self.slab_class);
+                self.conn = Some(conn);
+                return true;
+            }
+        }
+        return false;
+    }
+
+}
+
+impl Iterator<MetaEntry> for MetaDump {
+
+    fn next(&mut self) -> Option<MetaEntry> {
+        loop {
+            let line = match self.next_line() {
+                Some(line)  => line,
+                None        => return None
+            };
+            match MetaEntry::parse(self.server_addr, line) {
+                Some(mut entry) => {
+                    if entry.key.starts_with(self.wire_prefix) {
+                        entry.key = entry.key.slice_from(self.key_prefix.len()).to_owned();
+                        return Some(entry);
+                    }
+                },
+                None => debug!( fmt!("invalid metadump line: %s", line) )
+            }
+        }
+    }
+
+}
+
+
+impl RustyMem {
+
+    /// Iterate over the metadata of the items in all the servers whose keys start with the prefix, after the
+    /// key prefix of the RustyMem.  Dump the slab class only if given.
+    /// for entry in rm.metadump("user:", None) { ... }
+    pub fn metadump(&self, prefix: &str, slab_class: Option<uint>) -> MetaDump {
+        return MetaDump {
+            server_addrs:   self.server_addrs(),
+            next_server:    0,
+            conn:           None,
+            server_addr:    ~"",
+            slab_class:     slab_class,
+            key_prefix:     self.params.key_prefix.clone(),
+            wire_prefix:    self.params.key_prefix + prefix,
+            errors:         ~[],
+        };
+    }
+
+    /// Items and bytes in all the servers by namespace, the part of the key up to the first separator.
+    /// Keys without the separator are counted under the empty namespace.
+    pub fn usage_by_namespace(&self, separator: char) -> ~[NamespaceUsage] {
+        let mut usage = TreeMap::<~str, NamespaceUsage>::new();
+        for entry in self.metadump("", None) {
+            let namespace = match entry.key.find(separator) {
+                Some(pos)   => entry.key.slice_to(pos).to_owned(),
+                None        => ~""
+            };
+            if !usage.contains_key(&namespace) {
+                usage.insert(namespace.clone(), NamespaceUsage { namespace: namespace.clone(), items: 0, bytes: 0 });
+            }
+            let ns_usage = usage.find_mut(&namespace).unwrap();
+            ns_usage.items += 1;
+            ns_usage.bytes += entry.size as u64;
+        }
+        return usage.iter().map(|(_, ns_usage)| ns_usage.clone()).collect::<~[NamespaceUsage]>();
+    }
+
+}
+
@@ -0,0 +1,488 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::vec;
+use std::result::Result;
+use extra::arc::MutexArc;
+use extra::time;
+
+
+use super::super::RustyMem;
+use super::super::ProtoConnection;
+use super::super::{PipelineCmd, PIPE_STORE, PIPE_INCR, PIPE_DECR, PIPE_TOUCH, PIPE_DELETE};
+use super::super::MemcachedStat;
+use super::super::MemStatus;
+use super::super::MemResult;
+use super::super::MemData;
+use super::super::Success;
+use super::super::Key_Not_Found;
+use super::super::Key_Exists;
+use super::super::Item_Not_Stored;
+use super::super::Network_Error;
+
+
+
+//
+// Per-operation metrics
+//
+// With MemParams.metrics set, every connection is wrapped in a MeteredConnection, which times each
+// ProtoConnection call and records an OpMetric to the MetricsSink of the MemMetrics, labeled by the server
+// address and the command.  The bytes counted are the keys and values sent and received, not the protocol
+// framing.  A get returning nothing is recorded as Key_Not_Found.
+// MemMetrics is shared by the clones of the params, so the clients of a MemPool or the tasks of an AsyncMem
+// record to the same sink.  HistogramSink keeps the metrics in memory, in latency buckets, to be snapshot.
+// The health probes of ejected servers are not recorded.
+//
+
+/// Upper bounds of the latency buckets in microseconds.  Slower calls go in a last, unbounded bucket.
+pub static LATENCY_BUCKETS_US: &'static [u64] = &[100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 500000, 1000000];
+
+
+/// One call of a ProtoConnection
+pub struct OpMetric {
+    server_addr:    ~str,
+    /// Command of the call, e.g. "get", "set", "incr"
+    command:        &'static str,
+    elapsed_us:     u64,
+    /// Bytes of the keys and data sent
+    bytes_sent:     u64,
+    /// Bytes of the keys and data received
+    bytes_received: u64,
+    status:         MemStatus,
+}
+
+
+/// Receiver of the metrics of the calls.  Implement it to forward the metrics to a monitoring system.
+pub trait MetricsSink {
+
+    /// Record the metric of a call.
+    fn record(&mut self, metric: &OpMetric);
+
+    /// Snapshot of the metrics recorded, if the sink keeps them.
+    fn snapshot(&self) -> Option<MetricsSnapshot> {
+        return None;
+    }
+
+}
+
+
+/// Handle of a MetricsSink shared by the connections, set in MemParams.metrics.
+#[deriving(Clone)]
+pub struct MemMetrics {
+    priv sink:      MutexArc<~MetricsSink>,
+}
+
+impl MemMetrics {
+
+    pub fn new(sink: ~MetricsSink) -> MemMetrics {
+        return MemMetrics { sink: MutexArc::new(sink) };
+    }
+
+    /// MemMetrics keeping the metrics in memory with a HistogramSink.
+    /// params.metrics = Some(MemMetrics::histogram());
+    pub fn histogram() -> MemMetrics {
+        return MemMetrics::new(~HistogramSink::new() as ~MetricsSink);
+    }
+
+    pub fn record(&self, metric: &OpMetric) {
+        self.access(|sink| sink.record(metric));
+    }
+
+    pub fn snapshot(&self) -> Option<MetricsSnapshot> {
+        return self.access(|sink| sink.snapshot());
+    }
+
+    fn access<U>(&self, blk: &fn(&mut ~MetricsSink) -> U) -> U {
+        unsafe {
+            return self.sink.access(blk);
+        }
+    }
+
+}
+
+
+/// Metrics of a command on a server
+#[deriving(Clone)]
+pub struct OpStats {
+    server_addr:    ~str,
+    command:        ~str,
+    count:          u64,
+    sum_us:         u64,
+    max_us:         u64,
+    /// Calls per latency bucket, one per LATENCY_BUCKETS_US and a last one for the slower calls.  Not cumulative.
+    buckets:        ~[u64],
+    bytes_sent:     u64,
+    bytes_received: u64,
+    /// Calls per result status
+    statuses:       ~[(MemStatus, u64)],
+}
+
+impl OpStats {
+
+    fn new(server_addr: &str, command: &str) -> OpStats {
+        return OpStats {
+            server_addr:    server_addr.to_owned(),
+            command:        command.to_owned(),
+            count:          0,
+            sum_us:         0,
+            max_us:         0,
+            buckets:        vec::from_elem(LATENCY_BUCKETS_US.len() + 1, 0u64),
+            bytes_sent:     0,
+            bytes_received: 0,
+            statuses:       ~[],
+        };
+    }
+
+    pub fn mean_us(&self) -> f64 {
+        return if self.count == 0 { 0.0 } else { self.sum_us as f64 / self.count as f64 };
+    }
+
+    /// Latency under which the fraction q of the calls fall, as the upper bound of its bucket.  max_us for the last bucket.
+    pub fn percentile_us(&self, q: f64) -> u64 {
+        let rank = (q * self.count as f64).ceil() as u64;
+        let mut seen = 0u64;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += *n;
+            if seen >= rank && seen > 0 {
+                return if i < LATENCY_BUCKETS_US.len() { LATENCY_BUCKETS_US[i] } else { self.max_us };
+            }
+        }
+        return self.max_us;
+    }
+
+    /// Calls failed with an error, not counting the misses and the rejected stores.
+    pub fn errors(&self) -> u64 {
+        return self.statuses.iter().filter(|&&(status, _)| is_error(status)).fold(0u64, |sum, &(_, n)| sum + n);
+    }
+
+    fn add_metric(&mut self, metric: &OpMetric) {
+        self.count += 1;
+        self.sum_us += metric.elapsed_us;
+        if metric.elapsed_us > self.max_us {
+            self.max_us = metric.elapsed_us;
+        }
+        let bucket = match LATENCY_BUCKETS_US.iter().position(|bound| metric.elapsed_us <= *bound) {
+            Some(i) => i,
+            None => LATENCY_BUCKETS_US.len()
+        };
+        self.buckets[bucket] += 1;
+        self.bytes_sent += metric.bytes_sent;
+        self.bytes_received += metric.bytes_received;
+        self.add_status(metric.status, 1);
+    }
+
+    fn add_stats(&mut self, other: &OpStats) {
+        self.count += other.count;
+        self.sum_us += other.sum_us;
+        if other.max_us > self.max_us {
+            self.max_us = other.max_us;
+        }
+        for i in range(0, self.buckets.len()) {
+            self.buckets[i] += other.buckets[i];
+        }
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        for &(status, n) in other.statuses.iter() {
+            self.add_status(status, n);
+        }
+    }
+
+    fn add_status(&mut self, status: MemStatus, n: u64) {
+        for i in range(0, self.statuses.len()) {
+            let (s, count) = self.statuses[i];
+            if s == status {
+                self.statuses[i] = (s, count + n);
+                return;
+            }
+        }
+        self.statuses.push((status, n));
+    }
+
+}
+
+
+/// Metrics recorded by a HistogramSink
+#[deriving(Clone)]
+pub struct MetricsSnapshot {
+    /// One per server and command called
+    ops:    ~[OpStats],
+}
+
+impl MetricsSnapshot {
+
+    /// Metrics of the command on the server
+    pub fn op<'a>(&'a self, server_addr: &str, command: &str) -> Option<&'a OpStats> {
+        return self.ops.iter().find(|op| op.server_addr.as_slice() == server_addr && op.command.as_slice() == command);
+    }
+
+    /// Metrics of the command over all the servers, with an empty server_addr.
+    pub fn command_total(&self, command: &str) -> OpStats {
+        let mut total = OpStats::new("", command);
+        for op in self.ops.iter().filter(|op| op.command.as_slice() == command) {
+            total.add_stats(op);
+        }
+        return total;
+    }
+
+    /// Commands recorded, in the order first called
+    pub fn commands(&self) -> ~[~str] {
+        let mut commands : ~[~str] = ~[];
+        for op in self.ops.iter() {
+            if !commands.contains(&op.command) {
+                commands.push(op.command.clone());
+            }
+        }
+        return commands;
+    }
+
+}
+
+
+/// MetricsSink keeping the metrics in memory, per server and command
+pub struct HistogramSink {
+    priv ops:   ~[OpStats],
+}
+
+impl HistogramSink {
+
+    pub fn new() -> HistogramSink {
+        return HistogramSink { ops: ~[] };
+    }
+
+}
+
+impl MetricsSink for HistogramSink {
+
+    fn record(&mut self, metric: &OpMetric) {
+        let index = match self.ops.iter().position(|op| op.server_addr == metric.server_addr && op.command.as_slice() == metric.command) {
+            Some(i) => i,
+            None => {
+                self.ops.push(OpStats::new(metric.server_addr, metric.command));
+                self.ops.len() - 1
+            }
+        };
+        self.ops[index].add_metric(metric);
+    }
+
+    fn snapshot(&self) -> Option<MetricsSnapshot> {
+        return Some(MetricsSnapshot { ops: self.ops.clone() });
+    }
+
+}
+
+
+impl RustyMem {
+
+    /// Snapshot of the metrics recorded by the sink of MemParams.metrics.  None if not set, or the sink doesn't keep them.
+    pub fn metrics_snapshot(&self) -> Option<MetricsSnapshot> {
+        match self.params.metrics {
+            Some(ref metrics) => metrics.snapshot(),
+            None => None
+        }
+    }
+
+}
+
+
+/// Wrap the connection to record its calls to the metrics, if any.
+pub fn metered_connection(conn: ~ProtoConnection, metrics: &Option<MemMetrics>) -> ~ProtoConnection {
+    match *metrics {
+        Some(ref metrics) => {
+            let server_addr = conn.p_get_server_addr();
+            ~MeteredConnection { conn: conn, server_addr: server_addr, metrics: metrics.clone() } as ~ProtoConnection
+        },
+        None => conn
+    }
+}
+
+
+// ProtoConnection recording the calls of the wrapped connection
+struct MeteredConnection {
+    conn:           ~ProtoConnection,
+    server_addr:    ~str,
+    metrics:        MemMetrics,
+}
+
+impl MeteredConnection {
+
+    // Run the call on the connection and record it, with the status and bytes received from its result.  The
+    // results of a get or stats call can't tell a network failure from nothing found, so a call leaving the
+    // connection down is recorded as a Network_Error.
+    fn timed<T>(&mut self, command: &'static str, bytes_sent: uint, call: &fn(&mut ~ProtoConnection) -> T, outcome: &fn(&T) -> (MemStatus, uint)) -> T {
+        let start_ns = time::precise_time_ns();
+        let result = call(&mut self.conn);
+        let elapsed_us = (time::precise_time_ns() - start_ns) / 1000;
+        let (mut status, bytes_received) = outcome(&result);
+        if !self.conn.p_is_connected() && command != "quit" {
+            status = Network_Error;
+        }
+        self.metrics.record(&OpMetric {
+                server_addr:    self.server_addr.clone(),
+                command:        command,
+                elapsed_us:     elapsed_us,
+                bytes_sent:     bytes_sent as u64,
+                bytes_received: bytes_received as u64,
+                status:         status,
+            });
+        return result;
+    }
+
+}
+
+impl ProtoConnection for MeteredConnection {
+
+    fn p_set(&mut self,  key: &str,  data: &[u8],  cas: u64,  flags: u32,  exptime: uint,  noreply: bool) -> MemResult<u64> {
+        return self.timed("set", key.len() + data.len(), |conn| conn.p_set(key, data, cas, flags, exptime, noreply), |r| (r.status, 0));
+    }
+
+    fn p_cas(&mut self, key: &str, data: &[u8], cas_unique: u64, flags: u32, exptime: uint, noreply: bool) -> MemResult<u64> {
+        return self.timed("cas", key.len() + data.len(), |conn| conn.p_cas(key, data, cas_unique, flags, exptime, noreply), |r| (r.status, 0));
+    }
+
+    fn p_add(&mut self,  key: &str,  data: &[u8],  cas: u64,  flags: u32,  exptime: uint,  noreply: bool) -> MemResult<u64> {
+        return self.timed("add", key.len() + data.len(), |conn| conn.p_add(key, data, cas, flags, exptime, noreply), |r| (r.status, 0));
+    }
+
+    fn p_replace(&mut self,  key: &str,  data: &[u8],  cas: u64,  flags: u32,  exptime: uint,  noreply: bool) -> MemResult<u64> {
+        return self.timed("replace", key.len() + data.len(), |conn| conn.p_replace(key, data, cas, flags, exptime, noreply), |r| (r.status, 0));
+    }
+
+    fn p_append(&mut self, key: &str, data: &[u8], noreply: bool) -> MemResult<u64> {
+        return self.timed("append", key.len() + data.len(), |conn| conn.p_append(key, data, noreply), |r| (r.status, 0));
+    }
+
+    fn p_prepend(&mut self, key: &str, data: &[u8], noreply: bool) -> MemResult<u64> {
+        return self.timed("prepend", key.len() + data.len(), |conn| conn.p_prepend(key, data, noreply), |r| (r.status, 0));
+    }
+
+    fn p_touch(&mut self, key: &str, exptime: uint, noreply: bool) -> MemStatus {
+        return self.timed("touch", key.len(), |conn| conn.p_touch(key, exptime, noreply), |s| (*s, 0));
+    }
+
+    fn p_incr(&mut self, key: &str, inc_amount: u64, init_value: u64, exptime: uint, noreply: bool) -> MemResult<u64> {
+        return self.timed("incr", key.len(), |conn| conn.p_incr(key, inc_amount, init_value, exptime, noreply), |r| (r.status, 0));
+    }
+
+    fn p_decr(&mut self, key: &str, dec_amount: u64, init_value: u64, exptime: uint, noreply: bool) -> MemResult<u64> {
+        return self.timed("decr", key.len(), |conn| conn.p_decr(key, dec_amount, init_value, exptime, noreply), |r| (r.status, 0));
+    }
+
+    fn p_delete(&mut self, key: &str, noreply: bool) -> MemStatus {
+        return self.timed("delete", key.len(), |conn| conn.p_delete(key, noreply), |s| (*s, 0));
+    }
+
+    fn p_delete_cas(&mut self, key: &str, cas_unique: u64, noreply: bool) -> MemStatus {
+        return self.timed("delete", key.len(), |conn| conn.p_delete_cas(key, cas_unique, noreply), |s| (*s, 0));
+    }
+
+    fn p_get(&mut self, keys: &[&str]) -> ~[MemData] {
+        return self.timed("get", keys_len(keys), |conn| conn.p_get(keys), |md_list| data_outcome(md_list));
+    }
+
+    fn p_gets(&mut self, keys: &[&str]) -> ~[MemData] {
+        return self.timed("gets", keys_len(keys), |conn| conn.p_gets(keys), |md_list| data_outcome(md_list));
+    }
+
+    fn p_pipeline(&mut self, cmds: &[PipelineCmd]) -> ~[MemResult<u64>] {
+        return self.timed("pipeline", pipeline_len(cmds), |conn| conn.p_pipeline(cmds), |results| pipeline_outcome(results));
+    }
+
+    fn p_version(&mut self) -> Result<~str, ~str> {
+        return self.timed("version", 0, |conn| conn.p_version(), |r| {
+                match *r {
+                    Ok(ref version) => (Success, version.len()),
+                    Err(_) => (Network_Error, 0)
+                }
+            });
+    }
+
+    fn p_verbosity(&mut self, verbosity: u32, noreply: bool) -> MemStatus {
+        return self.timed("verbosity", 0, |conn| conn.p_verbosity(verbosity, noreply), |s| (*s, 0));
+    }
+
+    fn p_flush(&mut self, delay_in_seconds: uint, noreply: bool) -> MemStatus {
+        return self.timed("flush", 0, |conn| conn.p_flush(delay_in_seconds, noreply), |s| (*s, 0));
+    }
+
+    fn p_stats(&mut self) -> ~[MemcachedStat] {
+        return self.timed("stats", 0, |conn| conn.p_stats(), |stats| stats_outcome(stats));
+    }
+
+    fn p_stats_group(&mut self, group: &str) -> ~[MemcachedStat] {
+        return self.timed("stats", group.len(), |conn| conn.p_stats_group(group), |stats| stats_outcome(stats));
+    }
+
+    fn p_stats_reset(&mut self) -> MemStatus {
+        return self.timed("stats", "reset".len(), |conn| conn.p_stats_reset(), |s| (*s, 0));
+    }
+
+    fn p_quit(&mut self) -> MemStatus {
+        return self.timed("quit", 0, |conn| conn.p_quit(), |s| (*s, 0));
+    }
+
+    fn p_get_server_addr(&self) -> ~str {
+        return self.conn.p_get_server_addr();
+    }
+
+    fn p_is_connected(&self) -> bool {
+        return self.conn.p_is_connected();
+    }
+
+    fn p_reconnect(&mut self) -> bool {
+        return self.timed("reconnect", 0, |conn| conn.p_reconnect(), |connected| (if *connected { Success } else { Network_Error }, 0));
+    }
+
+}
+
+
+fn keys_len(keys: &[&str]) -> uint {
+    return keys.iter().fold(0u, |len, key| len + key.len());
+}
+
+fn data_outcome(md_list: &~[MemData]) -> (MemStatus, uint) {
+    let status = if md_list.len() == 0 { Key_Not_Found } else { Success };
+    return (status, md_list.iter().fold(0u, |len, md| len + md.key.len() + md.data.len()));
+}
+
+fn stats_outcome(stats: &~[MemcachedStat]) -> (MemStatus, uint) {
+    return (Success, stats.iter().fold(0u, |len, stat| len + stat.name.len() + stat.value.len()));
+}
+
+fn pipeline_len(cmds: &[PipelineCmd]) -> uint {
+    return cmds.iter().fold(0u, |len, cmd| {
+            len + match *cmd {
+                PIPE_STORE(_, ref key, ref data, _, _, _) => key.len() + data.len(),
+                PIPE_INCR(ref key, _, _, _) | PIPE_DECR(ref key, _, _, _) => key.len(),
+                PIPE_TOUCH(ref key, _) | PIPE_DELETE(ref key) => key.len(),
+            }
+        });
+}
+
+// The first error of the pipelined commands, or Success
+fn pipeline_outcome(results: &~[MemResult<u64>]) -> (MemStatus, uint) {
+    match results.iter().find(|r| is_error(r.status)) {
+        Some(r) => (r.status, 0),
+        None => (Success, 0)
+    }
+}
+
+// Whether the status is an error, rather than an expected result of a lookup or a conditional store
+fn is_error(status: MemStatus) -> bool {
+    return status != Success && status != Key_Not_Found && status != Key_Exists && status != Item_Not_Stored;
+}
+
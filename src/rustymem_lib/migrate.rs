@@ -0,0 +1,426 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::task;
+use std::cell::Cell;
+use std::comm::{stream, Port, SharedChan, GenericChan, GenericPort};
+use std::hashmap::HashSet;
+use extra::arc::{RWArc, MutexArc};
+
+
+use super::super::RustyMem;
+use super::super::MemParams;
+use super::super::MemData;
+use super::super::MemResult;
+use super::super::MemStatus;
+use super::super::Success;
+use super::super::OP_ADD;
+use super::super::connect_with;
+
+
+
+//
+// Live migration between two clusters
+//
+// MigratingMem wraps a RustyMem on the old cluster and one on the new cluster, and goes through the phases:
+//
+//   MIGRATE_WARMING:       writes go to both, reads to the old cluster.  The keys read are queued to the copier.
+//   MIGRATE_DUAL_WRITE:    writes go to both, reads to the new cluster, falling back to the old one on a miss.
+//                          A value found on the old cluster is backfilled into the new one.
+//   MIGRATE_NEW_ONLY:      everything goes to the new cluster.
+//
+// The phase is read on every call from a MigrationSwitch, which can be cloned and set from any task.
+// The copier is a background task with its own clients of both clusters, copying the queued keys from the
+// old cluster to the new one.  Backfills and copies use add, so they never overwrite a value written to the
+// new cluster since.  The TTL of the old item is not known, so they're stored with copy_exptime.
+// The copy queue is a set shared with the copier, so a key read again before it's copied is queued once,
+// and keys over MAX_COPY_QUEUE are not queued; they're backfilled on their reads in MIGRATE_DUAL_WRITE.
+// The copier is woken through a channel when the queue gets its first key.  A delete drops the key from
+// the queue, or leaves a tombstone if the copier is copying it.  The copier checks the tombstone before its
+// add, and again after it, deleting the key from the new cluster if it was deleted during the add, so a
+// deleted key is not brought back by a copy read before the delete.  The add is done out of the queue lock,
+// so a slow new cluster doesn't hold up the reads and deletes queuing to the copier.
+//
+
+// Keys copied per bulk get of the copier
+static COPY_BATCH: uint         = 100;
+
+// Max keys waiting in the copy queue
+static MAX_COPY_QUEUE: uint     = 10000;
+
+
+/// Phase of a migration
+#[deriving(Eq, Clone)]
+pub enum MigrationPhase {
+    MIGRATE_WARMING,
+    MIGRATE_DUAL_WRITE,
+    MIGRATE_NEW_ONLY,
+}
+
+
+/// Switch of the migration phase, shared between tasks.
+#[deriving(Clone)]
+pub struct MigrationSwitch {
+    priv phase:     RWArc<MigrationPhase>,
+}
+
+impl MigrationSwitch {
+
+    pub fn get(&self) -> MigrationPhase {
+        return self.phase.read(|phase| *phase);
+    }
+
+    pub fn set(&self, phase: MigrationPhase) {
+        do self.phase.write |current| {
+            debug!( fmt!("migration phase %? -> %?", *current, phase) );
+            *current = phase;
+        }
+    }
+
+}
+
+
+/// Counts of the reads and copies of a MigratingMem
+#[deriving(Clone)]
+pub struct MigrationStats {
+    /// Reads missing the new cluster and found on the old one
+    fallback_hits:      u64,
+    /// Reads missing both clusters
+    fallback_misses:    u64,
+    /// Values backfilled into the new cluster
+    backfills:          u64,
+    /// Keys queued to the copier
+    copies_queued:      u64,
+    /// Keys read but not queued, already in the queue or over MAX_COPY_QUEUE
+    copies_skipped:     u64,
+}
+
+
+// Keys queued to the copier, shared with it
+struct CopyQueue {
+    queued:     HashSet<~str>,
+    // Keys taken by the copier and not copied yet
+    copying:    HashSet<~str>,
+    // Keys deleted while being copied
+    deleted:    HashSet<~str>,
+}
+
+impl CopyQueue {
+
+    // Queue the key.  Return None if it's not queued, or whether the queue was empty to wake the copier.
+    fn push(&mut self, key: ~str) -> Option<bool> {
+        if self.queued.len() >= MAX_COPY_QUEUE || self.queued.contains(&key) {
+            return None;
+        }
+        let was_empty = self.queued.len() == 0;
+        self.queued.insert(key);
+        return Some(was_empty);
+    }
+
+    fn take_batch(&mut self, max: uint) -> ~[~str] {
+        let batch = self.queued.iter().take(max).map(|k| k.clone()).collect::<~[~str]>();
+        for key in batch.iter() {
+            self.queued.remove(key);
+            self.copying.insert(key.clone());
+        }
+        return batch;
+    }
+
+    fn done(&mut self, batch: &[~str]) {
+        for key in batch.iter() {
+            self.copying.remove(key);
+            self.deleted.remove(key);
+        }
+    }
+
+    fn delete(&mut self, key: ~str) {
+        self.queued.remove(&key);
+        if self.copying.contains(&key) {
+            self.deleted.insert(key);
+        }
+    }
+
+}
+
+
+/// Client migrating from an old cluster to a new one
+pub struct MigratingMem {
+    priv old:           RustyMem,
+    priv new:           RustyMem,
+    priv switch:        MigrationSwitch,
+    priv copy_exptime:  uint,
+    priv copier:        SharedChan<()>,
+    priv copy_queue:    MutexArc<CopyQueue>,
+    priv stats:         MigrationStats,
+}
+
+impl MigratingMem {
+
+    /// Connect to the old and new clusters, starting at the phase.  Values backfilled or copied to the new
+    /// cluster expire in copy_exptime seconds, 0 for no expiration.
+    pub fn new(old_params: MemParams, new_params: MemParams, phase: MigrationPhase, copy_exptime: uint) -> MigratingMem {
+        let (port, chan) = stream::<()>();
+        let copy_queue = MutexArc::new(CopyQueue { queued: HashSet::new(), copying: HashSet::new(), deleted: HashSet::new() });
+        let copier_params = Cell::new((old_params.clone(), new_params.clone()));
+        let copier_queue = Cell::new(copy_queue.clone());
+        let port = Cell::new(port);
+        do task::spawn {
+            let (old_params, new_params) = copier_params.take();
+            copy_keys(old_params, new_params, copy_exptime, copier_queue.take(), port.take());
+        }
+        return MigratingMem {
+            old:            connect_with(old_params),
+            new:            connect_with(new_params),
+            switch:         MigrationSwitch { phase: RWArc::new(phase) },
+            copy_exptime:   copy_exptime,
+            copier:         SharedChan::new(chan),
+            copy_queue:     copy_queue,
+            stats:          MigrationStats { fallback_hits: 0, fallback_misses: 0, backfills: 0, copies_queued: 0, copies_skipped: 0 },
+        };
+    }
+
+    /// Switch of the phase, to change it from any task.
+    pub fn switch(&self) -> MigrationSwitch {
+        return self.switch.clone();
+    }
+
+    pub fn phase(&self) -> MigrationPhase {
+        return self.switch.get();
+    }
+
+    pub fn set_phase(&self, phase: MigrationPhase) {
+        self.switch.set(phase);
+    }
+
+    pub fn stats(&self) -> MigrationStats {
+        return self.stats.clone();
+    }
+
+    /// The clients of the old and new clusters, e.g. for calls not wrapped here.
+    pub fn old_mem<'a>(&'a mut self) -> &'a mut RustyMem {
+        return &mut self.old;
+    }
+
+    pub fn new_mem<'a>(&'a mut self) -> &'a mut RustyMem {
+        return &mut self.new;
+    }
+
+
+    pub fn set_bytes(&mut self, key: &str, exptime: uint, data_bytes: &[u8]) -> MemResult<u64> {
+        return self.write(|rm| rm.set_bytes(key, exptime, data_bytes));
+    }
+
+    pub fn set_str(&mut self, key: &str, exptime: uint, data_str: &str) -> MemResult<u64> {
+        return self.write(|rm| rm.set_str(key, exptime, data_str));
+    }
+
+    pub fn set_int(&mut self, key: &str, exptime: uint, value: u64) -> MemResult<u64> {
+        return self.write(|rm| rm.set_int(key, exptime, value));
+    }
+
+    pub fn add_str(&mut self, key: &str, exptime: uint, data_str: &str) -> MemResult<u64> {
+        return self.write(|rm| rm.add_str(key, exptime, data_str));
+    }
+
+    pub fn touch(&mut self, key: &str, exptime: uint) -> MemStatus {
+        return self.write(|rm| rm.touch(key, exptime));
+    }
+
+    /// The key is dropped from the copy queue, and not kept by the copier if it has already read it.
+    pub fn delete(&mut self, key: &str) -> MemStatus {
+        let key_owned = key.to_owned();
+        access_queue(&self.copy_queue, |queue| queue.delete(key_owned.clone()));
+        return self.write(|rm| rm.delete(key));
+    }
+
+    /// Counters are updated on both clusters, each with its own value until the old one is retired.
+    pub fn incr(&mut self, key: &str, inc_amount: u64, init_value: u64, exptime: uint) -> MemResult<u64> {
+        return self.write(|rm| rm.incr(key, inc_amount, init_value, exptime));
+    }
+
+    pub fn decr(&mut self, key: &str, dec_amount: u64, init_value: u64, exptime: uint) -> MemResult<u64> {
+        return self.write(|rm| rm.decr(key, dec_amount, init_value, exptime));
+    }
+
+
+    /// Get the data item as MemData at key, from the cluster of the phase.
+    pub fn get_data(&mut self, key: &str) -> Option<MemData> {
+        match self.phase() {
+            MIGRATE_WARMING => {
+                let result = self.old.get_data(key);
+                if result.is_some() {
+                    self.queue_copy(key);
+                }
+                result
+            },
+            MIGRATE_DUAL_WRITE => {
+                match self.new.get_data(key) {
+                    Some(md) => Some(md),
+                    None => {
+                        let result = self.old.get_data(key);
+                        if result.is_none() {
+                            self.stats.fallback_misses += 1;
+                        }
+                        self.backfill(result.iter());
+                        result
+                    }
+                }
+            },
+            MIGRATE_NEW_ONLY => self.new.get_data(key)
+        }
+    }
+
+    pub fn get_bytes(&mut self, key: &str) -> Option<~[u8]> {
+        match self.get_data(key) {
+            Some(md) => md.try_bytes().ok(),
+            None => None
+        }
+    }
+
+    pub fn get_str(&mut self, key: &str) -> Option<~str> {
+        match self.get_data(key) {
+            Some(md) => md.try_str().ok(),
+            None => None
+        }
+    }
+
+    pub fn get_int(&mut self, key: &str) -> Option<u64> {
+        match self.get_data(key) {
+            Some(md) => md.as_type::<u64>(),
+            None => None
+        }
+    }
+
+    /// Get the list of data as MemData of the list of keys, from the cluster of the phase.
+    pub fn get_bulk_data(&mut self, keys: &[&str]) -> ~[MemData] {
+        match self.phase() {
+            MIGRATE_WARMING => {
+                let result = self.old.get_bulk_data(keys);
+                for md in result.iter() {
+                    self.queue_copy(md.key);
+                }
+                result
+            },
+            MIGRATE_DUAL_WRITE => {
+                let mut result = self.new.get_bulk_data(keys);
+                let mut found = HashSet::<~str>::new();
+                for md in result.iter() {
+                    found.insert(md.key.clone());
+                }
+                let missed = keys.iter().filter(|k| !found.contains(&k.to_owned())).map(|k| *k).collect::<~[&str]>();
+                if missed.len() > 0 {
+                    let old_result = self.old.get_bulk_data(missed);
+                    self.stats.fallback_misses += (missed.len() - old_result.len()) as u64;
+                    self.backfill(old_result.iter());
+                    result.push_all_move(old_result);
+                }
+                result
+            },
+            MIGRATE_NEW_ONLY => self.new.get_bulk_data(keys)
+        }
+    }
+
+
+    // Run the write on the clusters of the phase.  Return the result of the cluster read in the phase.
+    fn write<T>(&mut self, blk: &fn(&mut RustyMem) -> T) -> T {
+        match self.phase() {
+            MIGRATE_WARMING => {
+                blk(&mut self.new);
+                blk(&mut self.old)
+            },
+            MIGRATE_DUAL_WRITE => {
+                blk(&mut self.old);
+                blk(&mut self.new)
+            },
+            MIGRATE_NEW_ONLY => blk(&mut self.new)
+        }
+    }
+
+    // Add the values read from the old cluster to the new one, counting them as fallback hits.
+    fn backfill<'a, I: Iterator<&'a MemData>>(&mut self, md_iter: I) {
+        let mut md_iter = md_iter;
+        for md in md_iter {
+            self.stats.fallback_hits += 1;
+            let result = self.new.store_flags_cmd(OP_ADD, md.key, md.data, 0, md.flags, self.copy_exptime);
+            if result.status == Success {
+                self.stats.backfills += 1;
+            }
+        }
+    }
+
+    fn queue_copy(&mut self, key: &str) {
+        let key = key.to_owned();
+        match access_queue(&self.copy_queue, |queue| queue.push(key.clone())) {
+            Some(was_empty) => {
+                if was_empty {
+                    self.copier.send(());
+                }
+                self.stats.copies_queued += 1;
+            },
+            None => self.stats.copies_skipped += 1
+        }
+    }
+
+}
+
+
+// Copy the queued keys from the old cluster to the new one, until the MigratingMem is dropped.  Each wakeup
+// drains the queue.
+fn copy_keys(old_params: MemParams, new_params: MemParams, copy_exptime: uint, copy_queue: MutexArc<CopyQueue>, port: Port<()>) {
+    let mut old = connect_with(old_params);
+    let mut new = connect_with(new_params);
+    loop {
+        match port.try_recv() {
+            Some(_) => (),
+            None => break
+        }
+        loop {
+            let batch = access_queue(&copy_queue, |queue| queue.take_batch(COPY_BATCH));
+            if batch.len() == 0 {
+                break;
+            }
+            let key_refs = batch.iter().map(|k| k.as_slice()).collect::<~[&str]>();
+            let md_list = old.get_bulk_data(key_refs);
+            let mut copied = 0;
+            for md in md_list.iter() {
+                if access_queue(&copy_queue, |queue| queue.deleted.contains(&md.key)) {
+                    continue;
+                }
+                // Out of the queue lock, not to hold up the callers on a slow server.
+                let result = new.store_flags_cmd(OP_ADD, md.key, md.data, 0, md.flags, copy_exptime);
+                if result.status != Success {
+                    continue;
+                }
+                // Deleted while being added, possibly before the add landed.
+                if access_queue(&copy_queue, |queue| queue.deleted.contains(&md.key)) {
+                    new.delete(md.key);
+                } else {
+                    copied += 1;
+                }
+            }
+            access_queue(&copy_queue, |queue| queue.done(batch));
+            debug!( fmt!("copied %? of %? keys", copied, batch.len()) );
+        }
+    }
+}
+
+fn access_queue<U>(copy_queue: &MutexArc<CopyQueue>, blk: &fn(&mut CopyQueue) -> U) -> U {
+    unsafe {
+        return copy_queue.access(blk);
+    }
+}
@@ -0,0 +1,132 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::result::Result;
+
+
+use common::timeutil;
+
+
+use super::super::RustyMem;
+use super::super::MemStatus;
+use super::super::MemResult;
+use super::super::Success;
+use super::super::Key_Exists;
+use super::super::Key_Not_Found;
+
+
+
+//
+// Namespace versioning
+//
+// Memcached can't delete by pattern.  Instead each namespace has a version counter stored in the cache,
+// and the effective key of an entry is "ns:version:key".  Bumping the counter invalidates all the entries
+// of the namespace at once; the old entries are never read again and age out of the LRU.  The versions are
+// cached locally by the wire key of the counter, since the same namespace under another key prefix is
+// another counter.
+//
+
+
+/// Locally cached version of a namespace
+pub struct NsVersion {
+    version:        u64,
+    expire_ms:      u64,
+}
+
+
+impl RustyMem {
+
+    /// Build the effective key "ns:version:key" of the key in the namespace.
+    /// The namespace version is cached locally for params.ns_cache_ms milliseconds.
+    pub fn ns_key(&mut self, namespace: &str, key: &str) -> Result<~str, MemStatus> {
+        match self.ns_version(namespace) {
+            Ok(version) => Ok(fmt!("%s:%?:%s", namespace, version, key)),
+            Err(status) => Err(status)
+        }
+    }
+
+    /// Get the current version of the namespace, creating its counter if not existed.
+    pub fn ns_version(&mut self, namespace: &str) -> Result<u64, MemStatus> {
+        let now = timeutil::now_ms();
+        let vkey = ns_version_key(namespace);
+        let cache_key = match self.wire_key(vkey) {
+            Ok(wkey)    => wkey,
+            Err(status) => return Err(status)
+        };
+        match self.ns_versions.find(&cache_key) {
+            Some(nsv) if nsv.expire_ms > now => return Ok(nsv.version),
+            _ => ()
+        }
+
+        let version = match self.ns_read_version(vkey) {
+            Some(version) => version,
+            None => {
+                // Seed a new counter with the time so a counter lost to eviction doesn't bring back old versions.
+                let seed = timeutil::now_secs();
+                match self.add_as(vkey, 0, &seed).status {
+                    Success     => seed,
+                    Key_Exists  => match self.ns_read_version(vkey) {
+                        Some(version) => version,
+                        None => return Err(Key_Not_Found)
+                    },
+                    status      => return Err(status)
+                }
+            }
+        };
+        self.ns_cache_version(cache_key, version, now);
+        return Ok(version);
+    }
+
+    // Read the version counter past the L1 cache, whose entry could be older than ns_cache_ms.
+    fn ns_read_version(&mut self, vkey: &str) -> Option<u64> {
+        return do self.with_l1_bypass |rm| { rm.get_as::<u64>(vkey) };
+    }
+
+    /// Invalidate all the entries in the namespace by bumping its version.
+    /// Other RustyMem instances see the new version when their locally cached version expires.
+    pub fn ns_invalidate(&mut self, namespace: &str) -> MemResult<u64> {
+        let vkey = ns_version_key(namespace);
+        let cache_key = match self.wire_key(vkey) {
+            Ok(wkey)    => wkey,
+            Err(status) => return MemResult { status: status, value: 0 }
+        };
+        let seed = timeutil::now_secs();
+        let result = self.incr(vkey, 1, seed, 0);
+        match result.status {
+            Success if result.value > 0 => self.ns_cache_version(cache_key, result.value, timeutil::now_ms()),
+            _ => { self.ns_versions.remove(&cache_key); }
+        }
+        return result;
+    }
+
+    // Cache the version by the wire key of the namespace's counter.
+    fn ns_cache_version(&mut self, cache_key: ~str, version: u64, now: u64) {
+        let nsv = NsVersion {
+            version:    version,
+            expire_ms:  now + self.params.ns_cache_ms as u64
+        };
+        self.ns_versions.insert(cache_key, nsv);
+    }
+
+}
+
+
+// Key of the version counter of the namespace
+fn ns_version_key(namespace: &str) -> ~str {
+    return "__ns:" + namespace;
+}
+
@@ -0,0 +1,326 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::task;
+use std::cell::Cell;
+use std::result::Result;
+use extra::arc::{MutexArc, Condvar};
+
+
+use common::timeutil;
+
+
+use super::super::RustyMem;
+use super::super::MemParams;
+use super::super::MemStatus;
+use super::super::Pool_Exhausted;
+use super::super::connect_with;
+
+
+
+//
+// Client pool shared between tasks
+//
+// The pool keeps RustyMem clients with one connection per server each, so pool_min and pool_max clients are
+// pool_min and pool_max connections per server.  A task checks out a client, runs its calls on it, and the
+// client is checked back in when the PooledMem is dropped.  Clones of a MemPool share the same clients.
+// A client idle for pool_validate_idle_ms is validated with a version call on its connections at checkout.
+// A client failing the validation, or losing a connection while checked out, is closed instead of reused.
+// Each client has its own L1 cache and server health.
+// A checkout on an exhausted pool waits on the condition of the pool lock, signaled when a client is checked
+// in or a slot is freed.  A timer task broadcasts the condition at the end of pool_wait_ms, for the waiting
+// checkout to give up.
+//
+
+
+/// Counts of the clients of a pool
+#[deriving(Clone)]
+pub struct PoolStats {
+    idle:       uint,
+    in_use:     uint,
+    max_size:   uint,
+    /// Checkouts which had to wait for a client
+    waits:      u64,
+    /// Checkouts failed with Pool_Exhausted after waiting pool_wait_ms
+    timeouts:   u64,
+    /// Clients closed for a broken connection
+    evicted:    u64,
+}
+
+
+/// Pool of RustyMem clients, to be cloned and shared between tasks.
+/// let pool = MemPool::new(MemParams::new("127.0.0.1", P_BINARY));
+/// let mut pm = pool.checkout().unwrap();
+/// pm.mem().set_str("key1", 0, "value1");
+#[deriving(Clone)]
+pub struct MemPool {
+    priv state:     MutexArc<PoolState>,
+}
+
+struct PoolState {
+    params:     MemParams,
+    idle:       ~[IdleMem],
+    stats:      PoolStats,
+}
+
+struct IdleMem {
+    rm:             RustyMem,
+    last_used_ms:   u64,
+}
+
+enum Checkout {
+    CHECKOUT_IDLE(IdleMem),
+    CHECKOUT_NEW(MemParams),
+    CHECKOUT_NONE,
+}
+
+
+impl MemPool {
+
+    /// Create the pool with the params, opening pool_min clients.
+    pub fn new(params: MemParams) -> MemPool {
+        let idle = range(0, params.pool_min).map(|_| {
+                IdleMem { rm: connect_with(params.clone()), last_used_ms: timeutil::now_ms() }
+            }).collect::<~[IdleMem]>();
+        let stats = PoolStats {
+            idle:       idle.len(),
+            in_use:     0,
+            max_size:   params.pool_max,
+            waits:      0,
+            timeouts:   0,
+            evicted:    0,
+        };
+        let state = PoolState {
+            params: params,
+            idle:   idle,
+            stats:  stats,
+        };
+        return MemPool { state: MutexArc::new(state) };
+    }
+
+    /// Check out a client, waiting up to pool_wait_ms for one to be checked in when pool_max clients are in use.
+    /// Return Pool_Exhausted if none is available in time.
+    pub fn checkout(&self) -> Result<PooledMem, MemStatus> {
+        let (wait_ms, validate_idle_ms) = self.access(|state| (state.params.pool_wait_ms as u64, state.params.pool_validate_idle_ms as u64));
+        let deadline_ms = timeutil::now_ms() + wait_ms;
+        let mut waiting = false;
+        loop {
+            match self.access(|state| state.take()) {
+                CHECKOUT_IDLE(IdleMem { rm, last_used_ms }) => {
+                    let mut rm = rm;
+                    let idle_ms = timeutil::now_ms() - last_used_ms;
+                    if validate_idle_ms == 0 || idle_ms < validate_idle_ms || rm.ping_connected() {
+                        return Ok(PooledMem::new(self.clone(), rm));
+                    }
+                    debug!("evict pooled client failing validation");
+                    self.evict(rm);
+                },
+                CHECKOUT_NEW(params) => {
+                    return Ok(PooledMem::new(self.clone(), connect_with(params)));
+                },
+                CHECKOUT_NONE => {
+                    if !waiting {
+                        waiting = true;
+                        self.access(|state| state.stats.waits += 1);
+                        self.wake_at(deadline_ms);
+                    }
+                    if timeutil::now_ms() >= deadline_ms {
+                        self.access(|state| state.stats.timeouts += 1);
+                        return Err(Pool_Exhausted);
+                    }
+                    // Checked again under the lock, not to miss a checkin since the take.
+                    self.access_cond(|state, cond| {
+                            if !state.can_take() && timeutil::now_ms() < deadline_ms {
+                                cond.wait();
+                            }
+                        });
+                }
+            }
+        }
+    }
+
+    /// Check out a client and run the block with it.  Return Pool_Exhausted if no client is available in time.
+    /// let value = pool.with_mem(|rm| rm.get_str("key1"));
+    pub fn with_mem<T>(&self, blk: &fn(&mut RustyMem) -> T) -> Result<T, MemStatus> {
+        return match self.checkout() {
+            Ok(mut pm)  => Ok(blk(pm.mem())),
+            Err(status) => Err(status)
+        };
+    }
+
+    /// Current counts of the clients
+    pub fn stats(&self) -> PoolStats {
+        return self.access(|state| {
+                let mut stats = state.stats.clone();
+                stats.idle = state.idle.len();
+                stats
+            });
+    }
+
+    // Put a client back to the idle list, or close it if a connection up at checkout is down now.
+    // A client whose servers were changed while checked out is closed too.
+    fn checkin(&self, rm: RustyMem, connected_at_checkout: &[bool]) {
+        let broken = rm.get_connection_count() != connected_at_checkout.len() ||
+            range(0, rm.get_connection_count()).any(|i| connected_at_checkout[i] && !rm.connections[i].p_is_connected());
+        if broken {
+            debug!("evict pooled client with a broken connection");
+            self.evict(rm);
+            return;
+        }
+        self.checkin_new(rm);
+    }
+
+    // Close a checked out client, and open a new one if the pool falls under pool_min.
+    fn evict(&self, rm: RustyMem) {
+        let mut rm = rm;
+        rm.quit();
+        let refill = self.access_cond(|state, cond| {
+                state.stats.in_use -= 1;
+                state.stats.evicted += 1;
+                if state.idle.len() + state.stats.in_use < state.params.pool_min {
+                    state.stats.in_use += 1;
+                    Some(state.params.clone())
+                } else {
+                    cond.signal();
+                    None
+                }
+            });
+        match refill {
+            Some(params) => self.checkin_new(connect_with(params)),
+            None => ()
+        }
+    }
+
+    // Put the client to the idle list, and wake up a checkout waiting for one.
+    fn checkin_new(&self, rm: RustyMem) {
+        let mut rm = Some(rm);
+        self.access_cond(|state, cond| {
+                state.stats.in_use -= 1;
+                state.idle.push(IdleMem { rm: rm.take_unwrap(), last_used_ms: timeutil::now_ms() });
+                cond.signal();
+            });
+    }
+
+    // Wake up the checkouts waiting at the deadline, for the one waiting until then to time out.
+    fn wake_at(&self, deadline_ms: u64) {
+        let pool = Cell::new(self.clone());
+        do task::spawn {
+            let pool = pool.take();
+            let mut now_ms = timeutil::now_ms();
+            while now_ms < deadline_ms {
+                timeutil::sleep_ms(deadline_ms - now_ms);
+                now_ms = timeutil::now_ms();
+            }
+            pool.access_cond(|_, cond| { cond.broadcast(); });
+        }
+    }
+
+    // The state is never accessed nested, and clients are connected and closed outside of it, so holding the
+    // lock is short and can't deadlock.
+    fn access<U>(&self, blk: &fn(&mut PoolState) -> U) -> U {
+        unsafe {
+            return self.state.access(blk);
+        }
+    }
+
+    fn access_cond<U>(&self, blk: &fn(&mut PoolState, &Condvar) -> U) -> U {
+        unsafe {
+            return self.state.access_cond(blk);
+        }
+    }
+
+}
+
+
+impl PoolState {
+
+    // Take an idle client, or reserve a slot for a new one if under pool_max.
+    fn take(&mut self) -> Checkout {
+        if self.idle.len() > 0 {
+            self.stats.in_use += 1;
+            return CHECKOUT_IDLE(self.idle.pop());
+        }
+        if self.stats.in_use < self.params.pool_max {
+            self.stats.in_use += 1;
+            return CHECKOUT_NEW(self.params.clone());
+        }
+        return CHECKOUT_NONE;
+    }
+
+    fn can_take(&self) -> bool {
+        return self.idle.len() > 0 || self.stats.in_use < self.params.pool_max;
+    }
+
+}
+
+
+/// Client checked out of a MemPool, checked back in when dropped.
+pub struct PooledMem {
+    priv pool:                  MemPool,
+    priv rm:                    Option<RustyMem>,
+    priv connected_at_checkout: ~[bool],
+}
+
+impl PooledMem {
+
+    fn new(pool: MemPool, rm: RustyMem) -> PooledMem {
+        let connected = range(0, rm.get_connection_count()).map(|i| rm.connections[i].p_is_connected()).collect::<~[bool]>();
+        return PooledMem {
+            pool:                   pool,
+            rm:                     Some(rm),
+            connected_at_checkout:  connected,
+        };
+    }
+
+    /// The checked out client
+    pub fn mem<'a>(&'a mut self) -> &'a mut RustyMem {
+        match self.rm {
+            Some(ref mut rm) => rm,
+            None => fail!("client already checked in")
+        }
+    }
+
+}
+
+impl Drop for PooledMem {
+    fn drop(&mut self) {
+        match self.rm.take() {
+            Some(rm) => self.pool.checkin(rm, self.connected_at_checkout),
+            None => ()
+        }
+    }
+}
+
+
+impl RustyMem {
+
+    // Check the connections up with a version call.  Return false if any of them fails.
+    fn ping_connected(&mut self) -> bool {
+        for i in range(0, self.get_connection_count()) {
+            if self.connections[i].p_is_connected() {
+                let ok = self.run_on(i, |conn| conn.p_version().is_ok());
+                if !ok || !self.connections[i].p_is_connected() {
+                    return false;
+                }
+            }
+        }
+        return true;
+    }
+
+}
+
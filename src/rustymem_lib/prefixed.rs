@@ -0,0 +1,83 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+
+use std::util;
+
+
+use super::super::RustyMem;
+
+
+
+//
+// Prefixed view
+//
+// A view of a RustyMem with a key prefix of its own, over the same connections, L1 cache and server health.
+// The view holds the RustyMem borrowed with its key prefix swapped in, so every operation through it, bulk
+// gets and sharding included, uses the view's prefix, and the keys of the returned MemData have it stripped.
+// The RustyMem gets its own key prefix back when the view is dropped.  Views nest: a view derived from the
+// RustyMem of another view has the prefix of the other view back when it's dropped.
+//
+
+
+/// View of a RustyMem with a different key prefix.  Use mem() to run the operations under the view's prefix.
+/// let mut tenant2 = rm.prefixed("tenant2:");
+/// tenant2.mem().set_str("key1", 60, "value1");
+pub struct PrefixedMem<'a> {
+    priv rm:            &'a mut RustyMem,
+    priv saved_prefix:  ~str,
+}
+
+impl<'a> PrefixedMem<'a> {
+
+    /// Key prefix of the view
+    pub fn key_prefix(&self) -> ~str {
+        return self.rm.params.key_prefix.clone();
+    }
+
+    pub fn mem<'b>(&'b mut self) -> &'b mut RustyMem {
+        return &mut *self.rm;
+    }
+
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for PrefixedMem<'a> {
+    fn drop(&mut self) {
+        let saved_prefix = util::replace(&mut self.saved_prefix, ~"");
+        self.rm.params.key_prefix = saved_prefix;
+    }
+}
+
+
+impl RustyMem {
+
+    /// Derive a view of this RustyMem using the key prefix instead of its own, over the same connections.
+    pub fn prefixed<'a>(&'a mut self, key_prefix: &str) -> PrefixedMem<'a> {
+        let saved_prefix = util::replace(&mut self.params.key_prefix, key_prefix.to_owned());
+        return PrefixedMem { rm: self, saved_prefix: saved_prefix };
+    }
+
+    /// Run the block with a view of this RustyMem using a different key prefix, over the same connections.
+    /// do rm.with_key_prefix("tenant2:") |rm2| { rm2.get_str("key1") }
+    pub fn with_key_prefix<T>(&mut self, key_prefix: &str, blk: &fn(&mut RustyMem) -> T) -> T {
+        let mut view = self.prefixed(key_prefix);
+        return blk(view.mem());
+    }
+
+}
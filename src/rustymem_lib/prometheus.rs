@@ -0,0 +1,316 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::str;
+use std::task;
+use std::cell::Cell;
+use std::result::Result;
+use std::comm::oneshot;
+use std::rt::io::{Reader, Writer, Listener, Acceptor};
+use std::rt::io::net::tcp::{TcpListener, TcpStream};
+use std::rt::io::io_error;
+use extra::treemap::TreeMap;
+
+
+use common::netutil;
+
+
+use super::super::RustyMem;
+use super::super::MemcachedStat;
+use super::pool::{MemPool, PoolStats};
+use super::metrics::{MetricsSnapshot, LATENCY_BUCKETS_US};
+
+
+
+//
+// Prometheus text exposition
+//
+// The client metrics, the state of a MemPool and the stats of the servers are rendered in the Prometheus text
+// format, version 0.0.4:
+//
+//   rustymem_cache_*           L1 cache and memcached hits and misses of the client
+//   rustymem_op_*              calls per server and command, from the MetricsSnapshot of MemParams.metrics
+//   rustymem_pool_*            clients of the MemPool
+//   memcached_*                the numeric stats of each server, labeled by the server address, untyped
+//
+// The server stats are read when rendering, one stats call per server.  serve_prometheus() serves the text
+// of a render function over HTTP at /metrics, one request at a time, for a scraper on the local host.
+//
+
+static CONTENT_TYPE: &'static str   = "text/plain; version=0.0.4";
+static MAX_REQUEST_SIZE: uint       = 8192;
+
+
+impl RustyMem {
+
+    /// Client metrics and server stats in the Prometheus text format
+    pub fn prometheus_text(&mut self) -> ~str {
+        let mut expo = Exposition::new();
+        self.write_client_metrics(&mut expo);
+        self.write_server_stats(&mut expo);
+        return expo.text;
+    }
+
+    fn write_client_metrics(&self, expo: &mut Exposition) {
+        let cache = self.cache_stats();
+        expo.family("rustymem_cache_requests_total", "Reads of the client by cache level and result", "counter");
+        expo.sample("rustymem_cache_requests_total", [("level", "l1"), ("result", "hit")], cache.l1_hits.to_str());
+        expo.sample("rustymem_cache_requests_total", [("level", "l1"), ("result", "miss")], cache.l1_misses.to_str());
+        expo.sample("rustymem_cache_requests_total", [("level", "memcached"), ("result", "hit")], cache.l2_hits.to_str());
+        expo.sample("rustymem_cache_requests_total", [("level", "memcached"), ("result", "miss")], cache.l2_misses.to_str());
+        match self.metrics_snapshot() {
+            Some(snapshot) => write_op_metrics(expo, &snapshot),
+            None => ()
+        }
+    }
+
+    fn write_server_stats(&mut self, expo: &mut Exposition) {
+        let addrs = self.server_addrs();
+        let server_stats = self.stats();
+        let mut families = TreeMap::<~str, ~[(~str, ~str)]>::new();
+        expo.family("memcached_up", "Whether the server returned its stats", "gauge");
+        for (addr, stats) in addrs.iter().zip(server_stats.iter()) {
+            expo.sample("memcached_up", [("server", addr.as_slice())], (if stats.len() > 0 { ~"1" } else { ~"0" }));
+            for stat in stats.iter() {
+                let (name, value) = match numeric_stat(stat) {
+                    Some(sample) => sample,
+                    None => continue
+                };
+                if !families.contains_key(&name) {
+                    families.insert(name.clone(), ~[]);
+                }
+                families.find_mut(&name).unwrap().push((addr.clone(), value));
+            }
+        }
+        let mut versions : ~[(~str, ~str)] = ~[];
+        for (addr, stats) in addrs.iter().zip(server_stats.iter()) {
+            match stats.iter().find(|stat| stat.name.as_slice() == "version") {
+                Some(stat) => versions.push((addr.clone(), stat.value.clone())),
+                None => ()
+            }
+        }
+        if versions.len() > 0 {
+            expo.family("memcached_version_info", "Version of the server", "gauge");
+            for &(ref addr, ref version) in versions.iter() {
+                expo.sample("memcached_version_info", [("server", addr.as_slice()), ("version", version.as_slice())], ~"1");
+            }
+        }
+        for (name, samples) in families.iter() {
+            expo.family(*name, fmt!("Memcached stat %s", name.slice_from("memcached_".len())), "untyped");
+            for &(ref addr, ref value) in samples.iter() {
+                expo.sample(*name, [("server", addr.as_slice())], value.clone());
+            }
+        }
+    }
+
+}
+
+
+impl MemPool {
+
+    /// State of the pool, the metrics of its clients and the server stats in the Prometheus text format.
+    /// The server stats are read with a client checked out, and left out if none is available.
+    pub fn prometheus_text(&self) -> ~str {
+        let mut expo = Exposition::new();
+        write_pool_stats(&mut expo, &self.stats());
+        match self.checkout() {
+            Ok(mut pooled) => {
+                let rm = pooled.mem();
+                match rm.metrics_snapshot() {
+                    Some(snapshot) => write_op_metrics(&mut expo, &snapshot),
+                    None => ()
+                }
+                rm.write_server_stats(&mut expo);
+            },
+            Err(status) => debug!( fmt!("no client for the server stats, %?", status) )
+        }
+        return expo.text;
+    }
+
+}
+
+
+/// Serve the text of the render function at http://listen_addr/metrics, in a task of its own running for
+/// the life of the process.  Return the error if it can't listen at the address.
+/// let pool2 = pool.clone();
+/// rustymem::serve_prometheus("127.0.0.1:9150", || pool2.prometheus_text());
+pub fn serve_prometheus(listen_addr: &str, render: ~fn() -> ~str) -> Result<(), ~str> {
+    let sock_addr = netutil::HostAddr::with_host_port(listen_addr, 9150).get_sock_addr();
+    let (port, chan) = oneshot::<Result<(), ~str>>();
+    let chan = Cell::new(chan);
+    let render = Cell::new(render);
+    do task::spawn {
+        let mut failed = false;
+        let acceptor = do io_error::cond.trap(|_| failed = true).inside {
+            match TcpListener::bind(sock_addr) {
+                Some(listener) => listener.listen(),
+                None => None
+            }
+        };
+        let acceptor = if failed { None } else { acceptor };
+        match acceptor {
+            Some(mut acceptor) => {
+                chan.take().send(Ok(()));
+                let render = render.take();
+                loop {
+                    let stream = do io_error::cond.trap(|_| ()).inside { acceptor.accept() };
+                    match stream {
+                        Some(stream) => serve_request(stream, &render),
+                        None => ()
+                    }
+                }
+            },
+            None => chan.take().send(Err(fmt!("can't listen at %s", sock_addr.to_str())))
+        }
+    }
+    return port.recv();
+}
+
+
+// Answer one HTTP request on the stream, ignoring the I/O errors of a scraper going away.
+fn serve_request(stream: TcpStream, render: &~fn() -> ~str) {
+    let mut stream = stream;
+    do io_error::cond.trap(|_| ()).inside {
+        let mut request : ~[u8] = ~[];
+        let mut buf = [0u8, ..1024];
+        while request.len() < MAX_REQUEST_SIZE && !ends_header(request) {
+            match stream.read(buf.mut_slice(0, 1024)) {
+                Some(n) => request.push_all(buf.slice(0, n)),
+                None => break
+            }
+        }
+        let line_end = match request.iter().position(|b| *b == '\n' as u8) {
+            Some(i) => i,
+            None => request.len()
+        };
+        let request_line = if str::is_utf8(request.slice(0, line_end)) { str::from_utf8(request.slice(0, line_end)) } else { ~"" };
+        let path = match request_line.split_iter(' ').nth(1) {
+            Some(path) => path,
+            None => ""
+        };
+        let (status, body) = match path {
+            "/metrics" | "/" => ("200 OK", (*render)()),
+            _ => ("404 Not Found", ~"Not found, the metrics are at /metrics\n")
+        };
+        let header = fmt!("HTTP/1.0 %s\r\nContent-Type: %s\r\nContent-Length: %u\r\nConnection: close\r\n\r\n", status, CONTENT_TYPE, body.len());
+        stream.write(header.as_bytes());
+        stream.write(body.as_bytes());
+    }
+}
+
+fn ends_header(request: &[u8]) -> bool {
+    let len = request.len();
+    return len >= 4 && request.slice(len - 4, len) == "\r\n\r\n".as_bytes();
+}
+
+
+fn write_op_metrics(expo: &mut Exposition, snapshot: &MetricsSnapshot) {
+    expo.family("rustymem_op_total", "Calls to the servers by command and result status", "counter");
+    for op in snapshot.ops.iter() {
+        for &(status, n) in op.statuses.iter() {
+            let status_str = fmt!("%?", status);
+            expo.sample("rustymem_op_total", [("server", op.server_addr.as_slice()), ("command", op.command.as_slice()), ("status", status_str.as_slice())], n.to_str());
+        }
+    }
+    expo.family("rustymem_op_duration_seconds", "Latency of the calls to the servers", "histogram");
+    for op in snapshot.ops.iter() {
+        let labels = [("server", op.server_addr.as_slice()), ("command", op.command.as_slice())];
+        let mut cumulative = 0u64;
+        for (i, n) in op.buckets.iter().enumerate() {
+            cumulative += *n;
+            let le = if i < LATENCY_BUCKETS_US.len() { (LATENCY_BUCKETS_US[i] as f64 / 1000000.0).to_str() } else { ~"+Inf" };
+            expo.sample("rustymem_op_duration_seconds_bucket", [labels[0], labels[1], ("le", le.as_slice())], cumulative.to_str());
+        }
+        expo.sample("rustymem_op_duration_seconds_sum", labels, (op.sum_us as f64 / 1000000.0).to_str());
+        expo.sample("rustymem_op_duration_seconds_count", labels, op.count.to_str());
+    }
+    expo.family("rustymem_op_sent_bytes_total", "Bytes of the keys and data sent to the servers", "counter");
+    for op in snapshot.ops.iter() {
+        expo.sample("rustymem_op_sent_bytes_total", [("server", op.server_addr.as_slice()), ("command", op.command.as_slice())], op.bytes_sent.to_str());
+    }
+    expo.family("rustymem_op_received_bytes_total", "Bytes of the keys and data received from the servers", "counter");
+    for op in snapshot.ops.iter() {
+        expo.sample("rustymem_op_received_bytes_total", [("server", op.server_addr.as_slice()), ("command", op.command.as_slice())], op.bytes_received.to_str());
+    }
+}
+
+fn write_pool_stats(expo: &mut Exposition, stats: &PoolStats) {
+    expo.family("rustymem_pool_idle_clients", "Clients idle in the pool", "gauge");
+    expo.sample("rustymem_pool_idle_clients", [], stats.idle.to_str());
+    expo.family("rustymem_pool_in_use_clients", "Clients checked out of the pool", "gauge");
+    expo.sample("rustymem_pool_in_use_clients", [], stats.in_use.to_str());
+    expo.family("rustymem_pool_max_clients", "Max clients of the pool", "gauge");
+    expo.sample("rustymem_pool_max_clients", [], stats.max_size.to_str());
+    expo.family("rustymem_pool_waits_total", "Checkouts which had to wait for a client", "counter");
+    expo.sample("rustymem_pool_waits_total", [], stats.waits.to_str());
+    expo.family("rustymem_pool_timeouts_total", "Checkouts failed after waiting", "counter");
+    expo.sample("rustymem_pool_timeouts_total", [], stats.timeouts.to_str());
+    expo.family("rustymem_pool_evicted_total", "Clients closed for a broken connection", "counter");
+    expo.sample("rustymem_pool_evicted_total", [], stats.evicted.to_str());
+}
+
+// Metric name and value of a numeric stat, None for the others.  "rusage_user" of old servers is "sec:usec".
+fn numeric_stat(stat: &MemcachedStat) -> Option<(~str, ~str)> {
+    let value = stat.value.replace(":", ".");
+    if from_str::<f64>(value).is_none() {
+        return None;
+    }
+    return Some((fmt!("memcached_%s", metric_name(stat.name)), value));
+}
+
+// Name with the characters invalid in a metric name replaced by '_'
+fn metric_name(name: &str) -> ~str {
+    return name.chars().map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' }).collect::<~str>();
+}
+
+fn escape_label_value(value: &str) -> ~str {
+    return value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n");
+}
+
+
+// Text of the exposition being rendered
+struct Exposition {
+    text:   ~str,
+}
+
+impl Exposition {
+
+    fn new() -> Exposition {
+        return Exposition { text: ~"" };
+    }
+
+    fn family(&mut self, name: &str, help: &str, kind: &str) {
+        self.text.push_str(fmt!("# HELP %s %s\n", name, help));
+        self.text.push_str(fmt!("# TYPE %s %s\n", name, kind));
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: ~str) {
+        self.text.push_str(name);
+        if labels.len() > 0 {
+            let pairs = labels.iter().map(|&(label, label_value)| fmt!("%s=\"%s\"", label, escape_label_value(label_value))).collect::<~[~str]>();
+            self.text.push_char('{');
+            self.text.push_str(pairs.connect(","));
+            self.text.push_char('}');
+        }
+        self.text.push_char(' ');
+        self.text.push_str(value);
+        self.text.push_char('\n');
+    }
+
+}
+
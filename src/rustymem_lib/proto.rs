@@ -22,9 +22,24 @@ use super::super::MemStatus;
 use super::super::MemResult;
 use super::super::MemData;
 use super::super::MemcachedStat;
+use super::super::StoreOp;
 
 
 
+/// Command of ProtoConnection.p_pipeline()
+#[deriving(Clone)]
+pub enum PipelineCmd {
+    /// op, key, data, cas, flags, exptime
+    PIPE_STORE(StoreOp, ~str, ~[u8], u64, u32, uint),
+    /// key, amount, init_value, exptime
+    PIPE_INCR(~str, u64, u64, uint),
+    PIPE_DECR(~str, u64, u64, uint),
+    /// key, exptime
+    PIPE_TOUCH(~str, uint),
+    PIPE_DELETE(~str),
+}
+
+
 /// Low level memcached protocol API
 pub trait ProtoConnection {
 
@@ -65,6 +80,9 @@ pub trait ProtoConnection {
     // Delete command
     fn p_delete(&mut self, key: &str, noreply: bool) -> MemStatus;
 
+    // Delete the data only if it has not been updated since the last fetch, checking with the cas_unique value from last fetch.
+    fn p_delete_cas(&mut self, key: &str, cas_unique: u64, noreply: bool) -> MemStatus;
+
 
     //// Retrieval command
 
@@ -75,6 +93,14 @@ pub trait ProtoConnection {
     fn p_gets(&mut self, keys: &[&str]) -> ~[MemData];
 
 
+    //// Pipelining
+
+    // Run the commands pipelined, writing all the requests before reading the responses in order.
+    // Return one result per command: the cas of a store (0 with the ASCII protocol), the new value of an
+    // incr/decr, 0 for touch and delete.
+    fn p_pipeline(&mut self, cmds: &[PipelineCmd]) -> ~[MemResult<u64>];
+
+
     //// Other commands
 
     // Get the version string of the server
@@ -89,6 +115,13 @@ pub trait ProtoConnection {
     // Return all server statistics
     fn p_stats(&mut self) -> ~[MemcachedStat];
 
+    // Return the server statistics of the group, e.g. "slabs", "items", "sizes", "settings", "conns".
+    // An empty group is the general-purpose statistics of p_stats().  Use p_stats_reset() to reset the statistics.
+    fn p_stats_group(&mut self, group: &str) -> ~[MemcachedStat];
+
+    // Reset the server statistics.
+    fn p_stats_reset(&mut self) -> MemStatus;
+
     // Server closes the connection from client.
     fn p_quit(&mut self) -> MemStatus;
 
@@ -96,6 +129,13 @@ pub trait ProtoConnection {
     // Server config
     fn p_get_server_addr(&self) -> ~str;
 
+    // Whether the connection is up.  A connection is down when it fails to connect or has an I/O error,
+    // and its commands return Network_Error or no data.
+    fn p_is_connected(&self) -> bool;
+
+    // Drop the current connection and connect to the server again.  Return whether it's connected.
+    fn p_reconnect(&mut self) -> bool;
+
 }
 
 
@@ -0,0 +1,126 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::result::Result;
+
+
+use common::timeutil;
+
+
+use super::super::RustyMem;
+use super::super::MemStatus;
+use super::super::Success;
+use super::super::Invalid_Arguments;
+
+
+
+//
+// Rate limiting
+//
+// Each window of window_secs seconds has its own counter, "__rl:name:window_index", bumped by incr on
+// every check, including the denied ones.  The counter is created by incr with its expiry, so it goes
+// away on its own after the window.
+//
+// The fixed window limiter counts the requests in the current window only, allowing bursts of up to twice
+// the limit around a window boundary.  The sliding window limiter smooths that out by weighing in the
+// previous window's count by how much of it still overlaps the sliding window.
+//
+
+
+/// Result of a rate limit check
+pub struct RateLimit {
+    /// Whether the request is within the limit
+    allowed:    bool,
+    /// Requests left in the window
+    remaining:  u64,
+    /// Unix time in seconds when the current window ends
+    reset_at:   u64,
+}
+
+
+impl RustyMem {
+
+    /// Count a request against the limit of requests per window of window_secs seconds, with fixed windows.
+    /// Return Invalid_Arguments if window_secs is 0.
+    /// let rl = rm.rate_limit_fixed("api:user1", 100, 60).unwrap();
+    pub fn rate_limit_fixed(&mut self, name: &str, limit: u64, window_secs: uint) -> Result<RateLimit, MemStatus> {
+        if window_secs == 0 {
+            return Err(Invalid_Arguments);
+        }
+        let now = timeutil::now_secs();
+        let window = window_secs as u64;
+        let index = now / window;
+        let reset_at = (index + 1) * window;
+
+        let count = match self.rate_count(name, index, (reset_at - now) as uint + 1) {
+            Ok(count)   => count,
+            Err(status) => return Err(status)
+        };
+        return Ok(RateLimit {
+                allowed:    count <= limit,
+                remaining:  if count < limit { limit - count } else { 0 },
+                reset_at:   reset_at,
+            });
+    }
+
+    /// Count a request against the limit of requests in the last window_secs seconds, with a sliding window
+    /// estimated from the counts of the current and previous fixed windows.  Return Invalid_Arguments if window_secs is 0.
+    /// let rl = rm.rate_limit_sliding("api:user1", 100, 60).unwrap();
+    pub fn rate_limit_sliding(&mut self, name: &str, limit: u64, window_secs: uint) -> Result<RateLimit, MemStatus> {
+        if window_secs == 0 {
+            return Err(Invalid_Arguments);
+        }
+        let now = timeutil::now_secs();
+        let window = window_secs as u64;
+        let index = now / window;
+        let reset_at = (index + 1) * window;
+
+        let prev_count = match self.get_as::<u64>(rate_key(name, index - 1)) {
+            Some(count) => count,
+            None        => 0
+        };
+        // The current window's counter is read as the previous one in the next window.
+        let count = match self.rate_count(name, index, (reset_at - now + window) as uint + 1) {
+            Ok(count)   => count,
+            Err(status) => return Err(status)
+        };
+        let overlap = 1.0 - ((now - index * window) as f64 / window as f64);
+        let estimate = (prev_count as f64 * overlap) as u64 + count;
+        return Ok(RateLimit {
+                allowed:    estimate <= limit,
+                remaining:  if estimate < limit { limit - estimate } else { 0 },
+                reset_at:   reset_at,
+            });
+    }
+
+    // Bump the counter of the window, creating it with the exptime in seconds.
+    fn rate_count(&mut self, name: &str, index: u64, exptime: uint) -> Result<u64, MemStatus> {
+        let result = self.incr(rate_key(name, index), 1, 1, exptime);
+        match result.status {
+            Success => Ok(result.value),
+            status  => Err(status)
+        }
+    }
+
+}
+
+
+// Key of the counter of the window
+fn rate_key(name: &str, index: u64) -> ~str {
+    return format!("__rl:{}:{}", name, index);
+}
+
@@ -0,0 +1,155 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::hashmap::HashSet;
+use extra::json::Json;
+
+
+use super::super::RustyMem;
+use super::super::MemData;
+use super::super::MemResult;
+use super::super::OP_SET;
+use super::flags::VT_NEGATIVE;
+use super::codec::ValueCodec;
+
+
+
+//
+// Read-through caching
+//
+// The value is read from the cache, and on a miss computed by the loader and filled into the cache.
+// A loader finding nothing can have its negative result cached too, with its own exptime, so repeated
+// lookups of a missing entry don't all go to the loader.  Pass neg_exptime 0 to not cache negative results.
+//
+
+
+impl RustyMem {
+
+    /// Get the value at key decoded by the codec.  On a miss, compute it with the loader and set it with exptime.
+    /// A None from the loader is cached as a negative result for neg_exptime seconds, if neg_exptime is not 0.
+    /// Data at the key written by another codec is recomputed and overwritten.
+    /// let user : Option<User> = rm.get_or_compute_value("user1", 600, 30, &JsonCodec, || db.find_user(1));
+    pub fn get_or_compute_value<T, C: ValueCodec<T>>(&mut self, key: &str, exptime: uint, neg_exptime: uint, codec: &C, loader: &fn() -> Option<T>) -> Option<T> {
+        return self.read_through(key, neg_exptime,
+                                 |md| md.try_value(codec as &ValueCodec<T>).ok(),
+                                 loader,
+                                 |rm, value| { rm.set_value(key, exptime, codec, value); });
+    }
+
+    /// Get the str at key.  On a miss, compute it with the loader and set it with exptime.
+    /// A None from the loader is cached as a negative result for neg_exptime seconds, if neg_exptime is not 0.
+    pub fn get_or_compute_str(&mut self, key: &str, exptime: uint, neg_exptime: uint, loader: &fn() -> Option<~str>) -> Option<~str> {
+        return self.read_through(key, neg_exptime,
+                                 |md| md.try_str().ok(),
+                                 loader,
+                                 |rm, value| { rm.set_str(key, exptime, *value); });
+    }
+
+    /// Get the Json at key.  On a miss, compute it with the loader and set it with exptime.
+    /// A None from the loader is cached as a negative result for neg_exptime seconds, if neg_exptime is not 0.
+    pub fn get_or_compute_json(&mut self, key: &str, exptime: uint, neg_exptime: uint, loader: &fn() -> Option<Json>) -> Option<Json> {
+        return self.read_through(key, neg_exptime,
+                                 |md| md.as_json().ok(),
+                                 loader,
+                                 |rm, value| { rm.set_json(key, exptime, value); });
+    }
+
+    /// Get the values of the keys decoded by the codec, with one bulk get.  The loader is called once with
+    /// the missing keys, returning the found (key, value) pairs, which are set with exptime.  Missing keys not
+    /// returned by the loader are cached as negative results for neg_exptime seconds, if neg_exptime is not 0.
+    /// Return the (key, value) pairs of the cached and loaded values.
+    pub fn get_or_compute_bulk_value<T, C: ValueCodec<T>>(&mut self, keys: &[&str], exptime: uint, neg_exptime: uint, codec: &C,
+                                                          loader: &fn(&[~str]) -> ~[(~str, T)]) -> ~[(~str, T)] {
+        let mut result : ~[(~str, T)] = ~[];
+        let mut cached_keys = HashSet::<~str>::new();
+        for md in self.get_bulk_data(keys).move_iter() {
+            if md.value_type == VT_NEGATIVE {
+                cached_keys.insert(md.key.clone());
+            } else {
+                match md.try_value(codec as &ValueCodec<T>) {
+                    Ok(value) => {
+                        cached_keys.insert(md.key.clone());
+                        result.push((md.key.clone(), value));
+                    },
+                    Err(_) => ()
+                }
+            }
+        }
+
+        let mut missing_keys : ~[~str] = ~[];
+        for key in keys.iter() {
+            let key = key.to_owned();
+            if !cached_keys.contains(&key) && !missing_keys.contains(&key) {
+                missing_keys.push(key);
+            }
+        }
+        if missing_keys.len() == 0 {
+            return result;
+        }
+
+        let mut loaded_keys = HashSet::<~str>::new();
+        for entry in loader(missing_keys).move_iter() {
+            let (key, value) = entry;
+            self.set_value(key, exptime, codec, &value);
+            loaded_keys.insert(key.clone());
+            result.push((key, value));
+        }
+        if neg_exptime > 0 {
+            for key in missing_keys.iter() {
+                if !loaded_keys.contains(key) {
+                    self.set_negative(*key, neg_exptime);
+                }
+            }
+        }
+        return result;
+    }
+
+    /// Cache the absence of the value at key, with the expiration exptime in seconds.
+    /// The get_or_compute calls return None for it without calling their loader.
+    pub fn set_negative(&mut self, key: &str, exptime: uint) -> MemResult<u64> {
+        return self.store_cmd(OP_SET, key, [], 0, VT_NEGATIVE, exptime);
+    }
+
+    // Read the key and decode it, or on a miss run the loader and store its result.
+    fn read_through<T>(&mut self, key: &str, neg_exptime: uint,
+                       decode: &fn(&MemData) -> Option<T>,
+                       loader: &fn() -> Option<T>,
+                       store: &fn(&mut RustyMem, &T)) -> Option<T> {
+        match self.get_data(key) {
+            Some(md) => {
+                if md.value_type == VT_NEGATIVE {
+                    return None;
+                }
+                match decode(&md) {
+                    Some(value) => return Some(value),
+                    None => debug!( fmt!("recompute %?, cached data can't be decoded", key) )
+                }
+            },
+            None => ()
+        }
+
+        let value = loader();
+        match value {
+            Some(ref v) => store(self, v),
+            None if neg_exptime > 0 => { self.set_negative(key, neg_exptime); },
+            None => ()
+        }
+        return value;
+    }
+
+}
+
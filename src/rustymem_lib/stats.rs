@@ -0,0 +1,502 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::hashmap::HashMap;
+use extra::treemap::TreeMap;
+
+
+use common::timeutil;
+
+
+use super::super::RustyMem;
+use super::super::MemcachedStat;
+use super::super::MemStatus;
+
+
+
+//
+// Typed server statistics
+//
+// The general-purpose stats of the "stats" command are parsed into typed fields, missing ones left at 0.
+// Stats not known here, e.g. from newer servers, are kept as strings in ServerStats.other.
+// The per-slab stats of "stats slabs", "<slab>:<field>", and of "stats items", "items:<slab>:<field>",
+// are parsed into one struct per slab class the same way.
+//
+
+
+/// Stats of a server, from the "stats" command
+#[deriving(Clone)]
+pub struct ServerStats {
+    server_addr:        ~str,
+    /// Whether the server returned its stats.  Everything else is 0 if not.
+    available:          bool,
+    pid:                u64,
+    uptime_secs:        u64,
+    /// Unix time of the server
+    time:               u64,
+    version:            ~str,
+    pointer_size:       u64,
+    /// Seconds of user CPU time of the server process
+    rusage_user_secs:   f64,
+    /// Seconds of system CPU time of the server process
+    rusage_system_secs: f64,
+    curr_connections:   u64,
+    total_connections:  u64,
+    connection_structures: u64,
+    cmd_get:            u64,
+    cmd_set:            u64,
+    cmd_flush:          u64,
+    cmd_touch:          u64,
+    get_hits:           u64,
+    get_misses:         u64,
+    delete_hits:        u64,
+    delete_misses:      u64,
+    incr_hits:          u64,
+    incr_misses:        u64,
+    decr_hits:          u64,
+    decr_misses:        u64,
+    cas_hits:           u64,
+    cas_misses:         u64,
+    cas_badval:         u64,
+    touch_hits:         u64,
+    touch_misses:       u64,
+    auth_cmds:          u64,
+    auth_errors:        u64,
+    bytes_read:         u64,
+    bytes_written:      u64,
+    /// Memory limit of the items in bytes
+    limit_maxbytes:     u64,
+    threads:            u64,
+    /// Bytes used by the items
+    bytes:              u64,
+    curr_items:         u64,
+    total_items:        u64,
+    evictions:          u64,
+    reclaimed:          u64,
+    /// Stats not parsed into the fields above, by name
+    other:              HashMap<~str, ~str>,
+}
+
+impl ServerStats {
+
+    /// Parse the stats returned by ProtoConnection.p_stats().  An empty list is an unavailable server.
+    pub fn parse(server_addr: &str, stats: &[MemcachedStat]) -> ServerStats {
+        let mut ss = ServerStats::new(server_addr);
+        ss.available = stats.len() > 0;
+        for stat in stats.iter() {
+            let value = stat.value.as_slice();
+            match stat.name.as_slice() {
+                "pid"                   => ss.pid = parse_u64(value),
+                "uptime"                => ss.uptime_secs = parse_u64(value),
+                "time"                  => ss.time = parse_u64(value),
+                "version"               => ss.version = value.to_owned(),
+                "pointer_size"          => ss.pointer_size = parse_u64(value),
+                "rusage_user"           => ss.rusage_user_secs = parse_secs(value),
+                "rusage_system"         => ss.rusage_system_secs = parse_secs(value),
+                "curr_connections"      => ss.curr_connections = parse_u64(value),
+                "total_connections"     => ss.total_connections = parse_u64(value),
+                "connection_structures" => ss.connection_structures = parse_u64(value),
+                "cmd_get"               => ss.cmd_get = parse_u64(value),
+                "cmd_set"               => ss.cmd_set = parse_u64(value),
+                "cmd_flush"             => ss.cmd_flush = parse_u64(value),
+                "cmd_touch"             => ss.cmd_touch = parse_u64(value),
+                "get_hits"              => ss.get_hits = parse_u64(value),
+                "get_misses"            => ss.get_misses = parse_u64(value),
+                "delete_hits"           => ss.delete_hits = parse_u64(value),
+                "delete_misses"         => ss.delete_misses = parse_u64(value),
+                "incr_hits"             => ss.incr_hits = parse_u64(value),
+                "incr_misses"           => ss.incr_misses = parse_u64(value),
+                "decr_hits"             => ss.decr_hits = parse_u64(value),
+                "decr_misses"           => ss.decr_misses = parse_u64(value),
+                "cas_hits"              => ss.cas_hits = parse_u64(value),
+                "cas_misses"            => ss.cas_misses = parse_u64(value),
+                "cas_badval"            => ss.cas_badval = parse_u64(value),
+                "touch_hits"            => ss.touch_hits = parse_u64(value),
+                "touch_misses"          => ss.touch_misses = parse_u64(value),
+                "auth_cmds"             => ss.auth_cmds = parse_u64(value),
+                "auth_errors"           => ss.auth_errors = parse_u64(value),
+                "bytes_read"            => ss.bytes_read = parse_u64(value),
+                "bytes_written"         => ss.bytes_written = parse_u64(value),
+                "limit_maxbytes"        => ss.limit_maxbytes = parse_u64(value),
+                "threads"               => ss.threads = parse_u64(value),
+                "bytes"                 => ss.bytes = parse_u64(value),
+                "curr_items"            => ss.curr_items = parse_u64(value),
+                "total_items"           => ss.total_items = parse_u64(value),
+                "evictions"             => ss.evictions = parse_u64(value),
+                "reclaimed"             => ss.reclaimed = parse_u64(value),
+                name                    => { ss.other.insert(name.to_owned(), value.to_owned()); }
+            }
+        }
+        return ss;
+    }
+
+    fn new(server_addr: &str) -> ServerStats {
+        return ServerStats {
+            server_addr:        server_addr.to_owned(),
+            available:          false,
+            pid:                0,
+            uptime_secs:        0,
+            time:               0,
+            version:            ~"",
+            pointer_size:       0,
+            rusage_user_secs:   0.0,
+            rusage_system_secs: 0.0,
+            curr_connections:   0,
+            total_connections:  0,
+            connection_structures: 0,
+            cmd_get:            0,
+            cmd_set:            0,
+            cmd_flush:          0,
+            cmd_touch:          0,
+            get_hits:           0,
+            get_misses:         0,
+            delete_hits:        0,
+            delete_misses:      0,
+            incr_hits:          0,
+            incr_misses:        0,
+            decr_hits:          0,
+            decr_misses:        0,
+            cas_hits:           0,
+            cas_misses:         0,
+            cas_badval:         0,
+            touch_hits:         0,
+            touch_misses:       0,
+            auth_cmds:          0,
+            auth_errors:        0,
+            bytes_read:         0,
+            bytes_written:      0,
+            limit_maxbytes:     0,
+            threads:            0,
+            bytes:              0,
+            curr_items:         0,
+            total_items:        0,
+            evictions:          0,
+            reclaimed:          0,
+            other:              HashMap::new(),
+        };
+    }
+
+    /// Fraction of the gets finding the key.  0 if no get yet.
+    pub fn hit_ratio(&self) -> f64 {
+        return ratio(self.get_hits, self.get_hits + self.get_misses);
+    }
+
+    /// Fraction of the memory limit used by the items.
+    pub fn memory_utilization(&self) -> f64 {
+        return ratio(self.bytes, self.limit_maxbytes);
+    }
+
+}
+
+
+/// Stats of all the servers of a RustyMem, taken at a point in time
+#[deriving(Clone)]
+pub struct ClusterStats {
+    /// Stats of the servers, in the order of the connections
+    servers:    ~[ServerStats],
+    /// Monotonic time in milliseconds when the stats were taken
+    taken_ms:   u64,
+}
+
+impl ClusterStats {
+
+    pub fn total_hits(&self) -> u64 {
+        return self.sum(|ss| ss.get_hits);
+    }
+
+    pub fn total_misses(&self) -> u64 {
+        return self.sum(|ss| ss.get_misses);
+    }
+
+    /// Fraction of the gets finding the key, over all the servers.
+    pub fn hit_ratio(&self) -> f64 {
+        let hits = self.total_hits();
+        return ratio(hits, hits + self.total_misses());
+    }
+
+    pub fn total_items(&self) -> u64 {
+        return self.sum(|ss| ss.curr_items);
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        return self.sum(|ss| ss.bytes);
+    }
+
+    pub fn total_evictions(&self) -> u64 {
+        return self.sum(|ss| ss.evictions);
+    }
+
+    /// Evictions per second over all the servers, between the earlier snapshot and this one.  Servers are
+    /// matched by address, and a server restarted in between counts its evictions since the restart.
+    pub fn evictions_per_sec(&self, earlier: &ClusterStats) -> f64 {
+        if self.taken_ms <= earlier.taken_ms {
+            return 0.0;
+        }
+        let mut evictions = 0u64;
+        for ss in self.servers.iter() {
+            let before = earlier.servers.iter().find(|e| e.server_addr == ss.server_addr);
+            evictions += match before {
+                Some(e) if e.evictions <= ss.evictions && e.uptime_secs <= ss.uptime_secs => ss.evictions - e.evictions,
+                _ => ss.evictions
+            };
+        }
+        return evictions as f64 * 1000.0 / (self.taken_ms - earlier.taken_ms) as f64;
+    }
+
+    /// Fraction of the memory limit used by the items, per server address.
+    pub fn memory_utilization(&self) -> ~[(~str, f64)] {
+        return self.servers.iter().map(|ss| (ss.server_addr.clone(), ss.memory_utilization())).collect::<~[(~str, f64)]>();
+    }
+
+    fn sum(&self, field: &fn(&ServerStats) -> u64) -> u64 {
+        return self.servers.iter().fold(0u64, |total, ss| total + field(ss));
+    }
+
+}
+
+
+/// Memory stats of a slab class, from the "stats slabs" command
+#[deriving(Clone)]
+pub struct SlabStats {
+    slab_class:         uint,
+    /// Bytes of each chunk, the max size of an item in the class
+    chunk_size:         u64,
+    chunks_per_page:    u64,
+    total_pages:        u64,
+    total_chunks:       u64,
+    used_chunks:        u64,
+    free_chunks:        u64,
+    free_chunks_end:    u64,
+    /// Bytes requested by the items in the chunks
+    mem_requested:      u64,
+    get_hits:           u64,
+    cmd_set:            u64,
+    delete_hits:        u64,
+    /// Stats not parsed into the fields above, by field name
+    other:              HashMap<~str, ~str>,
+}
+
+impl SlabStats {
+
+    /// Parse the stats of "stats slabs" into the slab classes, in the order of the class.  The totals
+    /// over the classes, e.g. total_malloced, are not per slab and are skipped.
+    pub fn parse_all(stats: &[MemcachedStat]) -> ~[SlabStats] {
+        return group_by_slab(stats, "").iter().map(|(slab_class, fields)| {
+                let mut ss = SlabStats::new(*slab_class);
+                for &(ref field, ref value) in fields.iter() {
+                    let value = value.as_slice();
+                    match field.as_slice() {
+                        "chunk_size"        => ss.chunk_size = parse_u64(value),
+                        "chunks_per_page"   => ss.chunks_per_page = parse_u64(value),
+                        "total_pages"       => ss.total_pages = parse_u64(value),
+                        "total_chunks"      => ss.total_chunks = parse_u64(value),
+                        "used_chunks"       => ss.used_chunks = parse_u64(value),
+                        "free_chunks"       => ss.free_chunks = parse_u64(value),
+                        "free_chunks_end"   => ss.free_chunks_end = parse_u64(value),
+                        "mem_requested"     => ss.mem_requested = parse_u64(value),
+                        "get_hits"          => ss.get_hits = parse_u64(value),
+                        "cmd_set"           => ss.cmd_set = parse_u64(value),
+                        "delete_hits"       => ss.delete_hits = parse_u64(value),
+                        name                => { ss.other.insert(name.to_owned(), value.to_owned()); }
+                    }
+                }
+                ss
+            }).collect::<~[SlabStats]>();
+    }
+
+    fn new(slab_class: uint) -> SlabStats {
+        return SlabStats {
+            slab_class:         slab_class,
+            chunk_size:         0,
+            chunks_per_page:    0,
+            total_pages:        0,
+            total_chunks:       0,
+            used_chunks:        0,
+            free_chunks:        0,
+            free_chunks_end:    0,
+            mem_requested:      0,
+            get_hits:           0,
+            cmd_set:            0,
+            delete_hits:        0,
+            other:              HashMap::new(),
+        };
+    }
+
+    /// Fraction of the memory of the used chunks taken by the items, low when the items are much smaller than the chunks.
+    pub fn fill_ratio(&self) -> f64 {
+        return ratio(self.mem_requested, self.used_chunks * self.chunk_size);
+    }
+
+}
+
+
+/// Item stats of a slab class, from the "stats items" command
+#[deriving(Clone)]
+pub struct SlabItemStats {
+    slab_class:         uint,
+    /// Items in the class
+    number:             u64,
+    /// Seconds since the last access of the oldest item in the LRU
+    age_secs:           u64,
+    evicted:            u64,
+    /// Items evicted with an expiration time set
+    evicted_nonzero:    u64,
+    /// Seconds since the last access of the last evicted item
+    evicted_time_secs:  u64,
+    outofmemory:        u64,
+    tailrepairs:        u64,
+    reclaimed:          u64,
+    expired_unfetched:  u64,
+    evicted_unfetched:  u64,
+    /// Stats not parsed into the fields above, by field name
+    other:              HashMap<~str, ~str>,
+}
+
+impl SlabItemStats {
+
+    /// Parse the stats of "stats items" into the slab classes, in the order of the class.
+    pub fn parse_all(stats: &[MemcachedStat]) -> ~[SlabItemStats] {
+        return group_by_slab(stats, "items:").iter().map(|(slab_class, fields)| {
+                let mut ss = SlabItemStats::new(*slab_class);
+                for &(ref field, ref value) in fields.iter() {
+                    let value = value.as_slice();
+                    match field.as_slice() {
+                        "number"            => ss.number = parse_u64(value),
+                        "age"               => ss.age_secs = parse_u64(value),
+                        "evicted"           => ss.evicted = parse_u64(value),
+                        "evicted_nonzero"   => ss.evicted_nonzero = parse_u64(value),
+                        "evicted_time"      => ss.evicted_time_secs = parse_u64(value),
+                        "outofmemory"       => ss.outofmemory = parse_u64(value),
+                        "tailrepairs"       => ss.tailrepairs = parse_u64(value),
+                        "reclaimed"         => ss.reclaimed = parse_u64(value),
+                        "expired_unfetched" => ss.expired_unfetched = parse_u64(value),
+                        "evicted_unfetched" => ss.evicted_unfetched = parse_u64(value),
+                        name                => { ss.other.insert(name.to_owned(), value.to_owned()); }
+                    }
+                }
+                ss
+            }).collect::<~[SlabItemStats]>();
+    }
+
+    fn new(slab_class: uint) -> SlabItemStats {
+        return SlabItemStats {
+            slab_class:         slab_class,
+            number:             0,
+            age_secs:           0,
+            evicted:            0,
+            evicted_nonzero:    0,
+            evicted_time_secs:  0,
+            outofmemory:        0,
+            tailrepairs:        0,
+            reclaimed:          0,
+            expired_unfetched:  0,
+            evicted_unfetched:  0,
+            other:              HashMap::new(),
+        };
+    }
+
+}
+
+
+impl RustyMem {
+
+    /// Typed stats of all the servers.
+    pub fn cluster_stats(&mut self) -> ClusterStats {
+        let server_addrs = self.server_addrs();
+        let stats = self.stats();
+        return ClusterStats {
+            servers:    range(0, server_addrs.len()).map(|i| ServerStats::parse(server_addrs[i], stats[i])).collect::<~[ServerStats]>(),
+            taken_ms:   timeutil::now_ms(),
+        };
+    }
+
+    /// Stats of the group from all the servers, e.g. "slabs", "items", "sizes", "settings", "conns".
+    pub fn stats_group(&mut self, group: &str) -> ~[~[MemcachedStat]] {
+        let mut result : ~[~[MemcachedStat]] = ~[];
+        for i in range(0, self.get_connection_count()) {
+            result.push(self.run_on(i, |conn| conn.p_stats_group(group)));
+        }
+        return result;
+    }
+
+    /// Slab memory stats of all the servers, in the order of the connections.
+    pub fn slab_stats(&mut self) -> ~[~[SlabStats]] {
+        return self.stats_group("slabs").iter().map(|stats| SlabStats::parse_all(*stats)).collect::<~[~[SlabStats]]>();
+    }
+
+    /// Slab item stats of all the servers, in the order of the connections.
+    pub fn slab_item_stats(&mut self) -> ~[~[SlabItemStats]] {
+        return self.stats_group("items").iter().map(|stats| SlabItemStats::parse_all(*stats)).collect::<~[~[SlabItemStats]]>();
+    }
+
+    /// Reset the stats counters of all the servers.  Return the status of each server, in the order of the connections.
+    pub fn reset_stats(&mut self) -> ~[MemStatus] {
+        let mut result : ~[MemStatus] = ~[];
+        for i in range(0, self.get_connection_count()) {
+            result.push(self.run_on(i, |conn| conn.p_stats_reset()));
+        }
+        return result;
+    }
+
+}
+
+
+// Group the per-slab stats named "<prefix><slab>:<field>" by the slab class, in the order of the class.
+// Stats not in this form are skipped.
+fn group_by_slab(stats: &[MemcachedStat], prefix: &str) -> TreeMap<uint, ~[(~str, ~str)]> {
+    let mut slabs = TreeMap::<uint, ~[(~str, ~str)]>::new();
+    for stat in stats.iter() {
+        if !stat.name.starts_with(prefix) {
+            continue;
+        }
+        let name = stat.name.slice_from(prefix.len());
+        let (slab_class, field) = match name.find(':') {
+            Some(pos) => match from_str::<uint>(name.slice_to(pos)) {
+                Some(slab_class) => (slab_class, name.slice_from(pos + 1).to_owned()),
+                None => continue
+            },
+            None => continue
+        };
+        if !slabs.contains_key(&slab_class) {
+            slabs.insert(slab_class, ~[]);
+        }
+        slabs.find_mut(&slab_class).unwrap().push((field, stat.value.clone()));
+    }
+    return slabs;
+}
+
+fn parse_u64(value: &str) -> u64 {
+    return match from_str::<u64>(value) {
+        Some(n) => n,
+        None => 0
+    };
+}
+
+// CPU time in seconds with the microseconds, "12.345678", or "12:345678" from old servers.
+fn parse_secs(value: &str) -> f64 {
+    let value = value.replace(":", ".");
+    return match from_str::<f64>(value) {
+        Some(secs) => secs,
+        None => 0.0
+    };
+}
+
+fn ratio(part: u64, total: u64) -> f64 {
+    return if total == 0 { 0.0 } else { part as f64 / total as f64 };
+}
+
@@ -0,0 +1,245 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::str;
+use std::vec;
+
+
+use common::ioutil;
+use common::timeutil;
+
+
+use super::super::RustyMem;
+use super::super::MemData;
+use super::super::MemResult;
+use super::super::MemStatus;
+use super::super::Success;
+use super::super::Invalid_Arguments;
+use super::super::OP_SET;
+use super::flags::{ValueType, VT_BYTES, VT_STR, VT_INT, VT_TAGGED};
+
+
+
+//
+// Tag-based invalidation
+//
+// Each tag has a version counter at TAG_KEY_PREFIX + tag.  A tagged entry is stored with the versions of its
+// tags, and is valid as long as none of them has been bumped since.  The caller reads the versions with
+// tag_versions() before computing the data, so an invalidation while it's computed leaves the entry stale
+// instead of storing data computed from the old state under the new version.  Invalidating a
+// tag is one incr, and every entry written under it becomes a miss on its next read.  The tagged data is:
+//
+//   tag count u16, then per tag: tag length u16, tag, version u64, then the data
+//
+// with the tagged bit set in the flags on top of the value type of the data.  Integers are big-endian.
+// A missing counter is created from the wall clock in milliseconds rather than from 0, so a counter evicted
+// and re-created doesn't come back to a version some entries were written under.  The counters are read
+// bypassing the L1 cache, since an invalidation by another client must be seen at once.
+//
+
+static TAG_KEY_PREFIX: &'static str     = "__tag:";
+static MAX_TAGS: uint                   = 0xFFFF;
+
+
+impl RustyMem {
+
+    /// Set data bytes at key, under the tag versions read by tag_versions() before computing the data.
+    /// let versions = rm.tag_versions(["user:1", "post:7"]).unwrap();
+    /// let page = render_home();
+    /// rm.set_tagged_bytes("page:/home", 600, page, versions);
+    pub fn set_tagged_bytes(&mut self, key: &str, exptime: uint, data_bytes: &[u8], tag_versions: &[(~str, u64)]) -> MemResult<u64> {
+        return self.store_tagged(key, data_bytes, VT_BYTES, exptime, tag_versions);
+    }
+
+    /// Set the str at key, under the tag versions read by tag_versions().
+    pub fn set_tagged_str(&mut self, key: &str, exptime: uint, data_str: &str, tag_versions: &[(~str, u64)]) -> MemResult<u64> {
+        return self.store_tagged(key, data_str.as_bytes(), VT_STR, exptime, tag_versions);
+    }
+
+    /// Set the integer at key, under the tag versions read by tag_versions().
+    pub fn set_tagged_int(&mut self, key: &str, exptime: uint, value: u64, tag_versions: &[(~str, u64)]) -> MemResult<u64> {
+        return self.store_tagged(key, value.to_str().as_bytes(), VT_INT, exptime, tag_versions);
+    }
+
+    /// Get the data item at key written by set_tagged.  Return None if not found, or any of its tags has been
+    /// invalidated since it was written.  Data written without tags is returned as is.
+    pub fn get_tagged_data(&mut self, key: &str) -> Option<MemData> {
+        let mut md = match self.get_data(key) {
+            Some(md) => md,
+            None => return None
+        };
+        if md.value_type != VT_TAGGED {
+            return Some(md);
+        }
+        let (tag_versions, data) = match decode_tagged(md.data) {
+            Some(decoded) => decoded,
+            None => return None
+        };
+        let tags = tag_versions.iter().map(|&(ref tag, _)| tag.as_slice()).collect::<~[&str]>();
+        let current_versions = self.read_tag_versions(tags);
+        for (&(_, version), current) in tag_versions.iter().zip(current_versions.iter()) {
+            if *current != Some(version) {
+                debug!( fmt!("tagged entry %s is stale", key) );
+                return None;
+            }
+        }
+        let scheme = &self.params.flag_scheme;
+        md.data = data;
+        md.flags = md.flags & !scheme.tagged_flag;
+        md.value_type = scheme.to_value_type(md.flags);
+        return Some(md);
+    }
+
+    pub fn get_tagged_bytes(&mut self, key: &str) -> Option<~[u8]> {
+        match self.get_tagged_data(key) {
+            Some(md) => md.try_bytes().ok(),
+            None => None
+        }
+    }
+
+    pub fn get_tagged_str(&mut self, key: &str) -> Option<~str> {
+        match self.get_tagged_data(key) {
+            Some(md) => md.try_str().ok(),
+            None => None
+        }
+    }
+
+    pub fn get_tagged_int(&mut self, key: &str) -> Option<u64> {
+        match self.get_tagged_data(key) {
+            Some(md) => md.as_type::<u64>(),
+            None => None
+        }
+    }
+
+    /// Invalidate all the entries written under the tag.
+    pub fn invalidate_tag(&mut self, tag: &str) -> MemStatus {
+        return self.incr(tag_key(tag), 1, new_tag_version(), 0).status;
+    }
+
+    /// Invalidate all the entries written under any of the tags.  Return the first failed status, or Success.
+    pub fn invalidate_tags(&mut self, tags: &[&str]) -> MemStatus {
+        let mut result = Success;
+        for tag in tags.iter() {
+            let status = self.invalidate_tag(*tag);
+            if status != Success && result == Success {
+                result = status;
+            }
+        }
+        return result;
+    }
+
+    /// Current version of the tag, creating its counter if missing.
+    pub fn tag_version(&mut self, tag: &str) -> MemResult<u64> {
+        return self.incr(tag_key(tag), 0, new_tag_version(), 0);
+    }
+
+    /// Current versions of the tags, creating the missing counters, to be read before computing the data
+    /// to set under them.  Err with the status of a counter failing to be created.
+    pub fn tag_versions(&mut self, tags: &[&str]) -> Result<~[(~str, u64)], MemStatus> {
+        if tags.len() > MAX_TAGS || tags.iter().any(|tag| tag.len() > 0xFFFF) {
+            return Err(Invalid_Arguments);
+        }
+        let versions = self.read_tag_versions(tags);
+        let mut tag_versions : ~[(~str, u64)] = ~[];
+        for (tag, version) in tags.iter().zip(versions.iter()) {
+            let version = match *version {
+                Some(v) => v,
+                None => {
+                    let result = self.tag_version(*tag);
+                    if result.status != Success {
+                        return Err(result.status);
+                    }
+                    result.value
+                }
+            };
+            tag_versions.push((tag.to_owned(), version));
+        }
+        return Ok(tag_versions);
+    }
+
+
+    fn store_tagged(&mut self, key: &str, data: &[u8], value_type: ValueType, exptime: uint, tag_versions: &[(~str, u64)]) -> MemResult<u64> {
+        if tag_versions.len() > MAX_TAGS || tag_versions.iter().any(|&(ref tag, _)| tag.len() > 0xFFFF) {
+            return MemResult { status: Invalid_Arguments, value: 0 };
+        }
+        let flags = self.params.flag_scheme.to_flags(value_type) | self.params.flag_scheme.tagged_flag;
+        return self.store_flags_cmd(OP_SET, key, encode_tagged(tag_versions, data), 0, flags, exptime);
+    }
+
+    // Versions of the tags in one bulk get, None for the missing counters.
+    fn read_tag_versions(&mut self, tags: &[&str]) -> ~[Option<u64>] {
+        let keys = tags.iter().map(|tag| tag_key(*tag)).collect::<~[~str]>();
+        let key_refs = keys.iter().map(|k| k.as_slice()).collect::<~[&str]>();
+        let md_list = do self.with_l1_bypass |rm| { rm.get_bulk_data(key_refs) };
+        return keys.iter().map(|k| {
+                match md_list.iter().find(|md| md.key == *k) {
+                    Some(md) => md.as_type::<u64>(),
+                    None => None
+                }
+            }).collect::<~[Option<u64>]>();
+    }
+
+}
+
+
+fn tag_key(tag: &str) -> ~str {
+    return TAG_KEY_PREFIX + tag;
+}
+
+fn new_tag_version() -> u64 {
+    return timeutil::now_ms();
+}
+
+fn encode_tagged(tag_versions: &[(~str, u64)], data: &[u8]) -> ~[u8] {
+    let tags_len = tag_versions.iter().fold(0u, |len, &(ref tag, _)| len + 2 + tag.len() + 8);
+    let mut buf = vec::from_elem(2 + tags_len + data.len(), 0u8);
+    let mut offset = ioutil::pack_u16_be(buf, 0, tag_versions.len() as u16);
+    for &(ref tag, version) in tag_versions.iter() {
+        offset = ioutil::pack_u16_be(buf, offset, tag.len() as u16);
+        offset = ioutil::copy_bytes(buf, offset, tag.as_bytes(), 0, tag.len());
+        offset = ioutil::pack_u64_be(buf, offset, version);
+    }
+    ioutil::copy_bytes(buf, offset, data, 0, data.len());
+    return buf;
+}
+
+// Tag versions and data of the tagged data.  None if it's malformed.
+fn decode_tagged(buf: &[u8]) -> Option<(~[(~str, u64)], ~[u8])> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let count = ioutil::unpack_u16_be(buf, 0) as uint;
+    let mut offset = 2;
+    let mut tag_versions : ~[(~str, u64)] = ~[];
+    for _ in range(0, count) {
+        if offset + 2 > buf.len() {
+            return None;
+        }
+        let tag_len = ioutil::unpack_u16_be(buf, offset) as uint;
+        offset += 2;
+        if offset + tag_len + 8 > buf.len() || !str::is_utf8(buf.slice(offset, offset + tag_len)) {
+            return None;
+        }
+        let tag = str::from_utf8(buf.slice(offset, offset + tag_len));
+        let version = ioutil::unpack_u64_be(buf, offset + tag_len);
+        offset += tag_len + 8;
+        tag_versions.push((tag, version));
+    }
+    return Some((tag_versions, buf.slice(offset, buf.len()).to_owned()));
+}
+
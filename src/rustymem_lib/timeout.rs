@@ -0,0 +1,390 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+
+use std::cmp;
+use std::task;
+use std::vec;
+use std::cell::Cell;
+use std::result::Result;
+use std::comm::{stream, Port, Chan, SharedChan, GenericChan, GenericSmartChan, GenericPort, Peekable};
+
+
+use common::netutil;
+use common::timeutil;
+
+
+use super::super::ProtoConnection;
+use super::super::PipelineCmd;
+use super::super::MemProtocol;
+use super::super::MemStatus;
+use super::super::MemResult;
+use super::super::MemData;
+use super::super::MemcachedStat;
+use super::super::Network_Error;
+use super::super::DEFAULT_PORT;
+use super::super::new_protocol_connection;
+
+
+
+//
+// Connection with an I/O timeout
+//
+// The socket I/O has no timeout and can't be interrupted, so the protocol connection runs in a worker task
+// of its own, and each call is sent to it and waited for up to timeout_ms.  A timer task sends None in the
+// reply channel when the time is up, and whichever comes first is taken.  The timer task lives as long as
+// the connection, armed with the deadline of each call and disarmed when the reply comes first, checking for
+// the disarm every TIMER_TICK_MS while armed.  On a timeout, the connection is
+// down and the worker is abandoned, to finish its call and exit whenever the server answers or the socket
+// fails.  The next p_reconnect() starts a new worker with a new connection, waiting for its connect the same way.
+// A connection being down makes RustyMem fail the reads over to the replicas and record the server's failure.
+//
+
+// Longest sleep of an armed timer before checking for its disarm
+static TIMER_TICK_MS: u64   = 10;
+
+
+enum Reply {
+    R_STATUS(MemStatus),
+    R_RESULT(MemResult<u64>),
+    R_RESULTS(~[MemResult<u64>]),
+    R_DATA(~[MemData]),
+    R_STATS(~[MemcachedStat]),
+    R_VERSION(Result<~str, ~str>),
+    R_CONNECTED(bool),
+}
+
+// A call to run on the protocol connection in the worker
+type Call = ~fn(&mut ~ProtoConnection) -> Reply;
+
+// The reply of a call, and whether the connection is still up after it.  None when the call timed out.
+type ReplyChan = SharedChan<Option<(Reply, bool)>>;
+
+// Message to the timer task
+enum TimerMsg {
+    // Send None in the reply channel at the deadline in milliseconds, unless disarmed before.
+    TIMER_ARM(u64, ReplyChan),
+    TIMER_DISARM,
+}
+
+
+/// ProtoConnection running the protocol connection in a worker task, with a timeout on each call.
+pub struct TimeoutConnection {
+    priv server_addr:   ~str,
+    priv protocol:      MemProtocol,
+    priv timeout_ms:    uint,
+    priv worker:        Option<Chan<(Call, ReplyChan)>>,
+    priv timer:         Chan<TimerMsg>,
+    priv connected:     bool,
+}
+
+impl TimeoutConnection {
+
+    /// Connect to the server, waiting up to timeout_ms.
+    pub fn new_connection(server_addr: &str, protocol: MemProtocol, timeout_ms: uint) -> TimeoutConnection {
+        let (timer_port, timer_chan) = stream::<TimerMsg>();
+        let timer_port = Cell::new(timer_port);
+        do task::spawn {
+            run_timer(timer_port.take());
+        }
+        let mut conn = TimeoutConnection {
+            server_addr:    netutil::HostAddr::with_host_port(server_addr, DEFAULT_PORT).to_str(),
+            protocol:       protocol,
+            timeout_ms:     timeout_ms,
+            worker:         None,
+            timer:          timer_chan,
+            connected:      false,
+        };
+        conn.start_worker();
+        return conn;
+    }
+
+    // Start a worker connecting to the server, and wait for its connect.
+    fn start_worker(&mut self) -> bool {
+        let (port, chan) = stream::<(Call, ReplyChan)>();
+        let (ready_port, ready_chan) = stream::<Option<(Reply, bool)>>();
+        let ready_chan = SharedChan::new(ready_chan);
+        let server_addr = Cell::new(self.server_addr.clone());
+        let protocol = self.protocol;
+        let worker_ready = Cell::new(ready_chan.clone());
+        let port = Cell::new(port);
+        do task::spawn {
+            serve_calls(server_addr.take(), protocol, worker_ready.take(), port.take());
+        }
+        self.connected = match self.wait_reply(ready_port, ready_chan) {
+            Some((_, connected)) => connected,
+            None => false
+        };
+        self.worker = if self.connected { Some(chan) } else { None };
+        if !self.connected {
+            debug!( fmt!("connect to %s failed or timed out", self.server_addr) );
+        }
+        return self.connected;
+    }
+
+    // Run the call in the worker.  Return None if the connection is down or the call timed out.
+    fn call(&mut self, call: Call) -> Option<Reply> {
+        if !self.connected || self.worker.is_none() {
+            return None;
+        }
+        let (port, chan) = stream::<Option<(Reply, bool)>>();
+        let chan = SharedChan::new(chan);
+        self.worker.get_ref().send((call, chan.clone()));
+        match self.wait_reply(port, chan) {
+            Some((reply, connected)) => {
+                self.connected = connected;
+                Some(reply)
+            },
+            None => {
+                debug!( fmt!("call to %s timed out after %? ms", self.server_addr, self.timeout_ms) );
+                self.connected = false;
+                self.worker = None;
+                None
+            }
+        }
+    }
+
+    // Wait up to timeout_ms for the reply on the port.  chan is the port's channel, used by the timer to
+    // send None when the time is up.
+    fn wait_reply(&self, port: Port<Option<(Reply, bool)>>, chan: ReplyChan) -> Option<(Reply, bool)> {
+        self.timer.send(TIMER_ARM(timeutil::now_ms() + self.timeout_ms as u64, chan));
+        let reply = match port.try_recv() {
+            Some(reply) => reply,
+            None => None
+        };
+        self.timer.send(TIMER_DISARM);
+        return reply;
+    }
+
+    fn call_status(&mut self, call: Call) -> MemStatus {
+        match self.call(call) {
+            Some(R_STATUS(status)) => status,
+            _ => Network_Error
+        }
+    }
+
+    fn call_result(&mut self, call: Call) -> MemResult<u64> {
+        match self.call(call) {
+            Some(R_RESULT(result)) => result,
+            _ => MemResult { status: Network_Error, value: 0 }
+        }
+    }
+
+    fn call_data(&mut self, call: Call) -> ~[MemData] {
+        match self.call(call) {
+            Some(R_DATA(md_list)) => md_list,
+            _ => ~[]
+        }
+    }
+
+    fn call_stats(&mut self, call: Call) -> ~[MemcachedStat] {
+        match self.call(call) {
+            Some(R_STATS(stats)) => stats,
+            _ => ~[]
+        }
+    }
+
+}
+
+impl ProtoConnection for TimeoutConnection {
+
+    fn p_set(&mut self,  key: &str,  data: &[u8],  cas: u64,  flags: u32,  exptime: uint,  noreply: bool) -> MemResult<u64> {
+        let (key, data) = (key.to_owned(), data.to_owned());
+        return self.call_result(|conn| R_RESULT(conn.p_set(key, data, cas, flags, exptime, noreply)));
+    }
+
+    fn p_cas(&mut self, key: &str, data: &[u8], cas_unique: u64, flags: u32, exptime: uint, noreply: bool) -> MemResult<u64> {
+        let (key, data) = (key.to_owned(), data.to_owned());
+        return self.call_result(|conn| R_RESULT(conn.p_cas(key, data, cas_unique, flags, exptime, noreply)));
+    }
+
+    fn p_add(&mut self,  key: &str,  data: &[u8],  cas: u64,  flags: u32,  exptime: uint,  noreply: bool) -> MemResult<u64> {
+        let (key, data) = (key.to_owned(), data.to_owned());
+        return self.call_result(|conn| R_RESULT(conn.p_add(key, data, cas, flags, exptime, noreply)));
+    }
+
+    fn p_replace(&mut self,  key: &str,  data: &[u8],  cas: u64,  flags: u32,  exptime: uint,  noreply: bool) -> MemResult<u64> {
+        let (key, data) = (key.to_owned(), data.to_owned());
+        return self.call_result(|conn| R_RESULT(conn.p_replace(key, data, cas, flags, exptime, noreply)));
+    }
+
+    fn p_append(&mut self, key: &str, data: &[u8], noreply: bool) -> MemResult<u64> {
+        let (key, data) = (key.to_owned(), data.to_owned());
+        return self.call_result(|conn| R_RESULT(conn.p_append(key, data, noreply)));
+    }
+
+    fn p_prepend(&mut self, key: &str, data: &[u8], noreply: bool) -> MemResult<u64> {
+        let (key, data) = (key.to_owned(), data.to_owned());
+        return self.call_result(|conn| R_RESULT(conn.p_prepend(key, data, noreply)));
+    }
+
+    fn p_touch(&mut self, key: &str, exptime: uint, noreply: bool) -> MemStatus {
+        let key = key.to_owned();
+        return self.call_status(|conn| R_STATUS(conn.p_touch(key, exptime, noreply)));
+    }
+
+    fn p_incr(&mut self, key: &str, inc_amount: u64, init_value: u64, exptime: uint, noreply: bool) -> MemResult<u64> {
+        let key = key.to_owned();
+        return self.call_result(|conn| R_RESULT(conn.p_incr(key, inc_amount, init_value, exptime, noreply)));
+    }
+
+    fn p_decr(&mut self, key: &str, dec_amount: u64, init_value: u64, exptime: uint, noreply: bool) -> MemResult<u64> {
+        let key = key.to_owned();
+        return self.call_result(|conn| R_RESULT(conn.p_decr(key, dec_amount, init_value, exptime, noreply)));
+    }
+
+    fn p_delete(&mut self, key: &str, noreply: bool) -> MemStatus {
+        let key = key.to_owned();
+        return self.call_status(|conn| R_STATUS(conn.p_delete(key, noreply)));
+    }
+
+    fn p_delete_cas(&mut self, key: &str, cas_unique: u64, noreply: bool) -> MemStatus {
+        let key = key.to_owned();
+        return self.call_status(|conn| R_STATUS(conn.p_delete_cas(key, cas_unique, noreply)));
+    }
+
+    fn p_get(&mut self, keys: &[&str]) -> ~[MemData] {
+        let keys = keys.iter().map(|k| k.to_owned()).collect::<~[~str]>();
+        return self.call_data(|conn| {
+                let key_refs = keys.iter().map(|k| k.as_slice()).collect::<~[&str]>();
+                R_DATA(conn.p_get(key_refs))
+            });
+    }
+
+    fn p_gets(&mut self, keys: &[&str]) -> ~[MemData] {
+        let keys = keys.iter().map(|k| k.to_owned()).collect::<~[~str]>();
+        return self.call_data(|conn| {
+                let key_refs = keys.iter().map(|k| k.as_slice()).collect::<~[&str]>();
+                R_DATA(conn.p_gets(key_refs))
+            });
+    }
+
+    fn p_pipeline(&mut self, cmds: &[PipelineCmd]) -> ~[MemResult<u64>] {
+        let cmds = cmds.to_owned();
+        let count = cmds.len();
+        match self.call(|conn| R_RESULTS(conn.p_pipeline(cmds))) {
+            Some(R_RESULTS(results)) => results,
+            _ => vec::from_fn(count, |_| MemResult { status: Network_Error, value: 0 })
+        }
+    }
+
+    fn p_version(&mut self) -> Result<~str, ~str> {
+        match self.call(|conn| R_VERSION(conn.p_version())) {
+            Some(R_VERSION(version)) => version,
+            _ => Err(fmt!("%s is down or timed out", self.server_addr))
+        }
+    }
+
+    fn p_verbosity(&mut self, verbosity: u32, noreply: bool) -> MemStatus {
+        return self.call_status(|conn| R_STATUS(conn.p_verbosity(verbosity, noreply)));
+    }
+
+    fn p_flush(&mut self, delay_in_seconds: uint, noreply: bool) -> MemStatus {
+        return self.call_status(|conn| R_STATUS(conn.p_flush(delay_in_seconds, noreply)));
+    }
+
+    fn p_stats(&mut self) -> ~[MemcachedStat] {
+        return self.call_stats(|conn| R_STATS(conn.p_stats()));
+    }
+
+    fn p_stats_group(&mut self, group: &str) -> ~[MemcachedStat] {
+        let group = group.to_owned();
+        return self.call_stats(|conn| R_STATS(conn.p_stats_group(group)));
+    }
+
+    fn p_stats_reset(&mut self) -> MemStatus {
+        return self.call_status(|conn| R_STATUS(conn.p_stats_reset()));
+    }
+
+    fn p_quit(&mut self) -> MemStatus {
+        let status = self.call_status(|conn| R_STATUS(conn.p_quit()));
+        self.connected = false;
+        self.worker = None;
+        return status;
+    }
+
+    fn p_get_server_addr(&self) -> ~str {
+        return self.server_addr.clone();
+    }
+
+    fn p_is_connected(&self) -> bool {
+        return self.connected;
+    }
+
+    fn p_reconnect(&mut self) -> bool {
+        if self.worker.is_none() {
+            return self.start_worker();
+        }
+        // The worker is alive with its connection down after an I/O error.
+        self.connected = true;
+        match self.call(|conn| R_CONNECTED(conn.p_reconnect())) {
+            Some(R_CONNECTED(connected)) => connected,
+            _ => false
+        }
+    }
+
+}
+
+
+// Run the calls on a connection to the server, until the TimeoutConnection drops the worker.
+fn serve_calls(server_addr: ~str, protocol: MemProtocol, ready: ReplyChan, port: Port<(Call, ReplyChan)>) {
+    let mut conn = new_protocol_connection(server_addr, protocol);
+    let connected = conn.p_is_connected();
+    ready.try_send(Some((R_CONNECTED(connected), connected)));
+    if !connected {
+        return;
+    }
+    loop {
+        match port.try_recv() {
+            Some((call, reply)) => {
+                let result = call(&mut conn);
+                reply.try_send(Some((result, conn.p_is_connected())));
+            },
+            None => break
+        }
+    }
+    conn.p_quit();
+}
+
+// Time the calls of a TimeoutConnection, until it's dropped.  Each arm is followed by a disarm, after the
+// reply or the timeout.
+fn run_timer(port: Port<TimerMsg>) {
+    loop {
+        let (deadline_ms, chan) = match port.try_recv() {
+            Some(TIMER_ARM(deadline_ms, chan)) => (deadline_ms, chan),
+            Some(TIMER_DISARM) => continue,
+            None => break
+        };
+        let mut disarmed = false;
+        loop {
+            if port.peek() {
+                disarmed = true;
+                break;
+            }
+            let now_ms = timeutil::now_ms();
+            if now_ms >= deadline_ms {
+                break;
+            }
+            timeutil::sleep_ms(cmp::min(TIMER_TICK_MS, deadline_ms - now_ms));
+        }
+        if disarmed {
+            port.try_recv();
+        } else {
+            chan.try_send(None);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::util;
+use std::result::Result;
+
+
+use common::strutil;
+use common::netutil;
+
+
+use super::super::RustyMem;
+use super::super::ProtoConnection;
+use super::super::MemStatus;
+use super::super::Invalid_Arguments;
+use super::super::DEFAULT_PORT;
+use super::super::HASH_MOD;
+use super::super::new_server_connection;
+
+
+
+//
+// Runtime change of the servers
+//
+// The connections of the servers kept are reused, new servers are connected, and the connections of the
+// removed servers are closed.  The shard map is the order of the connections, so it's rebuilt with them.
+//
+
+// Cap on the key hash period examined for the moved key estimate
+static MAX_ESTIMATE_PERIOD: uint    = 1000000;
+
+
+/// Expected effect of a change of the servers on the keys
+pub struct RebalanceEstimate {
+    /// Fraction of the keys mapped to a different server after the change
+    moved_fraction: f64,
+    /// Items in the servers before the change, by their curr_items stats
+    total_items:    u64,
+    /// Items expected to be mapped to a different server, becoming misses
+    moved_items:    u64,
+}
+
+
+impl RustyMem {
+
+    /// Add a server to the end of the server list.  Adding a server already in the list changes nothing.
+    /// rm.add_server("127.0.0.3:11211")
+    pub fn add_server(&mut self, server_addr: &str) -> RebalanceEstimate {
+        let mut addrs = self.server_addrs();
+        let addr = normalize_addr(server_addr);
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+        return self.change_servers(addrs);
+    }
+
+    /// Remove a server from the server list.  Return Invalid_Arguments if it's not in the list, or it's the last server.
+    pub fn remove_server(&mut self, server_addr: &str) -> Result<RebalanceEstimate, MemStatus> {
+        let addr = normalize_addr(server_addr);
+        let addrs = self.server_addrs();
+        if !addrs.contains(&addr) || addrs.len() == 1 {
+            return Err(Invalid_Arguments);
+        }
+        let addrs = addrs.move_iter().filter(|a| *a != addr).collect::<~[~str]>();
+        return Ok(self.change_servers(addrs));
+    }
+
+    /// Replace the server list, with the same format as in connect().  Duplicates are ignored.  Return Invalid_Arguments if it has no server.
+    /// rm.set_servers("127.0.0.1 127.0.0.2:11212")
+    pub fn set_servers(&mut self, server_addrs: &str) -> Result<RebalanceEstimate, MemStatus> {
+        let mut addrs : ~[~str] = ~[];
+        for addr in strutil::clean_split(server_addrs, ' ').iter().map(|addr| normalize_addr(*addr)) {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        if addrs.len() == 0 {
+            return Err(Invalid_Arguments);
+        }
+        return Ok(self.change_servers(addrs));
+    }
+
+    /// Addresses of the servers, in the order of their connections.
+    pub fn server_addrs(&self) -> ~[~str] {
+        return self.connections.iter().map(|conn| conn.p_get_server_addr()).collect::<~[~str]>();
+    }
+
+    // Rebuild the connections for the new server list.
+    fn change_servers(&mut self, new_addrs: ~[~str]) -> RebalanceEstimate {
+        let old_addrs = self.server_addrs();
+        let estimate = self.estimate_rebalance(old_addrs, new_addrs);
+        if old_addrs == new_addrs {
+            return estimate;
+        }
+        debug!( fmt!("change servers %? to %?", old_addrs, new_addrs) );
+
+        let mut old_connections : ~[Option<~ProtoConnection>] = ~[];
+        for conn in util::replace(&mut self.connections, ~[]).move_iter() {
+            old_connections.push(Some(conn));
+        }
+        let mut connections : ~[~ProtoConnection] = ~[];
+        for addr in new_addrs.iter() {
+            let conn = match old_addrs.iter().position(|a| a == addr) {
+                Some(i) => old_connections[i].take_unwrap(),
+                None    => new_server_connection(*addr, &self.params)
+            };
+            connections.push(conn);
+        }
+        for conn in old_connections.mut_iter() {
+            match conn.take() {
+                Some(mut removed) => { removed.p_quit(); },
+                None => ()
+            }
+        }
+
+        self.connections = connections;
+        self.health.retain_servers(new_addrs);
+        self.params.servers = new_addrs.connect(" ");
+        for i in range(0, self.get_connection_count()) {
+            if !self.connections[i].p_is_connected() && self.health.servers[i].consecutive_failures == 0 {
+                self.connection_failed(i, "connect failed");
+            }
+        }
+        return estimate;
+    }
+
+    // Estimate the keys moved from the old servers to the new servers, with the shard method.
+    // A key moves if its hash maps it to a different server address.
+    fn estimate_rebalance(&mut self, old_addrs: &[~str], new_addrs: &[~str]) -> RebalanceEstimate {
+        let moved_fraction = match self.params.shard {
+            HASH_MOD => hash_mod_moved_fraction(old_addrs, new_addrs),
+        };
+        let total_items = self.stats().iter().map(|server_stats| {
+                match server_stats.iter().find(|stat| stat.name.as_slice() == "curr_items") {
+                    Some(stat) => match from_str::<u64>(stat.value) { Some(n) => n, None => 0 },
+                    None => 0
+                }
+            }).fold(0u64, |sum, items| sum + items);
+        return RebalanceEstimate {
+            moved_fraction: moved_fraction,
+            total_items:    total_items,
+            moved_items:    (total_items as f64 * moved_fraction) as u64,
+        };
+    }
+
+}
+
+
+// Fraction of the hash values mapped to a different server address by hash % N, going from the old
+// servers to the new servers.  The mapping repeats every lcm(old count, new count) hash values.
+fn hash_mod_moved_fraction(old_addrs: &[~str], new_addrs: &[~str]) -> f64 {
+    let old_count = old_addrs.len();
+    let new_count = new_addrs.len();
+    if old_count == 0 || new_count == 0 {
+        return 1.0;
+    }
+    let period = old_count / gcd(old_count, new_count) * new_count;
+    let period = if period > MAX_ESTIMATE_PERIOD { MAX_ESTIMATE_PERIOD } else { period };
+    let mut moved = 0u;
+    for h in range(0, period) {
+        if old_addrs[h % old_count] != new_addrs[h % new_count] {
+            moved += 1;
+        }
+    }
+    return moved as f64 / period as f64;
+}
+
+fn gcd(a: uint, b: uint) -> uint {
+    return if b == 0 { a } else { gcd(b, a % b) };
+}
+
+// Server address in the host:port form returned by ProtoConnection.p_get_server_addr()
+fn normalize_addr(server_addr: &str) -> ~str {
+    return netutil::HostAddr::with_host_port(server_addr, DEFAULT_PORT).to_str();
+}
+
@@ -0,0 +1,111 @@
+/******************************************************************************
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0.  If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Software distributed under the License is distributed on an "AS IS" basis,
+ * WITHOUT WARRANTY OF ANY KIND, either express or implied. See the License for
+ * the specific language governing rights and limitations under the License.
+ *
+ * The Original Code is: RustyMem
+ * The Initial Developer of the Original Code is: William Wong (williamw520@gmail.com)
+ * Portions created by William Wong are Copyright (C) 2013 William Wong, All Rights Reserved.
+ *
+ ******************************************************************************/
+
+
+
+use std::cmp;
+use std::rand;
+use std::result::Result;
+
+
+use common::timeutil;
+
+
+use super::super::RustyMem;
+use super::super::MemStatus;
+use super::super::Success;
+use super::super::Key_Exists;
+use super::super::Key_Not_Found;
+use super::super::Item_Not_Stored;
+use super::super::OP_CAS;
+use super::super::OP_ADD;
+use super::codec::{ValueCodec, BytesCodec};
+
+
+
+//
+// Compare-and-swap update loop
+//
+// The current value is read with its CAS, the new value computed from it, and written back with cas,
+// or with add if there was no value.  A write losing to another client is retried on the new value,
+// after a random backoff so the competing clients don't collide again in lockstep.
+//
+
+
+impl RustyMem {
+
+    /// Update the value at key with the block computing the new value from the current one, None if not existed.
+    /// The value is encoded by the codec.  The block is called again on each retry after a conflicting write,
+    /// up to params.cas_attempts times.  Return the stored value and its CAS.
+    /// Under the ASCII protocol, the CAS is read back after the write, and is 0 if another client has changed the value since.
+    /// rm.update_value("counters", 0, &JsonCodec, |old: Option<~[uint]>| { ... })
+    pub fn update_value<T, C: ValueCodec<T>>(&mut self, key: &str, exptime: uint, codec: &C, blk: &fn(Option<T>) -> T) -> Result<(T, u64), MemStatus> {
+        let flags = self.params.flag_scheme.to_codec_flags(codec.value_type(), codec.codec_id());
+        let mut attempt = 0u;
+        loop {
+            attempt += 1;
+            // The CAS must be fresh from memcached, not from the L1 cache.
+            let current = self.with_l1_bypass(|rm| rm.get_data(key));
+            let (old_value, cas) = match current {
+                Some(md) => match md.try_value(codec as &ValueCodec<T>) {
+                    Ok(value)   => (Some(value), md.cas),
+                    Err(status) => return Err(status)
+                },
+                None => (None, 0)
+            };
+            let op = if cas == 0 { OP_ADD } else { OP_CAS };
+            let new_value = blk(old_value);
+            let data = codec.encode(&new_value);
+            let result = self.store_flags_cmd(op, key, data, cas, flags, exptime);
+            match result.status {
+                Success => {
+                    let new_cas = if result.value != 0 { result.value } else { self.read_back_cas(key, data) };
+                    return Ok((new_value, new_cas));
+                },
+                // cas on a changed or deleted value, or add on a value created since the read.
+                Key_Exists | Key_Not_Found | Item_Not_Stored if attempt < self.params.cas_attempts => {
+                    debug!( fmt!("update conflict at %?, attempt %?", key, attempt) );
+                    self.cas_backoff(attempt);
+                },
+                status => return Err(status)
+            }
+        }
+    }
+
+    /// Update the bytes at key with the block computing the new bytes from the current ones, None if not existed.
+    /// Return the stored bytes and their CAS.
+    /// rm.update_bytes("key1", 0, |old| { match old { Some(b) => b + bytes!("x"), None => bytes!("x").to_owned() } })
+    pub fn update_bytes(&mut self, key: &str, exptime: uint, blk: &fn(Option<~[u8]>) -> ~[u8]) -> Result<(~[u8], u64), MemStatus> {
+        return self.update_value(key, exptime, &BytesCodec, blk);
+    }
+
+    // The ASCII protocol doesn't return the CAS on store.  Read it back if the value is still the one written.
+    fn read_back_cas(&mut self, key: &str, data: &[u8]) -> u64 {
+        match self.with_l1_bypass(|rm| rm.get_data(key)) {
+            Some(md) if md.data.as_slice() == data => md.cas,
+            _ => 0
+        }
+    }
+
+    // Sleep a random time up to the backoff doubling with each attempt.
+    fn cas_backoff(&self, attempt: uint) {
+        let max_ms = (self.params.cas_backoff_ms as u64) << cmp::min(attempt, 6);
+        if max_ms > 0 {
+            timeutil::sleep_ms(rand::random::<u64>() % max_ms + 1);
+        }
+    }
+
+}
+
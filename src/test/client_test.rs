@@ -28,12 +28,12 @@ use rustymem::ProtoConnection;
 
 
 fn test_new_conn() -> RustyMem {
-    return rustymem::connect_with( MemParams { servers: ~"127.0.0.1", protocol: P_ASCII, shard: HASH_MOD } );
+    return rustymem::connect_with( MemParams::new("127.0.0.1", P_ASCII) );
 }
 
 fn test_binary_conn() {
 
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
 
     println( fmt!("versions: %?", rm.versions()) );
 
@@ -303,6 +303,750 @@ fn test_rm(rm: &mut RustyMem) {
 
 }
 
+fn test_keys() {
+
+    let mut rm = rustymem::connect("127.0.0.1");
+
+    println( fmt!("set_str bad key: %?", rm.set_str("bad key", 0, "value")) );
+    println( fmt!("set_str key with CRLF: %?", rm.set_str("bad\r\nkey", 0, "value")) );
+    println( fmt!("set_str empty key: %?", rm.set_str("", 0, "value")) );
+    let long_key = str::from_chars(vec::from_elem(300, 'k'));
+    println( fmt!("set_str long key: %?", rm.set_str(long_key, 0, "value")) );
+    println( fmt!("get_str long key: %?", rm.get_str(long_key)) );
+    println( fmt!("delete bad key: %?", rm.delete("bad key")) );
+    println( fmt!("get_bulk_str key1 bad key: %?", rm.get_bulk_str(["key1", "bad key"])) );
+
+    let mut params = MemParams::new("127.0.0.1", P_ASCII);
+    params.key_mode = KEY_HASH_LONG;
+    let mut rm = rustymem::connect_with(params);
+
+    println( fmt!("set_str bad key: %?", rm.set_str("bad key", 0, "value1")) );
+    println( fmt!("set_str long key: %?", rm.set_str(long_key, 0, "value2")) );
+    println( fmt!("get_str bad key: %?", rm.get_str("bad key")) );
+    println( fmt!("get_bulk_str bad key, long key: %?", rm.get_bulk_str(["bad key", long_key.as_slice()])) );
+    println( fmt!("set_str empty key: %?", rm.set_str("", 0, "value")) );
+}
+
+fn test_key_prefix() {
+
+    let mut params = MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY);
+    params.key_prefix = ~"app1:";
+    let mut rm = rustymem::connect_with(params);
+
+    println( fmt!("set_str key1: %?", rm.set_str("key1", 60, "app1 value1")) );
+    println( fmt!("set_str key2: %?", rm.set_str("key2", 60, "app1 value2")) );
+    println( fmt!("get_str key1: %?", rm.get_str("key1")) );
+    println( fmt!("get_bulk_str key1 key2 key_none: %?", rm.get_bulk_str(["key1", "key2", "key_none"])) );
+
+    do rm.with_key_prefix("app2:") |rm2| {
+        println( fmt!("app2 set_str key1: %?", rm2.set_str("key1", 60, "app2 value1")) );
+        println( fmt!("app2 get_bulk_str key1 key2: %?", rm2.get_bulk_str(["key1", "key2"])) );
+    }
+
+    println( fmt!("get_str key1: %?", rm.get_str("key1")) );
+    {
+        let mut app3 = rm.prefixed("app3:");
+        println( fmt!("app3 prefix: %?", app3.key_prefix()) );
+        println( fmt!("app3 set_str key1: %?", app3.mem().set_str("key1", 60, "app3 value1")) );
+        println( fmt!("app3 get_bulk_str key1 key2: %?", app3.mem().get_bulk_str(["key1", "key2"])) );
+    }
+    println( fmt!("get_str key1 after the app3 view: %?", rm.get_str("key1")) );
+    do rm.with_key_prefix("") |rm0| {
+        println( fmt!("no prefix get_bulk_str app1:key1 app2:key1: %?", rm0.get_bulk_str(["app1:key1", "app2:key1", "app3:key1"])) );
+    }
+}
+
+fn test_namespace() {
+
+    let mut rm = rustymem::connect("127.0.0.1");
+
+    let key1 = rm.ns_key("tenant42", "user1").unwrap();
+    println( fmt!("ns_key tenant42 user1: %?", key1) );
+    println( fmt!("set_str %?: %?", key1, rm.set_str(key1, 60, "user1 data")) );
+    println( fmt!("get_str %?: %?", key1, rm.get_str(key1)) );
+
+    println( fmt!("ns_invalidate tenant42: %?", rm.ns_invalidate("tenant42")) );
+    let key2 = rm.ns_key("tenant42", "user1").unwrap();
+    println( fmt!("ns_key tenant42 user1: %?", key2) );
+    println( fmt!("get_str %?: %?", key2, rm.get_str(key2)) );
+
+    // The same namespace under another key prefix has a counter of its own.
+    do rm.with_key_prefix("app2:") |rm2| {
+        println( fmt!("app2 ns_version tenant42: %?", rm2.ns_version("tenant42")) );
+        println( fmt!("app2 ns_invalidate tenant42: %?", rm2.ns_invalidate("tenant42")) );
+    }
+    println( fmt!("ns_version tenant42 unchanged: %?", rm.ns_version("tenant42")) );
+}
+
+fn test_flags() {
+
+    let mut rm = rustymem::connect("127.0.0.1");
+
+    println( fmt!("set_bytes bin1: %?", rm.set_bytes("bin1", 60, [0xFFu8, 0xFE, 0x00])) );
+    println( fmt!("set_str str1: %?", rm.set_str("str1", 60, "hello")) );
+    println( fmt!("set_int int1: %?", rm.set_int("int1", 60, 42)) );
+    println( fmt!("set_json json1: %?", rm.set_json("json1", 60, &~[1, 2, 3])) );
+
+    println( fmt!("get_data str1: %?", rm.get_data("str1")) );
+    println( fmt!("get_data json1: %?", rm.get_data("json1")) );
+    println( fmt!("get_str bin1: %?", rm.get_str("bin1")) );
+    println( fmt!("get_int int1: %?", rm.get_int("int1")) );
+    println( fmt!("incr int1: %?", rm.incr("int1", 1, 0, 0)) );
+    println( fmt!("get_int int1: %?", rm.get_int("int1")) );
+    println( fmt!("get_int json1: %?", rm.get_int("json1")) );
+    println( fmt!("get_json str1: %?", rm.get_json("str1")) );
+    println( fmt!("check_type json1 VT_INT: %?", rm.get_data("json1").unwrap().check_type(VT_INT)) );
+
+    let mut params = MemParams::new("127.0.0.1", P_BINARY);
+    params.flag_scheme = FlagScheme::spymemcached();
+    let mut rm = rustymem::connect_with(params);
+    println( fmt!("spymemcached set_int int2: %?", rm.set_int("int2", 60, 7)) );
+    println( fmt!("spymemcached get_data int2: %?", rm.get_data("int2")) );
+
+    // pylibmc writes integers as FLAG_INTEGER 2 or FLAG_LONG 4, both read as integers.
+    let mut params = MemParams::new("127.0.0.1", P_BINARY);
+    params.flag_scheme = FlagScheme::pylibmc();
+    let mut rm = rustymem::connect_with(params);
+    rm.get_connection(0).p_set("int3", bytes!("8"), 0, 2, 60, false);
+    rm.get_connection(0).p_set("int4", bytes!("9"), 0, 4, 60, false);
+    println( fmt!("pylibmc get_int int3 FLAG_INTEGER, int4 FLAG_LONG: %?, %?", rm.get_int("int3"), rm.get_int("int4")) );
+}
+
+fn test_compression() {
+
+    let mut params = MemParams::new("127.0.0.1", P_BINARY);
+    params.compress_threshold = 1024;
+    let mut rm = rustymem::connect_with(params);
+
+    let page = str::from_chars(vec::from_elem(100*1024, 'x'));
+    println( fmt!("set_str page1: %?", rm.set_str("page1", 60, page)) );
+    println( fmt!("get_str page1 matched: %?", rm.get_str("page1") == Some(page.clone())) );
+    println( fmt!("get_bulk_str page1 matched: %?", rm.get_bulk_str(["page1"])[0].second() == page) );
+    println( fmt!("set_str small1: %?", rm.set_str("small1", 60, "small value")) );
+    println( fmt!("get_data small1: %?", rm.get_data("small1")) );
+
+    // Read the compressed data without compression
+    let mut rm2 = rustymem::connect("127.0.0.1");
+    println( fmt!("no compression get_str page1 matched: %?", rm2.get_str("page1") == Some(page.clone())) );
+    println( fmt!("raw p_gets page1 size: %?", rm2.get_connection(0).p_gets(["page1"])[0].data.len()) );
+}
+
+fn test_large_values() {
+
+    let mut params = MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY);
+    params.large_values = true;
+    let mut rm = rustymem::connect_with(params);
+
+    let big = vec::from_fn(3*1024*1024 + 100, |i| (i % 251) as u8);
+    println( fmt!("set_bytes big1: %?", rm.set_bytes("big1", 60, big)) );
+    println( fmt!("get_bytes big1 matched: %?", rm.get_bytes("big1") == Some(big.clone())) );
+    println( fmt!("get_bulk_bytes big1 key1: %?", rm.get_bulk_bytes(["big1", "key1"]).map(|kv| (kv.first(), kv.second().len()))) );
+
+    // add, replace and cas apply to the chunked value as a whole.
+    println( fmt!("add_bytes big1, expect Item_Not_Stored: %?", rm.add_bytes("big1", 60, big).status) );
+    let cas = match rm.get_data("big1") { Some(md) => md.cas, None => 0 };
+    println( fmt!("cas_bytes big1: %?", rm.cas_bytes("big1", cas, 60, big).status) );
+    println( fmt!("cas_bytes big1 stale cas, expect Key_Exists: %?", rm.cas_bytes("big1", cas, 60, big).status) );
+    println( fmt!("replace_bytes big1: %?", rm.replace_bytes("big1", 0, 60, big).status) );
+
+    // Chunks are reassembled on read even without large_values.
+    let mut rm2 = rustymem::connect("127.0.0.1:11211 127.0.0.1:11212");
+    println( fmt!("get_bytes big1 without large_values matched: %?", rm2.get_bytes("big1") == Some(big.clone())) );
+    println( fmt!("raw p_gets big1: %?", rm2.get_connection(0).p_gets(["big1"])) );
+    println( fmt!("set_bytes big2 without large_values: %?", rm2.set_bytes("big2", 60, big)) );
+
+    let mut params = MemParams::new("127.0.0.1:11211", P_BINARY);
+    params.large_values = true;
+    params.max_item_size = 0;
+    let mut rm3 = rustymem::connect_with(params);
+    println( fmt!("set_bytes big3 with max_item_size 0, expect Invalid_Arguments: %?", rm3.set_bytes("big3", 60, big).status) );
+
+    // The async client reads and writes the same chunked values.
+    let mut params = MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY);
+    params.large_values = true;
+    let am = AsyncMem::new(params);
+    let mut get1 = am.get_bytes("big1");
+    println( fmt!("async get_bytes big1 matched: %?", get1.get() == Some(big.clone())) );
+    let mut set4 = am.set_bytes("big4", 60, big);
+    println( fmt!("async set_bytes big4: %?", set4.get().status) );
+    println( fmt!("get_bytes big4 matched: %?", rm.get_bytes("big4") == Some(big.clone())) );
+}
+
+#[deriving(Encodable, Decodable)]
+struct TestUser {
+    name:       ~str,
+    age:        uint,
+    balance:    f64,
+}
+
+fn test_codecs() {
+
+    let mut rm = rustymem::connect("127.0.0.1");
+
+    let user = TestUser { name: ~"john", age: 42, balance: 1234.5678901234 };
+
+    println( fmt!("set_value user1 JsonCodec: %?", rm.set_value("user1", 60, &JsonCodec, &user)) );
+    println( fmt!("set_value user2 EbmlCodec: %?", rm.set_value("user2", 60, &EbmlCodec, &user)) );
+    println( fmt!("set_value bytes1 BytesCodec: %?", rm.set_value("bytes1", 60, &BytesCodec, &~[1u8, 2, 3])) );
+
+    println( fmt!("get_value user1 JsonCodec: %?", rm.get_value::<TestUser, JsonCodec>("user1", &JsonCodec)) );
+    println( fmt!("get_value user2 EbmlCodec: %?", rm.get_value::<TestUser, EbmlCodec>("user2", &EbmlCodec)) );
+    println( fmt!("get_value user2 JsonCodec, mismatched: %?", rm.get_value::<TestUser, JsonCodec>("user2", &JsonCodec)) );
+    println( fmt!("get_json user1: %?", rm.get_json("user1")) );
+    println( fmt!("get_value bytes1 BytesCodec: %?", rm.get_value::<~[u8], BytesCodec>("bytes1", &BytesCodec)) );
+
+    let codecs = [&JsonCodec as &ValueCodec<TestUser>, &EbmlCodec as &ValueCodec<TestUser>];
+    println( fmt!("get_value_any user1: %?", rm.get_value_any("user1", codecs)) );
+    println( fmt!("get_value_any user2: %?", rm.get_value_any("user2", codecs)) );
+}
+
+fn test_update() {
+
+    let mut rm = rustymem::connect("127.0.0.1");
+
+    println( fmt!("delete upd1: %?", rm.delete("upd1")) );
+
+    // Missing value is added.
+    println( fmt!("update_bytes upd1: %?", rm.update_bytes("upd1", 60, |old| {
+                match old {
+                    Some(data) => data + bytes!("b"),
+                    None => bytes!("a").to_owned()
+                }
+            })) );
+    println( fmt!("update_bytes upd1: %?", rm.update_bytes("upd1", 60, |old| {
+                match old {
+                    Some(data) => data + bytes!("b"),
+                    None => bytes!("a").to_owned()
+                }
+            })) );
+    println( fmt!("get_str upd1: %?", rm.get_str("upd1")) );
+
+    // Competing write in the middle of the update forces a retry.
+    let mut other = rustymem::connect("127.0.0.1");
+    let mut calls = 0;
+    println( fmt!("update_value upd2: %?", rm.update_value("upd2", 60, &JsonCodec, |old: Option<~[uint]>| {
+                calls += 1;
+                if calls == 1 {
+                    other.set_value("upd2", 60, &JsonCodec, &~[100u]);
+                }
+                match old {
+                    Some(list) => list + ~[calls],
+                    None => ~[calls]
+                }
+            })) );
+    println( fmt!("update_value calls: %?", calls) );
+
+    let mut ascii_rm = rustymem::connect_with( MemParams::new("127.0.0.1", P_ASCII) );
+    println( fmt!("update_bytes ascii upd1: %?", ascii_rm.update_bytes("upd1", 60, |old| {
+                match old {
+                    Some(data) => data + bytes!("c"),
+                    None => bytes!("a").to_owned()
+                }
+            })) );
+}
+
+fn test_get_or_compute() {
+
+    let mut rm = rustymem::connect("127.0.0.1");
+
+    rm.delete("rt1");
+    rm.delete("rt_none");
+
+    let mut loads = 0;
+    println( fmt!("get_or_compute_str rt1: %?", rm.get_or_compute_str("rt1", 60, 10, || { loads += 1; Some(~"from db") })) );
+    println( fmt!("get_or_compute_str rt1: %?", rm.get_or_compute_str("rt1", 60, 10, || { loads += 1; Some(~"from db") })) );
+    println( fmt!("loads, expect 1: %?", loads) );
+
+    loads = 0;
+    println( fmt!("get_or_compute_str rt_none: %?", rm.get_or_compute_str("rt_none", 60, 10, || { loads += 1; None })) );
+    println( fmt!("get_or_compute_str rt_none: %?", rm.get_or_compute_str("rt_none", 60, 10, || { loads += 1; None })) );
+    println( fmt!("loads with negative caching, expect 1: %?", loads) );
+    println( fmt!("get_str rt_none: %?", rm.get_str("rt_none")) );
+
+    println( fmt!("get_or_compute_value rt2: %?", rm.get_or_compute_value("rt2", 60, 0, &JsonCodec, || Some(~[1u, 2, 3]))) );
+
+    rm.delete("rtb1");
+    rm.delete("rtb2");
+    rm.delete("rtb3");
+    rm.set_value("rtb1", 60, &JsonCodec, &10u);
+    let values = rm.get_or_compute_bulk_value(["rtb1", "rtb2", "rtb3"], 60, 10, &JsonCodec, |missing| {
+            println( fmt!("loader called with %?", missing) );
+            missing.iter().filter(|k| k.as_slice() != "rtb3").map(|k| (k.clone(), 20u)).collect::<~[(~str, uint)]>()
+        });
+    println( fmt!("get_or_compute_bulk_value: %?", values) );
+    let values = rm.get_or_compute_bulk_value(["rtb1", "rtb2", "rtb3"], 60, 10, &JsonCodec, |missing| {
+            println( fmt!("loader should not be called, %?", missing) );
+            ~[]
+        });
+    println( fmt!("get_or_compute_bulk_value: %?", values) );
+}
+
+fn test_lock() {
+
+    let mut rm = rustymem::connect("127.0.0.1");
+    let mut other = rustymem::connect_with( MemParams::new("127.0.0.1", P_ASCII) );
+
+    {
+        let mut lock = rm.try_lock("job1", 30).unwrap();
+        println( fmt!("locked job1, token: %?", lock.token()) );
+        println( fmt!("other try_lock job1, expect Key_Exists: %?", other.try_lock("job1", 30).is_ok()) );
+        println( fmt!("other lock_release with wrong token: %?", other.lock_release("job1", "not-the-owner")) );
+        println( fmt!("renew job1: %?", lock.renew(60)) );
+        println( fmt!("get_str in lock: %?", lock.mem().get_str("__lock:job1")) );
+    }
+    // Released on drop
+    match other.try_lock("job1", 30) {
+        Ok(mut lock) => println( fmt!("other locked job1 after drop, release: %?", lock.release()) ),
+        Err(status) => println( fmt!("other try_lock job1 failed: %?", status) )
+    }
+
+    // Blocking acquire times out while the lock is held.
+    println( fmt!("lock_acquire job2: %?", rm.lock_acquire("job2", "owner1", 2)) );
+    match other.lock("job2", 30, 500) {
+        Ok(_) => println("other lock job2 acquired, expect timeout"),
+        Err(status) => println( fmt!("other lock job2 timed out: %?", status) )
+    }
+    // And succeeds after the lock expires.
+    match other.lock("job2", 30, 5000) {
+        Ok(lock) => println( fmt!("other lock job2 acquired after expiry: %?", lock.token()) ),
+        Err(status) => println( fmt!("other lock job2 failed: %?", status) )
+    }
+}
+
+fn test_rate_limit() {
+
+    for protocol in [P_ASCII, P_BINARY].iter() {
+        let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1", *protocol) );
+
+        rm.delete("ctr1");
+        println( fmt!("incr missing ctr1 init 10: %?", rm.incr("ctr1", 1, 10, 60)) );
+        println( fmt!("incr ctr1: %?", rm.incr("ctr1", 5, 10, 60)) );
+        println( fmt!("decr ctr1: %?", rm.decr("ctr1", 2, 10, 60)) );
+        rm.delete("ctr2");
+        println( fmt!("incr missing ctr2 no create: %?", rm.incr("ctr2", 1, 10, 0xFFFFFFFF)) );
+
+        let name = fmt!("api:user1:%?", *protocol as int);
+        for i in range(0, 5) {
+            println( fmt!("rate_limit_fixed %?: %?", i, rm.rate_limit_fixed(name, 3, 10)) );
+        }
+        for i in range(0, 5) {
+            println( fmt!("rate_limit_sliding %?: %?", i, rm.rate_limit_sliding(name + ":s", 3, 10)) );
+        }
+        println( fmt!("rate_limit_fixed 0 secs, expect Invalid_Arguments: %?", rm.rate_limit_fixed(name, 3, 0)) );
+        println( fmt!("rate_limit_sliding 0 secs, expect Invalid_Arguments: %?", rm.rate_limit_sliding(name, 3, 0)) );
+    }
+}
+
+fn test_l1_cache() {
+
+    let mut params = MemParams::new("127.0.0.1", P_BINARY);
+    params.l1_max_bytes = 64 * 1024;
+    params.l1_ttl_ms = 2000;
+    let mut rm = rustymem::connect_with(params);
+    let mut other = rustymem::connect("127.0.0.1");
+
+    println( fmt!("set_str flag1 on: %?", rm.set_str("flag1", 60, "on")) );
+    println( fmt!("get_str flag1 from L1: %?", rm.get_str("flag1")) );
+    println( fmt!("get_bulk_str flag1, flag2: %?", rm.get_bulk_str(["flag1", "flag2"])) );
+
+    // Another client's write is not seen until the L1 entry expires, unless bypassed.
+    println( fmt!("other set_str flag1 off: %?", other.set_str("flag1", 60, "off")) );
+    println( fmt!("get_str flag1 stale from L1: %?", rm.get_str("flag1")) );
+    println( fmt!("get_str flag1 bypassing L1: %?", do rm.with_l1_bypass |rm2| { rm2.get_str("flag1") }) );
+    println( fmt!("get_str flag1 refreshed in L1: %?", rm.get_str("flag1")) );
+
+    println( fmt!("delete flag1: %?", rm.delete("flag1")) );
+    println( fmt!("get_str flag1 after delete: %?", rm.get_str("flag1")) );
+
+    println( fmt!("cache_stats: %?", rm.cache_stats()) );
+
+    // ASCII stores return no cas, so the stored value is not cached, and the read gets the cas from the server.
+    let mut params = MemParams::new("127.0.0.1", P_ASCII);
+    params.l1_max_bytes = 64 * 1024;
+    let mut rm = rustymem::connect_with(params);
+    println( fmt!("ascii set_str flag3: %?", rm.set_str("flag3", 60, "on")) );
+    let cas = match rm.get_data("flag3") { Some(md) => md.cas, None => 0 };
+    println( fmt!("ascii get_data flag3 cas: %?", cas) );
+    println( fmt!("ascii cas_str flag3: %?", rm.cas_str("flag3", cas, 60, "off")) );
+}
+
+fn test_replication() {
+
+    // Run memcached at 11211 and 11212.  Nothing runs at 11213, to have a server down.
+    let mut params = MemParams::new("127.0.0.1:11211 127.0.0.1:11212 127.0.0.1:11213", P_BINARY);
+    params.replication = 2;
+    let mut rm = rustymem::connect_with(params);
+
+    for i in range(0, 6) {
+        let key = fmt!("rkey%?", i);
+        println( fmt!("set_str %s: %?", key, rm.set_str(key, 60, "replicated")) );
+        println( fmt!("get_str %s: %?", key, rm.get_str(key)) );
+    }
+    let keys = ["rkey0", "rkey1", "rkey2", "rkey3", "rkey4", "rkey5"];
+    println( fmt!("get_bulk_str: %?", rm.get_bulk_str(keys)) );
+
+    println( fmt!("incr rctr: %?", rm.incr("rctr", 1, 1, 60)) );
+    println( fmt!("incr rctr: %?", rm.incr("rctr", 1, 1, 60)) );
+
+    // Each key is on 2 of the servers.
+    let mut server1 = rustymem::connect("127.0.0.1:11211");
+    let mut server2 = rustymem::connect("127.0.0.1:11212");
+    println( fmt!("server1 get_bulk_str: %?", server1.get_bulk_str(keys)) );
+    println( fmt!("server2 get_bulk_str: %?", server2.get_bulk_str(keys)) );
+
+    for i in range(0, 6) {
+        println( fmt!("delete rkey%?: %?", i, rm.delete(fmt!("rkey%?", i))) );
+    }
+    println( fmt!("server1 after delete: %?", server1.get_bulk_str(keys)) );
+    println( fmt!("server2 after delete: %?", server2.get_bulk_str(keys)) );
+
+    // Suspend the memcached at 11211 (kill -STOP) during this loop to see the reads time out and fail over to 11212.
+    let mut params = MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY);
+    params.replication = 2;
+    params.io_timeout_ms = 200;
+    let mut rm = rustymem::connect_with(params);
+    rm.set_str("rkey0", 600, "replicated");
+    for _ in range(0, 20) {
+        println( fmt!("get_str rkey0: %?, health %?", rm.get_str("rkey0"), rm.health().map(|h| h.consecutive_failures)) );
+        std::rt::io::timer::Timer::new().unwrap().sleep(1000);
+    }
+}
+
+fn test_health() {
+
+    // Run memcached at 11211.  Start and stop memcached at 11212 during the test to see it ejected and put back.
+    let mut params = MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY);
+    params.eject_after_failures = 2;
+    params.eject_rehash = true;
+    params.retry_down_ms = 500;
+    params.probe_interval_ms = 1000;
+    let mut rm = rustymem::connect_with(params);
+
+    for round in range(0, 30) {
+        let mut stored = 0;
+        for i in range(0, 10) {
+            if rm.set_str(fmt!("hkey%?", i), 60, "value").status == Success {
+                stored += 1;
+            }
+        }
+        println( fmt!("round %?, stored %? of 10", round, stored) );
+        for health in rm.health().iter() {
+            println( fmt!("    %s: failures %?, ejected %?, last error %?",
+                          health.server_addr, health.consecutive_failures, health.ejected, health.last_error) );
+        }
+        std::rt::io::timer::Timer::new().unwrap().sleep(1000);
+    }
+}
+
+fn test_topology() {
+
+    // Run memcached at 11211, 11212 and 11213.
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY) );
+    for i in range(0, 100) {
+        rm.set_str(fmt!("tkey%?", i), 60, "value");
+    }
+    let print_estimate = |estimate: &RebalanceEstimate| {
+        println( fmt!("moved fraction %?, moved items %? of %?", estimate.moved_fraction, estimate.moved_items, estimate.total_items) );
+    };
+
+    let estimate = rm.add_server("127.0.0.1:11213");
+    print_estimate(&estimate);
+    println( fmt!("servers: %?", rm.server_addrs()) );
+    let keys = range(0, 100).map(|i| fmt!("tkey%?", i)).collect::<~[~str]>();
+    let key_refs = keys.iter().map(|k| k.as_slice()).collect::<~[&str]>();
+    println( fmt!("found %? of 100 after add_server", rm.get_bulk_str(key_refs).len()) );
+
+    match rm.remove_server("127.0.0.1:11212") {
+        Ok(estimate)    => print_estimate(&estimate),
+        Err(status)     => println( fmt!("remove_server error %?", status) )
+    }
+    println( fmt!("servers: %?", rm.server_addrs()) );
+
+    match rm.set_servers("127.0.0.1:11211 127.0.0.1:11212") {
+        Ok(estimate)    => print_estimate(&estimate),
+        Err(status)     => println( fmt!("set_servers error %?", status) )
+    }
+    println( fmt!("servers: %?", rm.server_addrs()) );
+    println( fmt!("found %? of 100 after set_servers", rm.get_bulk_str(key_refs).len()) );
+}
+
+fn test_pool() {
+
+    let mut params = MemParams::new("127.0.0.1:11211", P_BINARY);
+    params.pool_min = 2;
+    params.pool_max = 4;
+    params.pool_wait_ms = 500;
+    let pool = MemPool::new(params);
+
+    let (port, chan) = std::comm::stream::<uint>();
+    let chan = std::comm::SharedChan::new(chan);
+    for t in range(0, 8u) {
+        let pool = pool.clone();
+        let chan = chan.clone();
+        do std::task::spawn {
+            let mut stored = 0u;
+            for i in range(0, 100) {
+                let result = pool.with_mem(|rm| rm.set_str(fmt!("pkey%?:%?", t, i), 60, "value").status);
+                if result == Ok(Success) {
+                    stored += 1;
+                }
+            }
+            chan.send(stored);
+        }
+    }
+    for t in range(0, 8) {
+        println( fmt!("task %? stored %?", t, port.recv()) );
+    }
+
+    let stats = pool.stats();
+    println( fmt!("idle %?, in use %?, waits %?, timeouts %?, evicted %?", stats.idle, stats.in_use, stats.waits, stats.timeouts, stats.evicted) );
+
+    let mut pm = pool.checkout().unwrap();
+    println( fmt!("pkey0:0 = %?", pm.mem().get_str("pkey0:0")) );
+}
+
+fn test_async() {
+
+    let am = AsyncMem::new( MemParams::new("127.0.0.1:11211", P_BINARY) );
+
+    let mut sets = range(0, 20).map(|i| am.set_str(fmt!("akey%?", i), 60, fmt!("value%?", i))).collect::<~[extra::future::Future<MemResult<u64>>]>();
+    for f in sets.mut_iter() {
+        println( fmt!("set status %?", f.get().status) );
+    }
+
+    // Concurrent gets from several tasks are batched onto the wire by the server task.
+    let (port, chan) = std::comm::stream::<~str>();
+    let chan = std::comm::SharedChan::new(chan);
+    for t in range(0, 4u) {
+        let am = am.clone();
+        let chan = chan.clone();
+        do std::task::spawn {
+            let mut futures = range(0, 20).map(|i| am.get_str(fmt!("akey%?", i))).collect::<~[extra::future::Future<Option<~str>>]>();
+            let mut found = 0;
+            for f in futures.mut_iter() {
+                if f.get().is_some() {
+                    found += 1;
+                }
+            }
+            chan.send(fmt!("task %? found %?", t, found));
+        }
+    }
+    for _ in range(0, 4) {
+        println(port.recv());
+    }
+
+    let mut counter = am.incr("acounter", 1, 0, 60);
+    println( fmt!("incr %?", counter.get().value) );
+    let mut bulk = am.get_bulk_str(["akey1", "akey2", "nokey"]);
+    println( fmt!("bulk %?", bulk.get()) );
+    let mut deleted = am.delete("akey1");
+    println( fmt!("delete %?", deleted.get()) );
+
+    let mut set_as = am.set_as("aint", 60, &123);
+    println( fmt!("set_as %?", set_as.get().status) );
+    let mut get_as = am.get_as::<int>("aint");
+    println( fmt!("get_as %?", get_as.get()) );
+    let mut bulk_as = am.get_bulk_as::<int>(["aint", "nokey"]);
+    println( fmt!("get_bulk_as %?", bulk_as.get()) );
+}
+
+fn test_cluster_stats() {
+
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY) );
+    let before = rm.cluster_stats();
+    for i in range(0, 100) {
+        rm.set_str(fmt!("skey%?", i), 60, "value");
+        rm.get_str(fmt!("skey%?", i * 2));
+    }
+    std::rt::io::timer::Timer::new().unwrap().sleep(1000);
+    let after = rm.cluster_stats();
+
+    for ss in after.servers.iter() {
+        println( fmt!("%s: up %?, version %s, uptime %?s, user cpu %?s, items %?, hit ratio %?, memory %?, other %?",
+                      ss.server_addr, ss.available, ss.version, ss.uptime_secs, ss.rusage_user_secs,
+                      ss.curr_items, ss.hit_ratio(), ss.memory_utilization(), ss.other.len()) );
+    }
+    println( fmt!("cluster: items %?, hit ratio %?, evictions/sec %?", after.total_items(), after.hit_ratio(), after.evictions_per_sec(&before)) );
+    println( fmt!("memory utilization: %?", after.memory_utilization()) );
+}
+
+fn test_stats_groups() {
+
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
+    rm.set_str("small", 60, "v");
+    rm.set_str("large", 60, "v".repeat(10000));
+
+    for group in ["sizes", "settings", "conns"].iter() {
+        println( fmt!("stats %s: %?", *group, rm.stats_group(*group)) );
+    }
+    for slab in rm.slab_stats()[0].iter() {
+        println( fmt!("slab %?: chunk size %?, used chunks %?, fill ratio %?", slab.slab_class, slab.chunk_size, slab.used_chunks, slab.fill_ratio()) );
+    }
+    for items in rm.slab_item_stats()[0].iter() {
+        println( fmt!("items %?: number %?, age %?s, evicted %?", items.slab_class, items.number, items.age_secs, items.evicted) );
+    }
+
+    println( fmt!("reset: %?", rm.reset_stats()) );
+    println( fmt!("get_hits after reset: %?", rm.cluster_stats().servers[0].get_hits) );
+
+    let mut rm2 = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
+    println( fmt!("binary stats slabs: %?", rm2.get_connection(0).p_stats_group("slabs")) );
+    println( fmt!("binary reset: %?", rm2.reset_stats()) );
+}
+
+fn test_metadump() {
+
+    // Needs memcached 1.4.31 or later.
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY) );
+    for i in range(0, 10) {
+        rm.set_str(fmt!("user:%?", i), 60, "value");
+        rm.set_str(fmt!("page:/home/%?", i), 0, "value");
+    }
+
+    let mut dump = rm.metadump("user:", None);
+    loop {
+        match dump.next() {
+            Some(entry) => println( fmt!("%s %s exp %? la %? size %? cls %?", entry.server_addr, entry.key, entry.exptime, entry.last_access, entry.size, entry.slab_class) ),
+            None => break
+        }
+    }
+    println( fmt!("errors: %?", dump.errors()) );
+
+    for usage in rm.usage_by_namespace(':').iter() {
+        println( fmt!("namespace %?: %? items, %? bytes", usage.namespace, usage.items, usage.bytes) );
+    }
+}
+
+fn test_dump_restore() {
+
+    // Needs memcached 1.4.31 or later at 11211 and 11212, and another server at 11213 to restore into.
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY) );
+    for i in range(0, 50) {
+        rm.set_str(fmt!("dkey%?", i), 3600, fmt!("value%?", i));
+    }
+    rm.set_int("dcounter", 0, 100);
+
+    match rm.dump_file("/tmp/rustymem.dump", "d") {
+        Ok(summary) => println( fmt!("dumped %? entries, %? bytes, missed %?, errors %?", summary.entries, summary.bytes, summary.missed, summary.errors) ),
+        Err(e)      => println( fmt!("dump error %?", e) )
+    }
+
+    let mut rm2 = rustymem::connect_with( MemParams::new("127.0.0.1:11213", P_ASCII) );
+    match rm2.restore_file("/tmp/rustymem.dump") {
+        Ok(summary) => println( fmt!("restored %?, expired %?, failed %?", summary.restored, summary.expired, summary.failed) ),
+        Err(e)      => println( fmt!("restore error %?", e) )
+    }
+    println( fmt!("dkey7 = %?, dcounter = %?", rm2.get_str("dkey7"), rm2.get_int("dcounter")) );
+
+    // A corrupted dump is rejected.
+    let mut dump = std::io::read_whole_file(&Path("/tmp/rustymem.dump")).unwrap();
+    dump[20] ^= 0xFF;
+    println( fmt!("corrupted restore: %?", rm2.restore(dump).is_ok()) );
+}
+
+fn test_migrate() {
+
+    // Old cluster at 11211, new cluster at 11212.
+    let mut old = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
+    old.set_str("mkey1", 3600, "old value1");
+    old.set_str("mkey2", 3600, "old value2");
+
+    let mut mm = MigratingMem::new( MemParams::new("127.0.0.1:11211", P_BINARY), MemParams::new("127.0.0.1:11212", P_BINARY), MIGRATE_WARMING, 3600 );
+    println( fmt!("warming: mkey1 = %?", mm.get_str("mkey1")) );
+    println( fmt!("warming: mkey1 again, queued once = %?", mm.get_str("mkey1")) );
+    mm.set_str("mkey3", 3600, "dual value3");
+
+    // A key deleted after being queued is not copied back.
+    old.set_str("mkey5", 3600, "old value5");
+    println( fmt!("warming: mkey5 = %?", mm.get_str("mkey5")) );
+    println( fmt!("delete mkey5: %?", mm.delete("mkey5")) );
+
+    // Switched from another task holding the switch.
+    let switch = mm.switch();
+    do std::task::spawn {
+        switch.set(MIGRATE_DUAL_WRITE);
+    }
+    std::rt::io::timer::Timer::new().unwrap().sleep(100);
+    println( fmt!("phase %?: mkey2 = %?", mm.phase(), mm.get_str("mkey2")) );
+    println( fmt!("bulk: %?", mm.get_bulk_data(["mkey1", "mkey2", "mkey3", "mkey4"]).map(|md| md.key.clone())) );
+
+    mm.set_phase(MIGRATE_NEW_ONLY);
+    println( fmt!("new only: mkey1 = %?, mkey2 = %?, mkey3 = %?", mm.get_str("mkey1"), mm.get_str("mkey2"), mm.get_str("mkey3")) );
+    println( fmt!("new only: mkey5 = %?", mm.get_str("mkey5")) );
+    let stats = mm.stats();
+    println( fmt!("fallback hits %?, misses %?, backfills %?, copies queued %?, skipped %?", stats.fallback_hits, stats.fallback_misses, stats.backfills, stats.copies_queued, stats.copies_skipped) );
+}
+
+fn test_tags() {
+
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY) );
+    let home_versions = rm.tag_versions(["user:1", "post:7"]).unwrap();
+    let profile_versions = rm.tag_versions(["user:1"]).unwrap();
+    let count_versions = rm.tag_versions(["post:7"]).unwrap();
+    rm.set_tagged_str("page:home", 600, "<html>home</html>", home_versions);
+    rm.set_tagged_str("page:profile", 600, "<html>profile</html>", profile_versions);
+    rm.set_tagged_int("count:posts", 600, 42, count_versions);
+    println( fmt!("home = %?, profile = %?, count = %?", rm.get_tagged_str("page:home"), rm.get_tagged_str("page:profile"), rm.get_tagged_int("count:posts")) );
+    println( fmt!("user:1 version = %?", rm.tag_version("user:1").value) );
+
+    // Bumping user:1 makes both pages stale, but not the count.
+    rm.invalidate_tag("user:1");
+    println( fmt!("after invalidate: home = %?, profile = %?, count = %?", rm.get_tagged_str("page:home"), rm.get_tagged_str("page:profile"), rm.get_tagged_int("count:posts")) );
+
+    // A page computed while user:1 is bumped is stored under the versions read before, and is stale at once.
+    let home_versions = rm.tag_versions(["user:1", "post:7"]).unwrap();
+    rm.invalidate_tag("user:1");
+    rm.set_tagged_str("page:home", 600, "<html>home2</html>", home_versions);
+    println( fmt!("computed during invalidate: home = %?", rm.get_tagged_str("page:home")) );
+
+    // Tagged data isn't readable as a plain str.
+    let home_versions = rm.tag_versions(["user:1", "post:7"]).unwrap();
+    rm.set_tagged_str("page:home", 600, "<html>home3</html>", home_versions);
+    println( fmt!("plain get = %?, tagged get = %?", rm.get_str("page:home"), rm.get_tagged_str("page:home")) );
+}
+
+fn test_metrics() {
+
+    let mut params = MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY);
+    params.metrics = Some(MemMetrics::histogram());
+    let mut rm = rustymem::connect_with(params);
+    for i in range(0, 100) {
+        rm.set_str(fmt!("mtkey%?", i), 600, fmt!("value%?", i));
+        rm.get_str(fmt!("mtkey%?", i * 2));
+    }
+    rm.incr("mtcounter", 1, 0, 600);
+
+    let snapshot = rm.metrics_snapshot().unwrap();
+    for op in snapshot.ops.iter() {
+        println( fmt!("%s %s: count %?, mean %?us, p50 %?us, p99 %?us, max %?us, sent %?, received %?, errors %?, statuses %?",
+                      op.server_addr, op.command, op.count, op.mean_us(), op.percentile_us(0.5), op.percentile_us(0.99), op.max_us,
+                      op.bytes_sent, op.bytes_received, op.errors(), op.statuses) );
+    }
+    let gets = snapshot.command_total("gets");
+    println( fmt!("all gets: count %?, buckets %?", gets.count, gets.buckets) );
+}
+
+fn test_prometheus() {
+
+    let mut params = MemParams::new("127.0.0.1:11211 127.0.0.1:11212", P_BINARY);
+    params.metrics = Some(MemMetrics::histogram());
+    let pool = MemPool::new(params);
+    do pool.with_mem |rm| {
+        rm.set_str("pkey1", 600, "value1");
+        rm.get_str("pkey1");
+        rm.get_str("pkey2");
+    };
+    println( pool.prometheus_text() );
+
+    // Scrape with: curl http://127.0.0.1:9150/metrics
+    let pool2 = pool.clone();
+    match rustymem::serve_prometheus("127.0.0.1:9150", || pool2.prometheus_text()) {
+        Ok(_)   => println("serving the metrics at http://127.0.0.1:9150/metrics"),
+        Err(e)  => println( fmt!("serve error %?", e) )
+    }
+    std::rt::io::timer::Timer::new().unwrap().sleep(30000);
+}
+
 fn main()  {
 
     debug!("main() enter");
@@ -316,6 +1060,56 @@ fn main()  {
 
     // test_cluster();
 
+    // test_keys();
+
+    // test_key_prefix();
+
+    // test_namespace();
+
+    // test_flags();
+
+    // test_compression();
+
+    // test_large_values();
+
+    // test_codecs();
+
+    // test_update();
+
+    // test_get_or_compute();
+
+    // test_lock();
+
+    // test_rate_limit();
+
+    // test_l1_cache();
+
+    // test_replication();
+
+    // test_health();
+
+    // test_topology();
+
+    // test_pool();
+
+    // test_async();
+
+    // test_cluster_stats();
+
+    // test_stats_groups();
+
+    // test_metadump();
+
+    // test_dump_restore();
+
+    // test_migrate();
+
+    // test_tags();
+
+    // test_metrics();
+
+    // test_prometheus();
+
     let mut map = HashMap::<&str,~str>::new();
     map.insert(&"abc", ~"xyz");
 
@@ -334,7 +1128,7 @@ fn bench_baseline(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_connection_count(b: &mut extra::test::BenchHarness) {
-    let rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     do b.iter {
         rm.get_connection_count();
     }
@@ -343,7 +1137,7 @@ fn bench_connection_count(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_versions_a(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_ASCII, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
     rm.set_bytes("key1", 2*60*60, bytes!("key1value"));
     do b.iter {
         rm.versions();
@@ -353,7 +1147,7 @@ fn bench_versions_a(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_versions_b(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     rm.set_bytes("key1", 2*60*60, bytes!("key1value"));
     do b.iter {
         rm.versions();
@@ -363,7 +1157,7 @@ fn bench_versions_b(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_get_1_key_a(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_ASCII, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
     rm.set_bytes("key1", 2*60*60, bytes!("key1value"));
     do b.iter {
         rm.get_bytes("key1");
@@ -373,7 +1167,7 @@ fn bench_get_1_key_a(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_get_1_key_b(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     rm.set_bytes("key1", 2*60*60, bytes!("key1value"));
     do b.iter {
         rm.get_bytes("key1");
@@ -383,7 +1177,7 @@ fn bench_get_1_key_b(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_get_1_none_key_a(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_ASCII, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
     rm.set_bytes("key1", 2*60*60, bytes!("key1value"));
     do b.iter {
         rm.get_bytes("key_none");
@@ -393,7 +1187,7 @@ fn bench_get_1_none_key_a(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_get_1_none_key_b(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     rm.set_bytes("key1", 2*60*60, bytes!("key1value"));
     do b.iter {
         rm.get_bytes("key_none");
@@ -403,7 +1197,7 @@ fn bench_get_1_none_key_b(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_get_keys_a(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_ASCII, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
     rm.set_bytes("key1", 60, bytes!("key1value"));
     rm.set_bytes("key2", 60, bytes!("key2value"));
     rm.set_bytes("key3", 60, bytes!("key3value"));
@@ -419,7 +1213,7 @@ fn bench_get_keys_a(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_get_keys_b(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     rm.set_bytes("key1", 60, bytes!("key1value"));
     rm.set_bytes("key2", 60, bytes!("key2value"));
     rm.set_bytes("key3", 60, bytes!("key3value"));
@@ -435,7 +1229,7 @@ fn bench_get_keys_b(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_key_a(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_ASCII, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
     do b.iter {
         rm.set_bytes("key1", 2*60*60, bytes!("key1value"));
     }
@@ -444,7 +1238,7 @@ fn bench_set_1_key_a(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_key_b(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     do b.iter {
         rm.set_bytes("key1", 2*60*60, bytes!("key1value"));
     }
@@ -453,7 +1247,7 @@ fn bench_set_1_key_b(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_1K_a(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_ASCII, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
     let buf = vec::from_elem(1024, 0xABu8);
     do b.iter {
         rm.set_bytes("key1", 2*60*60, buf);
@@ -463,7 +1257,7 @@ fn bench_set_1_1K_a(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_1K_b(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     let buf = vec::from_elem(1024, 0xABu8);
     do b.iter {
         rm.set_bytes("key1", 2*60*60, buf);
@@ -473,7 +1267,7 @@ fn bench_set_1_1K_b(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_10K_a(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_ASCII, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
     let buf = vec::from_elem(1024*10, 0xABu8);
     do b.iter {
         rm.set_bytes("key1", 2*60*60, buf);
@@ -483,7 +1277,7 @@ fn bench_set_1_10K_a(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_10K_b(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     let buf = vec::from_elem(1024*10, 0xABu8);
     do b.iter {
         rm.set_bytes("key1", 2*60*60, buf);
@@ -493,7 +1287,7 @@ fn bench_set_1_10K_b(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_20K_a(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_ASCII, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
     let buf = vec::from_elem(1024*20, 0xABu8);
     do b.iter {
         rm.set_bytes("key1", 2*60*60, buf);
@@ -503,7 +1297,7 @@ fn bench_set_1_20K_a(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_20K_b(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     let buf = vec::from_elem(1024*20, 0xABu8);
     do b.iter {
         rm.set_bytes("key1", 2*60*60, buf);
@@ -513,7 +1307,7 @@ fn bench_set_1_20K_b(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_100K_a(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_ASCII, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_ASCII) );
     let buf = vec::from_elem(1024*100, 0xABu8);
     do b.iter {
         rm.set_bytes("key1", 2*60*60, buf);
@@ -523,7 +1317,7 @@ fn bench_set_1_100K_a(b: &mut extra::test::BenchHarness) {
 
 #[bench]
 fn bench_set_1_100K_b(b: &mut extra::test::BenchHarness) {
-    let mut rm = rustymem::connect_with( MemParams { servers: ~"127.0.0.1:11211", protocol: P_BINARY, shard: HASH_MOD } );
+    let mut rm = rustymem::connect_with( MemParams::new("127.0.0.1:11211", P_BINARY) );
     let buf = vec::from_elem(1024*100, 0xABu8);
     do b.iter {
         rm.set_bytes("key1", 2*60*60, buf);